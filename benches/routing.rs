@@ -0,0 +1,112 @@
+//! Benchmarks of the routing core, on tables filled with synthetic
+//! nodes.
+//!
+//! Buckets hold at most `bucket_size` nodes, so large tables mostly
+//! measure updates of full buckets (which evict nodes) and lookups in
+//! a saturated table.
+//!
+//! Run with `cargo bench`; see the README to compare with a saved
+//! baseline.
+
+#[macro_use]
+extern crate criterion;
+extern crate rand;
+extern crate fcp_routing;
+
+use criterion::{Criterion, BenchmarkId};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use fcp_routing::node::{Address, Node};
+use fcp_routing::node_store::GetNodeResult;
+use fcp_routing::router::Router;
+
+/// Number of nodes inserted before measuring.
+const TABLE_SIZES: [usize; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+/// Number of nodes asked for by lookups, like the driver does.
+const NB_CLOSEST: usize = 8;
+
+/// Seeds of the generators of the table's nodes, of lookup targets,
+/// and of nodes inserted while measuring, so they are unrelated (and
+/// the same from a run to another).
+const TABLE_SEED: [u32; 4] = [0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb];
+const TARGETS_SEED: [u32; 4] = [0x6c078965, 0x5d588b65, 0x2c1b3c6d, 0x297a2d39];
+const NEW_NODES_SEED: [u32; 4] = [0x9e3779b9, 0x7f4a7c15, 0xf39cc060, 0x5ced1b19];
+
+fn rng(seed: [u32; 4]) -> XorShiftRng {
+    XorShiftRng::from_seed(seed)
+}
+
+fn random_node<R: Rng>(rng: &mut R) -> (Address, Node) {
+    let public_key: [u8; 32] = rng.gen();
+    let node = Node::new(public_key, [0, 0, 0, 0, 0, 0, 0x01, rng.gen()], 18);
+    (node.address(), node)
+}
+
+fn filled_router(nb_nodes: usize) -> Router {
+    let mut rng = rng(TABLE_SEED);
+    let mut router = Router::new(Address::from_public_key(&rng.gen()));
+    for _ in 0..nb_nodes {
+        let (address, node) = random_node(&mut rng);
+        router.update(address, node);
+    }
+    router
+}
+
+/// Random addresses, used as lookup targets.
+fn targets(count: usize) -> Vec<Address> {
+    let mut rng = rng(TARGETS_SEED);
+    (0..count).map(|_| Address::new(&rng.gen())).collect()
+}
+
+fn bench_node_store(c: &mut Criterion) {
+    let mut group = c.benchmark_group("node_store");
+    for &size in TABLE_SIZES.iter() {
+        let mut router = filled_router(size);
+        let targets = targets(1024);
+
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("find_closest_nodes", size), |b| b.iter(|| {
+            i += 1;
+            router.node_store().find_closest_nodes(&targets[i % targets.len()], NB_CLOSEST).len()
+        }));
+
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("get_node", size), |b| b.iter(|| {
+            i += 1;
+            match router.node_store().get_node(&targets[i % targets.len()], NB_CLOSEST) {
+                GetNodeResult::FoundNode(_) => 1,
+                _ => 0,
+            }
+        }));
+
+        // Last, as it changes the table.
+        let mut rng = rng(NEW_NODES_SEED);
+        let new_nodes: Vec<(Address, Node)> = (0..1024).map(|_| random_node(&mut rng)).collect();
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("update", size), |b| b.iter(|| {
+            let (ref address, ref node) = new_nodes[i % new_nodes.len()];
+            router.update(address.clone(), node.clone());
+            i += 1;
+        }));
+    }
+    group.finish();
+}
+
+fn bench_router(c: &mut Criterion) {
+    let mut group = c.benchmark_group("router");
+    for &size in TABLE_SIZES.iter() {
+        let router = filled_router(size);
+        let targets = targets(1024);
+        let mut i = 0;
+        // Includes building the `fn` packets sent to the closest nodes.
+        group.bench_function(BenchmarkId::new("get_node", size), |b| b.iter(|| {
+            i += 1;
+            router.get_node(&targets[i % targets.len()], NB_CLOSEST).1.len()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_node_store, bench_router);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate fcp_routing;
+
+fuzz_target!(|data: &[u8]| {
+    fcp_routing::fuzzing::route_packet(data);
+});
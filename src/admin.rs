@@ -0,0 +1,699 @@
+//! Admin interface exposing routing functions with the names and
+//! shapes of cjdns' admin RPC (bencoded dictionaries over UDP), so
+//! existing tooling can query this router.
+//! See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/admin/README.md
+//!
+//! `AdminServer` only parses requests and builds replies;
+//! `UdpAdminServer` plugs it on a UDP socket.
+//! `RouterModule_pingNode` and `RouterModule_getPeers` are answered
+//! once the router gets a reply, so the driver's `PingCompleted` and
+//! `GetPeersCompleted` actions have to be given to `on_action`.
+//!
+//! `RouterModule_traceLookup` is not in cjdns: it runs an iterative
+//! lookup, and replies once it is over with the nodes queried at each
+//! step and what they answered.
+//!
+//! `AdminClient` calls functions of an admin interface.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+use sha2::{Sha256, Digest};
+
+use bencode::Value;
+use node::{Address, Node, DisplayPath, parse_path, public_key_to_base32, hex_to_bytes};
+use node_store::GetNodeResult;
+use driver::{Driver, Input, Action, LookupError};
+
+/// Number of nodes per page of `NodeStore_dumpTable`, like cjdns.
+const ENTRIES_PER_PAGE: usize = 4;
+
+/// Number of cookies remembered for authentication.
+const MAX_COOKIES: usize = 32;
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares two byte strings in a time that does not depend on where
+/// they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn error(message: &str) -> Value {
+    Value::dict(vec![("error", Value::string(message))])
+}
+
+/// Request waiting for the reply of a remote node.
+struct PendingRequest {
+    from: SocketAddr,
+    txid: Option<Value>,
+    address: Address,
+}
+
+/// `RouterModule_traceLookup` request, waiting for the end of the
+/// lookup.
+struct PendingLookup {
+    request: PendingRequest,
+    /// Nodes queried at each step, with their address.
+    rounds: Vec<Vec<(Address, Value)>>,
+}
+
+pub struct AdminServer {
+    /// If set, functions can only be called through authenticated
+    /// requests (`"q": "auth"`).
+    password: Option<String>,
+    next_cookie: u64,
+    cookies: VecDeque<String>,
+    pending_pings: Vec<PendingRequest>,
+    pending_get_peers: Vec<PendingRequest>,
+    pending_lookups: Vec<PendingLookup>,
+}
+
+impl AdminServer {
+    pub fn new(password: Option<String>) -> AdminServer {
+        AdminServer {
+            password: password,
+            next_cookie: 0,
+            cookies: VecDeque::new(),
+            pending_pings: Vec::new(),
+            pending_get_peers: Vec::new(),
+            pending_lookups: Vec::new(),
+        }
+    }
+
+    /// Handles a datagram received from `from`. Returns the reply to
+    /// send back, unless it will be sent later by `on_action`.
+    pub fn handle_request(&mut self, driver: &mut Driver, from: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+        let request = match Value::decode(datagram) {
+            Ok(request @ Value::Dict(_)) => request,
+            _ => return Some(error("invalid request").encode()),
+        };
+        let txid = request.get("txid").cloned();
+        let reply = match request.get("q").and_then(Value::as_str) {
+            Some("ping") => Some(Value::dict(vec![("q", Value::string("pong"))])),
+            Some("cookie") => Some(Value::dict(vec![("cookie", Value::string(&self.gen_cookie()))])),
+            Some("auth") => {
+                if self.check_auth(&request) {
+                    let function = request.get("aq").and_then(Value::as_str).unwrap_or("");
+                    self.call(driver, from, txid.clone(), function, request.get("args"))
+                }
+                else {
+                    Some(error("Auth failed."))
+                }
+            }
+            Some(function) => {
+                if self.password.is_none() {
+                    self.call(driver, from, txid.clone(), function, request.get("args"))
+                }
+                else {
+                    Some(error("Auth failed."))
+                }
+            }
+            None => Some(error("invalid request")),
+        };
+        reply.map(|reply| with_txid(reply, txid).encode())
+    }
+
+    /// Builds replies to pending requests completed by this action of
+    /// the driver. Returns them with their destinations.
+    pub fn on_action(&mut self, action: &Action) -> Vec<(SocketAddr, Vec<u8>)> {
+        match *action {
+            Action::PingCompleted { ref node, rtt } => {
+                let reply = match rtt {
+                    Some(rtt) => Value::dict(vec![
+                        ("result", Value::string("pong")),
+                        ("ms", Value::Int(rtt as i64)),
+                        ("protocolVersion", Value::Int(node.version() as i64)),
+                        ("from", Value::string(&node.to_string())),
+                        ]),
+                    None => Value::dict(vec![("result", Value::string("timeout"))]),
+                };
+                take_pending(&mut self.pending_pings, &node.address(), &reply)
+            }
+            Action::GetPeersCompleted { ref node, ref peers } => {
+                let reply = match *peers {
+                    Some(ref peers) => Value::dict(vec![
+                        ("result", Value::string("peers")),
+                        ("peers", Value::List(peers.iter().map(|peer| Value::string(&peer.to_string())).collect())),
+                        ]),
+                    None => Value::dict(vec![("result", Value::string("timeout"))]),
+                };
+                take_pending(&mut self.pending_get_peers, &node.address(), &reply)
+            }
+            Action::LookupQueried { ref target, ref nodes } => {
+                for lookup in self.pending_lookups.iter_mut().filter(|lookup| lookup.request.address == *target) {
+                    lookup.rounds.push(nodes.iter().map(|node| (node.address(), node_value(node, target))).collect());
+                }
+                Vec::new()
+            }
+            Action::LookupReplied { ref target, ref node, ref nodes } => {
+                let (key, value) = match *nodes {
+                    Some(ref nodes) => ("candidates", Value::List(nodes.iter().map(|node| node_value(node, target)).collect())),
+                    None => ("timeout", Value::Int(1)),
+                };
+                let address = node.address();
+                for lookup in self.pending_lookups.iter_mut().filter(|lookup| lookup.request.address == *target) {
+                    let queried = lookup.rounds.iter_mut().rev().flat_map(|round| round.iter_mut()).find(|&&mut (ref a, _)| *a == address);
+                    if let Some(&mut (_, Value::Dict(ref mut d))) = queried {
+                        d.insert(key.as_bytes().to_vec(), value.clone());
+                    }
+                }
+                Vec::new()
+            }
+            Action::LookupCompleted { ref target, ref result } => {
+                let mut replies = Vec::new();
+                let mut i = 0;
+                while i < self.pending_lookups.len() {
+                    if self.pending_lookups[i].request.address == *target {
+                        let lookup = self.pending_lookups.remove(i);
+                        let reply = lookup_reply(result, lookup.rounds);
+                        replies.push((lookup.request.from, with_txid(reply, lookup.request.txid).encode()));
+                    }
+                    else {
+                        i += 1;
+                    }
+                }
+                replies
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn gen_cookie(&mut self) -> String {
+        let cookie = format!("{}", self.next_cookie);
+        self.next_cookie += 1;
+        if self.cookies.len() >= MAX_COOKIES {
+            self.cookies.pop_front();
+        }
+        self.cookies.push_back(cookie.clone());
+        cookie
+    }
+
+    /// Checks the hash of an authenticated request, computed like
+    /// cjdns: `hash` is first set to sha256(password + cookie), then to
+    /// the sha256 of the whole bencoded request.
+    fn check_auth(&mut self, request: &Value) -> bool {
+        let password = match self.password {
+            Some(ref password) => password.clone(),
+            None => return true,
+        };
+        let cookie = match request.get("cookie").and_then(Value::as_str) {
+            Some(cookie) => cookie.to_owned(),
+            None => return false,
+        };
+        // Cookies can only be used once, even by a request that fails
+        // authentication, so they cannot be used to probe hashes.
+        match self.cookies.iter().position(|c| *c == cookie) {
+            Some(position) => { self.cookies.remove(position); },
+            None => return false,
+        }
+        let hash = match request.get("hash").and_then(Value::as_str).and_then(hex_to_bytes) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        let mut request = request.clone();
+        if let Value::Dict(ref mut d) = request {
+            d.insert(b"hash".to_vec(), Value::string(&sha256_hex(format!("{}{}", password, cookie).as_bytes())));
+        }
+        constant_time_eq(&Sha256::digest(&request.encode()), &hash)
+    }
+
+    /// Calls a function. Returns `None` if the reply will be sent
+    /// later.
+    fn call(&mut self, driver: &mut Driver, from: SocketAddr, txid: Option<Value>, function: &str, args: Option<&Value>) -> Option<Value> {
+        let arg = |name: &str| args.and_then(|args| args.get(name));
+        let arg_str = |name: &str| arg(name).and_then(Value::as_str);
+        match function {
+            "NodeStore_dumpTable" => {
+                let page = arg("page").and_then(Value::as_int).unwrap_or(0);
+                Some(dump_table(driver, if page < 0 { 0 } else { page as usize }))
+            }
+            "NodeStore_nodeForAddr" => {
+                let address = match arg_str("ip").map(Address::from_str) {
+                    Some(Ok(address)) => address,
+                    Some(Err(_)) => return Some(error("parse_ip")),
+                    None => return Some(error("ip required")),
+                };
+                match driver.router().node_store().get_node(&address, 1) {
+                    GetNodeResult::FoundNode(node) => Some(Value::dict(vec![
+                        ("error", Value::string("none")),
+                        ("result", Value::dict(vec![
+                            ("key", Value::string(&public_key_to_base32(node.public_key()))),
+                            ("protocolVersion", Value::Int(node.version() as i64)),
+                            ("routeLabel", Value::string(&DisplayPath(node.path()).to_string())),
+                            ])),
+                        ])),
+                    _ => Some(error("not found")),
+                }
+            }
+            "RouterModule_lookup" => {
+                let address = match arg_str("address").map(Address::from_str) {
+                    Some(Ok(address)) => address,
+                    _ => return Some(error("parse failure")),
+                };
+                match driver.router().node_store().get_node(&address, 1) {
+                    GetNodeResult::FoundNode(node) => Some(Value::dict(vec![
+                        ("error", Value::string("none")),
+                        ("result", Value::string(&DisplayPath(node.path()).to_string())),
+                        ])),
+                    _ => Some(error("not found")),
+                }
+            }
+            "RouterModule_traceLookup" => {
+                let address = match arg_str("address").map(Address::from_str) {
+                    Some(Ok(address)) => address,
+                    _ => return Some(error("parse failure")),
+                };
+                self.pending_lookups.push(PendingLookup {
+                    request: PendingRequest { from: from, txid: txid, address: address.clone() },
+                    rounds: Vec::new(),
+                });
+                driver.handle_input(Input::Lookup { target: address });
+                None
+            }
+            "RouterModule_pingNode" | "RouterModule_getPeers" => {
+                let node = match arg_str("path").and_then(|path| find_node(driver, path)) {
+                    Some(node) => node,
+                    None => return Some(error("could not find node to ping")),
+                };
+                let pending_request = PendingRequest { from: from, txid: txid, address: node.address() };
+                if function == "RouterModule_pingNode" {
+                    self.pending_pings.push(pending_request);
+                    driver.ping(node);
+                }
+                else {
+                    self.pending_get_peers.push(pending_request);
+                    driver.get_peers(node);
+                }
+                None
+            }
+            _ => Some(error("no such function")),
+        }
+    }
+}
+
+/// Adds the transaction id of the request to its reply.
+fn with_txid(reply: Value, txid: Option<Value>) -> Value {
+    match (reply, txid) {
+        (Value::Dict(mut d), Some(txid)) => {
+            d.insert(b"txid".to_vec(), txid);
+            Value::Dict(d)
+        }
+        (reply, _) => reply,
+    }
+}
+
+/// Removes requests waiting for this node, and returns the reply to
+/// send to each of them.
+fn take_pending(pending: &mut Vec<PendingRequest>, address: &Address, reply: &Value) -> Vec<(SocketAddr, Vec<u8>)> {
+    let mut replies = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        if pending[i].address == *address {
+            let request = pending.remove(i);
+            replies.push((request.from, with_txid(reply.clone(), request.txid).encode()));
+        }
+        else {
+            i += 1;
+        }
+    }
+    replies
+}
+
+/// Describes a node queried by a lookup, or returned by a queried node.
+fn node_value(node: &Node, target: &Address) -> Value {
+    let distance: String = node.address().distance(target).bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    Value::dict(vec![
+        ("addr", Value::string(&node.to_string())),
+        ("ip", Value::string(&node.address().to_string())),
+        ("distance", Value::string(&distance)),
+        ])
+}
+
+fn lookup_reply(result: &Result<Node, LookupError>, rounds: Vec<Vec<(Address, Value)>>) -> Value {
+    let rounds = Value::List(rounds.into_iter().map(|round| {
+        Value::List(round.into_iter().map(|(_, value)| value).collect())
+    }).collect());
+    match *result {
+        Ok(ref node) => Value::dict(vec![
+            ("error", Value::string("none")),
+            ("result", Value::dict(vec![
+                ("key", Value::string(&public_key_to_base32(node.public_key()))),
+                ("protocolVersion", Value::Int(node.version() as i64)),
+                ("routeLabel", Value::string(&DisplayPath(node.path()).to_string())),
+                ])),
+            ("rounds", rounds),
+            ]),
+        Err(LookupError::NotFound) => Value::dict(vec![("error", Value::string("not found")), ("rounds", rounds)]),
+        Err(LookupError::NoNodes) => Value::dict(vec![("error", Value::string("no nodes")), ("rounds", rounds)]),
+    }
+}
+
+/// Finds a node from a node name (`v<version>.<path>.<key>.k`), an
+/// IPv6 address, or a path.
+fn find_node(driver: &Driver, s: &str) -> Option<Node> {
+    if let Ok(node) = Node::from_str(s) {
+        return Some(node)
+    }
+    let node_store = driver.router().node_store();
+    if let Ok(address) = Address::from_str(s) {
+        return match node_store.get_node(&address, 1) {
+            GetNodeResult::FoundNode(node) => Some(node.clone()),
+            _ => None,
+        }
+    }
+    if let Ok(path) = parse_path(s) {
+        return node_store.nodes().into_iter().find(|&(_, node)| *node.path() == path).map(|(_, node)| node.clone())
+    }
+    None
+}
+
+fn dump_table(driver: &Driver, page: usize) -> Value {
+    let router = driver.router();
+    let nodes = router.node_store().nodes();
+    // Pages come from the network; do not let them overflow.
+    let first = page.saturating_mul(ENTRIES_PER_PAGE);
+    let entries = nodes.iter().skip(first).take(ENTRIES_PER_PAGE).map(|&(address, node)| {
+        Value::dict(vec![
+            ("addr", Value::string(&node.to_string())),
+            ("bucket", Value::Int(router.my_address().distance(address).bucket_index() as i64)),
+            ("ip", Value::string(&address.to_string())),
+            ("isOneHop", Value::Int(if router.peers().contains_key(address) { 1 } else { 0 })),
+            ("path", Value::string(&DisplayPath(node.path()).to_string())),
+            ("version", Value::Int(node.version() as i64)),
+            ])
+    }).collect();
+    let mut reply = vec![
+        ("routingTable", Value::List(entries)),
+        ("count", Value::Int(nodes.len() as i64)),
+        ];
+    if nodes.len() > first.saturating_add(ENTRIES_PER_PAGE) {
+        reply.push(("more", Value::Int(1)));
+    }
+    Value::dict(reply)
+}
+
+/// `AdminServer` listening on a UDP socket.
+pub struct UdpAdminServer {
+    socket: UdpSocket,
+    server: AdminServer,
+}
+
+impl UdpAdminServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, password: Option<String>) -> io::Result<UdpAdminServer> {
+        Ok(UdpAdminServer {
+            socket: UdpSocket::bind(addr)?,
+            server: AdminServer::new(password),
+        })
+    }
+
+    /// The socket, eg. to make it non-blocking or to get its address.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Receives one request, and sends the reply if it is available
+    /// right away. Blocks unless the socket is non-blocking.
+    pub fn recv_request(&mut self, driver: &mut Driver) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        let (nb_bytes, from) = self.socket.recv_from(&mut buf)?;
+        if let Some(reply) = self.server.handle_request(driver, from, &buf[..nb_bytes]) {
+            self.socket.send_to(&reply, from)?;
+        }
+        Ok(())
+    }
+
+    /// See `AdminServer::on_action`.
+    pub fn on_action(&mut self, action: &Action) -> io::Result<()> {
+        for (to, reply) in self.server.on_action(action) {
+            self.socket.send_to(&reply, to)?;
+        }
+        Ok(())
+    }
+}
+
+/// Client of an admin interface.
+pub struct AdminClient {
+    socket: UdpSocket,
+    /// If set, calls are authenticated.
+    password: Option<String>,
+    next_txid: u64,
+}
+
+impl AdminClient {
+    /// Creates a client of the admin interface at this address.
+    /// Replies are waited for up to 10 seconds (see `set_timeout`).
+    pub fn connect<A: ToSocketAddrs>(addr: A, password: Option<String>) -> io::Result<AdminClient> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")),
+        };
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(10)))?;
+        Ok(AdminClient { socket: socket, password: password, next_txid: 0 })
+    }
+
+    /// How long to wait for replies; `None` waits forever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Calls a function with these arguments (a dictionary), and
+    /// returns the reply.
+    pub fn call(&mut self, function: &str, args: Value) -> io::Result<Value> {
+        let password = match self.password.clone() {
+            Some(password) => password,
+            None => {
+                let txid = self.gen_txid();
+                let request = Value::dict(vec![("q", Value::string(function)), ("args", args), ("txid", txid.clone())]);
+                return self.request(&request, &txid)
+            }
+        };
+        let txid = self.gen_txid();
+        let reply = self.request(&Value::dict(vec![("q", Value::string("cookie")), ("txid", txid.clone())]), &txid)?;
+        let cookie = match reply.get("cookie").and_then(Value::as_str) {
+            Some(cookie) => cookie.to_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "no cookie in reply")),
+        };
+        let txid = self.gen_txid();
+        let mut request = Value::dict(vec![
+            ("q", Value::string("auth")),
+            ("aq", Value::string(function)),
+            ("args", args),
+            ("cookie", Value::string(&cookie)),
+            ("hash", Value::string(&sha256_hex(format!("{}{}", password, cookie).as_bytes()))),
+            ("txid", txid.clone()),
+            ]);
+        let hash = sha256_hex(&request.encode());
+        if let Value::Dict(ref mut d) = request {
+            d.insert(b"hash".to_vec(), Value::string(&hash));
+        }
+        self.request(&request, &txid)
+    }
+
+    fn gen_txid(&mut self) -> Value {
+        self.next_txid += 1;
+        Value::string(&format!("{}", self.next_txid))
+    }
+
+    /// Sends a request, and returns the reply with the same txid.
+    fn request(&self, request: &Value, txid: &Value) -> io::Result<Value> {
+        self.socket.send(&request.encode())?;
+        let mut buf = [0u8; 65536];
+        loop {
+            let nb_bytes = self.socket.recv(&mut buf)?;
+            match Value::decode(&buf[..nb_bytes]) {
+                Ok(reply) => {
+                    if reply.get("txid") == Some(txid) {
+                        return Ok(reply)
+                    }
+                }
+                Err(()) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bencoded reply")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use router::Router;
+    use driver::{DriverConfig, Input};
+    use label::path_from_u64;
+
+    fn new_driver() -> Driver {
+        let mut driver = Driver::new(Router::new(Address::from_public_key(&[1; 32])), DriverConfig::default());
+        for i in 2..8 {
+            driver.handle_input(Input::PeerUp { node: Node::new([i; 32], path_from_u64(0b1000 | i as u64), 18) });
+        }
+        driver
+    }
+
+    #[test]
+    fn test_dump_table_over_loopback() {
+        let mut driver = new_driver();
+        let mut server = UdpAdminServer::bind("127.0.0.1:0", None).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = Value::dict(vec![
+            ("q", Value::string("NodeStore_dumpTable")),
+            ("args", Value::dict(vec![("page", Value::Int(1))])),
+            ("txid", Value::string("abcd")),
+            ]);
+        client.send_to(&request.encode(), server.socket().local_addr().unwrap()).unwrap();
+        server.recv_request(&mut driver).unwrap();
+
+        let mut buf = [0u8; 4096];
+        let (nb_bytes, _) = client.recv_from(&mut buf).unwrap();
+        let reply = Value::decode(&buf[..nb_bytes]).unwrap();
+        assert_eq!(reply.get("txid"), Some(&Value::string("abcd")));
+        assert_eq!(reply.get("count"), Some(&Value::Int(6)));
+        assert_eq!(reply.get("more"), None);
+        match reply.get("routingTable") {
+            Some(&Value::List(ref entries)) => {
+                assert_eq!(entries.len(), 2);
+                assert_eq!(entries[0].get("isOneHop"), Some(&Value::Int(1)));
+            }
+            _ => panic!("No routing table in {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_auth_and_ping_node() {
+        let mut driver = new_driver();
+        while driver.poll_action().is_some() {}
+        let mut server = AdminServer::new(Some("secret".to_owned()));
+        let from = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+
+        let request = Value::dict(vec![("q", Value::string("RouterModule_pingNode"))]);
+        let reply = server.handle_request(&mut driver, from, &request.encode()).unwrap();
+        assert_eq!(Value::decode(&reply).unwrap(), error("Auth failed."));
+
+        let reply = server.handle_request(&mut driver, from, b"d1:q6:cookiee").unwrap();
+        let cookie = Value::decode(&reply).unwrap().get("cookie").and_then(Value::as_str).unwrap().to_owned();
+        let target = Node::new([3; 32], path_from_u64(0b1011), 18);
+        let mut request = Value::dict(vec![
+            ("q", Value::string("auth")),
+            ("aq", Value::string("RouterModule_pingNode")),
+            ("args", Value::dict(vec![("path", Value::string("0000.0000.0000.000b"))])),
+            ("cookie", Value::string(&cookie)),
+            ("hash", Value::string(&sha256_hex(format!("secret{}", cookie).as_bytes()))),
+            ]);
+        let hash = sha256_hex(&request.encode());
+        if let Value::Dict(ref mut d) = request {
+            d.insert(b"hash".to_vec(), Value::string(&hash));
+        }
+        assert_eq!(server.handle_request(&mut driver, from, &request.encode()), None);
+        match driver.poll_action() {
+            Some(Action::SendToNode { node, .. }) => assert_eq!(node, target),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        // The cookie cannot be reused.
+        let reply = server.handle_request(&mut driver, from, &request.encode()).unwrap();
+        assert_eq!(Value::decode(&reply).unwrap(), error("Auth failed."));
+
+        let replies = server.on_action(&Action::PingCompleted { node: target, rtt: Some(12) });
+        assert_eq!(replies.len(), 1);
+        let reply = Value::decode(&replies[0].1).unwrap();
+        assert_eq!(reply.get("result"), Some(&Value::string("pong")));
+        assert_eq!(reply.get("ms"), Some(&Value::Int(12)));
+    }
+
+    #[test]
+    fn test_failed_auth_consumes_cookie() {
+        let mut driver = new_driver();
+        while driver.poll_action().is_some() {}
+        let mut server = AdminServer::new(Some("secret".to_owned()));
+        let from = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+
+        let reply = server.handle_request(&mut driver, from, b"d1:q6:cookiee").unwrap();
+        let cookie = Value::decode(&reply).unwrap().get("cookie").and_then(Value::as_str).unwrap().to_owned();
+        let auth_request = |password: &str| {
+            let mut request = Value::dict(vec![
+                ("q", Value::string("auth")),
+                ("aq", Value::string("ping")),
+                ("cookie", Value::string(&cookie)),
+                ("hash", Value::string(&sha256_hex(format!("{}{}", password, cookie).as_bytes()))),
+                ]);
+            let hash = sha256_hex(&request.encode());
+            if let Value::Dict(ref mut d) = request {
+                d.insert(b"hash".to_vec(), Value::string(&hash));
+            }
+            request.encode()
+        };
+        let reply = server.handle_request(&mut driver, from, &auth_request("wrong")).unwrap();
+        assert_eq!(Value::decode(&reply).unwrap(), error("Auth failed."));
+        // The right password comes too late.
+        let reply = server.handle_request(&mut driver, from, &auth_request("secret")).unwrap();
+        assert_eq!(Value::decode(&reply).unwrap(), error("Auth failed."));
+    }
+
+    #[test]
+    fn test_trace_lookup() {
+        let mut driver = new_driver();
+        while driver.poll_action().is_some() {}
+        let mut server = AdminServer::new(None);
+        let from = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+
+        let target = Address::from_public_key(&[9; 32]);
+        let request = Value::dict(vec![
+            ("q", Value::string("RouterModule_traceLookup")),
+            ("args", Value::dict(vec![("address", Value::string(&target.to_string()))])),
+            ]);
+        assert_eq!(server.handle_request(&mut driver, from, &request.encode()), None);
+        // Nobody replies.
+        let mut replies = Vec::new();
+        for i in 1..10 {
+            while let Some(action) = driver.poll_action() {
+                replies.extend(server.on_action(&action));
+            }
+            driver.handle_input(Input::Tick { now: i*10000 });
+        }
+
+        assert_eq!(replies.len(), 1);
+        let reply = Value::decode(&replies[0].1).unwrap();
+        assert_eq!(reply.get("error"), Some(&Value::string("not found")));
+        let rounds = match reply.get("rounds") {
+            Some(&Value::List(ref rounds)) => rounds.clone(),
+            _ => panic!("No rounds in {:?}", reply),
+        };
+        // 3 peers are queried first, then another one each time a
+        // query times out.
+        let sizes: Vec<usize> = rounds.iter().map(|round| match *round {
+            Value::List(ref queried) => {
+                for node in queried {
+                    assert_eq!(node.get("timeout"), Some(&Value::Int(1)));
+                }
+                queried.len()
+            }
+            _ => panic!("Invalid round {:?}", round),
+        }).collect();
+        assert_eq!(sizes, vec![3, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_client() {
+        let mut server = UdpAdminServer::bind("127.0.0.1:0", Some("secret".to_owned())).unwrap();
+        let addr = server.socket().local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut driver = new_driver();
+            // Cookie request, then the call.
+            server.recv_request(&mut driver).unwrap();
+            server.recv_request(&mut driver).unwrap();
+        });
+
+        let mut client = AdminClient::connect(addr, Some("secret".to_owned())).unwrap();
+        let reply = client.call("NodeStore_dumpTable", Value::dict(vec![])).unwrap();
+        assert_eq!(reply.get("count"), Some(&Value::Int(6)));
+        server_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+//! Signed route announcements, enabled by the `announcements` feature.
+//!
+//! An announcement describes a node: its protocol version, the encoding
+//! scheme of its switch, and its peers with their labels (from the
+//! announcer) and link state. It is signed with the announcer's key,
+//! so it can be relayed by other nodes, and whoever has a path to the
+//! announcer gets paths to its peers.
+//!
+//! Keys are X25519 keys; like cjdns, announcements are signed with
+//! Ed25519 using the same scalar, whose Edwards point is the birational
+//! equivalent of the X25519 public key. Verifiers convert the
+//! announcer's public key back, trying both signs of the point.
+//!
+//! Format (integers are big-endian):
+//!
+//! * signature of the rest of the announcement (64 bytes)
+//! * public key of the announcer (32 bytes)
+//! * timestamp, in milliseconds (8 bytes)
+//! * protocol version (8 bytes)
+//! * number of encoding forms (1 byte), then for each of them: bit
+//!   count (1 byte), prefix length (1 byte) and prefix (4 bytes)
+//! * until the end, peers: public key (32 bytes), label (8 bytes),
+//!   version (8 bytes) and link state (1 byte)
+
+use std::error::Error;
+use std::fmt;
+
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::{Scalar, clamp_integer};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ed25519_dalek::hazmat::{ExpandedSecretKey, raw_sign};
+use fcp_switching::encoding_scheme::EncodingSchemeForm;
+use sha2::{Sha512, Digest};
+
+use node::{Address, Path, PUBLIC_KEY_LENGTH};
+use node_store::Reachability;
+use router::{Router, PROTOCOL_VERSION, encoding_scheme_forms};
+
+const SIGNATURE_LENGTH: usize = 64;
+const HEADER_LENGTH: usize = SIGNATURE_LENGTH + PUBLIC_KEY_LENGTH + 8 + 8;
+const FORM_LENGTH: usize = 6;
+const PEER_LENGTH: usize = PUBLIC_KEY_LENGTH + 8 + 8 + 1;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AnnouncementError {
+    Truncated,
+    InvalidLinkState(u8),
+    /// The announcer's public key gives an address outside fc00::/8.
+    InvalidPublicKey,
+    InvalidSignature,
+    /// The secret key used to sign is not the announcer's.
+    KeyMismatch,
+}
+
+impl fmt::Display for AnnouncementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AnnouncementError::Truncated => write!(f, "truncated announcement"),
+            AnnouncementError::InvalidLinkState(state) => write!(f, "invalid link state {}", state),
+            AnnouncementError::InvalidPublicKey => write!(f, "invalid public key"),
+            AnnouncementError::InvalidSignature => write!(f, "invalid signature"),
+            AnnouncementError::KeyMismatch => write!(f, "secret key does not match the announcer's public key"),
+        }
+    }
+}
+
+impl Error for AnnouncementError {
+}
+
+/// A peer of the announcer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnouncedPeer {
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    /// Path from the announcer to the peer.
+    pub label: Path,
+    pub version: u64,
+    /// Whether the peer answered the announcer's last attempt to
+    /// reach it.
+    pub state: Reachability,
+}
+
+impl AnnouncedPeer {
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public_key)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Announcement {
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    /// When the announcement was made, in milliseconds; newer
+    /// announcements of a node supersede older ones.
+    pub timestamp: u64,
+    pub version: u64,
+    pub encoding_scheme: Vec<EncodingSchemeForm>,
+    pub peers: Vec<AnnouncedPeer>,
+}
+
+/// Announcement whose signature was checked, returned by
+/// `Announcement::verify`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifiedAnnouncement(Announcement);
+
+impl VerifiedAnnouncement {
+    pub fn announcement(&self) -> &Announcement {
+        &self.0
+    }
+}
+
+fn link_state_to_byte(state: Reachability) -> u8 {
+    match state {
+        Reachability::Unconfirmed => 0,
+        Reachability::Reachable => 1,
+        Reachability::Unreachable => 2,
+    }
+}
+
+fn link_state_from_byte(byte: u8) -> Result<Reachability, AnnouncementError> {
+    match byte {
+        0 => Ok(Reachability::Unconfirmed),
+        1 => Ok(Reachability::Reachable),
+        2 => Ok(Reachability::Unreachable),
+        _ => Err(AnnouncementError::InvalidLinkState(byte)),
+    }
+}
+
+fn u32_be(data: &[u8]) -> u32 {
+    data[..4].iter().fold(0, |acc, byte| (acc << 8) | *byte as u32)
+}
+
+fn u64_be(data: &[u8]) -> u64 {
+    data[..8].iter().fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+/// Ed25519 key of the scalar of this X25519 secret key. The hash
+/// prefix (used to derive nonces) is taken from the hash of the secret
+/// key, like the second half of an Ed25519 expanded key.
+fn signing_key(secret_key: &[u8; 32]) -> (ExpandedSecretKey, VerifyingKey) {
+    let mut hash_prefix = [0u8; 32];
+    hash_prefix.copy_from_slice(&Sha512::digest(secret_key)[32..]);
+    let expanded = ExpandedSecretKey {
+        scalar: Scalar::from_bytes_mod_order(clamp_integer(*secret_key)),
+        hash_prefix: hash_prefix,
+    };
+    let verifying_key = VerifyingKey::from(&expanded);
+    (expanded, verifying_key)
+}
+
+impl Announcement {
+    /// Announces the peers of this router, with their reachability in
+    /// its node store.
+    pub fn from_router(router: &Router, public_key: [u8; PUBLIC_KEY_LENGTH], timestamp: u64) -> Announcement {
+        let mut peers: Vec<AnnouncedPeer> = router.peers().iter().map(|(address, node)| AnnouncedPeer {
+            public_key: *node.public_key(),
+            label: *node.path(),
+            version: node.version(),
+            state: router.node_store().reachability(address).unwrap_or(Reachability::Unconfirmed),
+        }).collect();
+        peers.sort_by_key(|peer| peer.label);
+        Announcement {
+            public_key: public_key,
+            timestamp: timestamp,
+            version: PROTOCOL_VERSION as u64,
+            encoding_scheme: encoding_scheme_forms(),
+            peers: peers,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public_key)
+    }
+
+    /// Everything but the signature.
+    fn encode_content(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LENGTH + 1 + FORM_LENGTH*self.encoding_scheme.len() + PEER_LENGTH*self.peers.len());
+        data.extend(&self.public_key);
+        data.extend(&self.timestamp.to_be_bytes());
+        data.extend(&self.version.to_be_bytes());
+        data.push(self.encoding_scheme.len() as u8);
+        for form in &self.encoding_scheme {
+            data.push(form.bit_count as u8);
+            data.push(form.prefix_length as u8);
+            data.extend(&(form.prefix as u32).to_be_bytes());
+        }
+        for peer in &self.peers {
+            data.extend(&peer.public_key);
+            data.extend(&peer.label);
+            data.extend(&peer.version.to_be_bytes());
+            data.push(link_state_to_byte(peer.state));
+        }
+        data
+    }
+
+    /// Serializes and signs the announcement with the announcer's
+    /// secret key.
+    pub fn sign(&self, secret_key: &[u8; 32]) -> Result<Vec<u8>, AnnouncementError> {
+        let (expanded, verifying_key) = signing_key(secret_key);
+        if verifying_key.to_montgomery().to_bytes() != self.public_key {
+            return Err(AnnouncementError::KeyMismatch)
+        }
+        let content = self.encode_content();
+        let signature = raw_sign::<Sha512>(&expanded, &content, &verifying_key);
+        let mut data = signature.to_bytes().to_vec();
+        data.extend(content);
+        Ok(data)
+    }
+
+    /// Parses an announcement, and checks it is signed by its announcer.
+    pub fn verify(data: &[u8]) -> Result<VerifiedAnnouncement, AnnouncementError> {
+        if data.len() < HEADER_LENGTH + 1 {
+            return Err(AnnouncementError::Truncated)
+        }
+        let mut signature = [0u8; SIGNATURE_LENGTH];
+        signature.copy_from_slice(&data[..SIGNATURE_LENGTH]);
+        let signature = Signature::from_bytes(&signature);
+        let content = &data[SIGNATURE_LENGTH..];
+
+        let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+        public_key.copy_from_slice(&content[..PUBLIC_KEY_LENGTH]);
+        if !Address::from_public_key(&public_key).is_valid() {
+            return Err(AnnouncementError::InvalidPublicKey)
+        }
+        let verified = (0..2).any(|sign| {
+            MontgomeryPoint(public_key).to_edwards(sign)
+                    .and_then(|point| VerifyingKey::from_bytes(&point.compress().to_bytes()).ok())
+                    .map_or(false, |verifying_key| verifying_key.verify(content, &signature).is_ok())
+        });
+        if !verified {
+            return Err(AnnouncementError::InvalidSignature)
+        }
+
+        let timestamp = u64_be(&content[PUBLIC_KEY_LENGTH..]);
+        let version = u64_be(&content[PUBLIC_KEY_LENGTH+8..]);
+        let mut rest = &content[PUBLIC_KEY_LENGTH+16..];
+        let nb_forms = rest[0] as usize;
+        rest = &rest[1..];
+        if rest.len() < nb_forms*FORM_LENGTH {
+            return Err(AnnouncementError::Truncated)
+        }
+        let encoding_scheme = rest[..nb_forms*FORM_LENGTH].chunks(FORM_LENGTH).map(|form| EncodingSchemeForm {
+            bit_count: form[0].into(),
+            prefix_length: form[1].into(),
+            prefix: u32_be(&form[2..]).into(),
+        }).collect();
+        rest = &rest[nb_forms*FORM_LENGTH..];
+        if rest.len() % PEER_LENGTH != 0 {
+            return Err(AnnouncementError::Truncated)
+        }
+        let peers = rest.chunks(PEER_LENGTH).map(|peer| {
+            let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+            public_key.copy_from_slice(&peer[..PUBLIC_KEY_LENGTH]);
+            let mut label = [0u8; 8];
+            label.copy_from_slice(&peer[PUBLIC_KEY_LENGTH..PUBLIC_KEY_LENGTH+8]);
+            Ok(AnnouncedPeer {
+                public_key: public_key,
+                label: label,
+                version: u64_be(&peer[PUBLIC_KEY_LENGTH+8..]),
+                state: link_state_from_byte(peer[PEER_LENGTH-1])?,
+            })
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(VerifiedAnnouncement(Announcement {
+            public_key: public_key,
+            timestamp: timestamp,
+            version: version,
+            encoding_scheme: encoding_scheme,
+            peers: peers,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use label::{path_from_u64, path_to_u64};
+    use node::Node;
+    use node_store::NodeStore;
+
+    const SECRET_KEY_A: [u8; 32] = [
+        0xac, 0x3e, 0x53, 0xb5, 0x18, 0xe6, 0x84, 0x49, 0x69, 0x2b, 0x0b, 0x2f, 0x29, 0x26, 0xef, 0x2f,
+        0xdc, 0x1e, 0xac, 0x5b, 0x9d, 0xbd, 0x10, 0xa4, 0x81, 0x14, 0x26, 0x3b, 0x8c, 0x8e, 0xd1, 0x2e];
+    const SECRET_KEY_B: [u8; 32] = [
+        0x10, 0x38, 0x0b, 0x7f, 0x5e, 0xa0, 0x9e, 0xcd, 0xc1, 0x5c, 0x09, 0x8b, 0x41, 0x15, 0x84, 0xaa,
+        0x35, 0xa2, 0xa3, 0xf1, 0xb9, 0x57, 0x9b, 0x26, 0x67, 0xb4, 0xbb, 0x1a, 0x17, 0x91, 0x91, 0xa0];
+
+    fn public_key(secret_key: &[u8; 32]) -> [u8; PUBLIC_KEY_LENGTH] {
+        signing_key(secret_key).1.to_montgomery().to_bytes()
+    }
+
+    #[test]
+    fn test_public_key() {
+        // Same as X25519
+        let address = format!("{}", Address::from_public_key(&public_key(&SECRET_KEY_B)));
+        assert_eq!(address, "fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6");
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (public_key_a, public_key_b) = (public_key(&SECRET_KEY_A), public_key(&SECRET_KEY_B));
+        let mut router = Router::new(Address::from_public_key(&public_key_a));
+        let peer = Node::new(public_key_b, path_from_u64(0b1_011), 18);
+        router.add_peer(peer.clone());
+        router.node_store_mut().mark_reachable(&peer.address());
+
+        let announcement = Announcement::from_router(&router, public_key_a, 1234);
+        assert_eq!(announcement.peers[0].state, Reachability::Reachable);
+        let data = announcement.sign(&SECRET_KEY_A).unwrap();
+        let verified = Announcement::verify(&data).unwrap();
+        assert_eq!(verified.announcement(), &announcement);
+        assert_eq!(announcement.sign(&SECRET_KEY_B), Err(AnnouncementError::KeyMismatch));
+
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() = 2;
+        assert_eq!(Announcement::verify(&tampered), Err(AnnouncementError::InvalidSignature));
+        assert_eq!(Announcement::verify(&data[..data.len()-1]), Err(AnnouncementError::InvalidSignature));
+        assert_eq!(Announcement::verify(&data[..100]), Err(AnnouncementError::Truncated));
+
+        // A node with a path to A learns a path to B.
+        let mut node_store = NodeStore::new(Address::new(&[0xfc; 16]));
+        assert!(node_store.add_announcement(&path_from_u64(0b1_101), &verified));
+        assert_eq!(node_store.nodes().len(), 2);
+        let (_, node) = node_store.nodes().into_iter().find(|&(address, _)| *address == peer.address()).unwrap();
+        assert_eq!(path_to_u64(node.path()), 0b1_011_101);
+
+        // Replays and older announcements are ignored.
+        assert!(!node_store.add_announcement(&path_from_u64(0b1_101), &verified));
+        router.node_store_mut().mark_unreachable(&peer.address());
+        let old = Announcement::from_router(&router, public_key_a, 1000);
+        assert_eq!(old.peers[0].state, Reachability::Unreachable);
+        let old = Announcement::verify(&old.sign(&SECRET_KEY_A).unwrap()).unwrap();
+        assert!(!node_store.add_announcement(&path_from_u64(0b1_101), &old));
+        assert_eq!(node_store.reachability(&peer.address()), Some(Reachability::Unconfirmed));
+    }
+}
@@ -0,0 +1,367 @@
+//! Asynchronous facade of the `Driver`, enabled by the `async` feature.
+//!
+//! `AsyncRouter::lookup` returns a future resolving when the iterative
+//! lookup is over. Packets are sent through a user-provided `Transport`;
+//! packets received from the network, and time passing, are fed with
+//! `AsyncRouter::handle_input`, typically from the application's
+//! receive loop and a timer.
+//!
+//! This does not depend on any particular runtime.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use fcp_switching::route_packet::RoutePacket;
+use fcp_switching::operation::Label;
+
+use node::{Address, Node};
+use router::Router;
+use driver::{Driver, Input, Action, LookupError};
+use event::SendCallback;
+
+/// Sends packets requested by the router to the network.
+pub trait Transport {
+    /// Sends a route packet to a node, through an end-to-end session.
+    fn send_to_node(&mut self, node: &Node, packet: RoutePacket);
+    /// Sends a route packet through the end-to-end session of the node
+    /// at this path.
+    fn send_to_label(&mut self, label: &Label, packet: RoutePacket);
+}
+
+/// A lookup future waiting for its result.
+struct Waiter {
+    target: Address,
+    result: Option<Result<Node, LookupError>>,
+    waker: Option<Waker>,
+}
+
+/// Packet to send through the `Transport`.
+enum Outgoing {
+    ToNode(Node, RoutePacket),
+    ToLabel(Label, RoutePacket),
+}
+
+struct Inner {
+    driver: Driver<SendCallback>,
+    next_waiter_id: u64,
+    waiters: HashMap<u64, Waiter>,
+    /// Packets waiting to be sent.
+    outbox: Vec<Outgoing>,
+    /// Whether a thread is sending packets of the outbox.
+    sending: bool,
+}
+
+impl Inner {
+    /// Moves packets to send to the outbox, and wakes the futures of
+    /// completed lookups.
+    fn process_actions(&mut self) {
+        while let Some(action) = self.driver.poll_action() {
+            match action {
+                Action::SendToNode { node, packet } => self.outbox.push(Outgoing::ToNode(node, packet)),
+                Action::SendToLabel { label, packet } => self.outbox.push(Outgoing::ToLabel(label, packet)),
+                Action::LookupCompleted { target, result } => {
+                    for waiter in self.waiters.values_mut() {
+                        if waiter.target == target && waiter.result.is_none() {
+                            waiter.result = Some(result.clone());
+                            if let Some(waker) = waiter.waker.take() {
+                                waker.wake();
+                            }
+                        }
+                    }
+                }
+                Action::NodeDiscovered { .. } |
+                Action::PingCompleted { .. } |
+                Action::GetPeersCompleted { .. } |
+                Action::FindNodeCompleted { .. } |
+                Action::LookupQueried { .. } |
+                Action::LookupReplied { .. } => (),
+            }
+        }
+    }
+}
+
+/// Shared handle to a `Driver` and its transport. Cloning it gives
+/// another handle to the same router.
+///
+/// The transport is called without holding the lock on the driver, so
+/// it may feed replies back with `handle_input` right away.
+pub struct AsyncRouter<T: Transport> {
+    inner: Arc<Mutex<Inner>>,
+    transport: Arc<Mutex<T>>,
+}
+
+impl<T: Transport> Clone for AsyncRouter<T> {
+    fn clone(&self) -> AsyncRouter<T> {
+        AsyncRouter { inner: self.inner.clone(), transport: self.transport.clone() }
+    }
+}
+
+impl<T: Transport> AsyncRouter<T> {
+    /// The driver's node store callbacks must be `Send`, as futures
+    /// may be polled on any thread; create its router with
+    /// `Router::with_observers(address, Observers::default())`.
+    pub fn new(driver: Driver<SendCallback>, transport: T) -> AsyncRouter<T> {
+        let inner = Inner {
+            driver: driver,
+            next_waiter_id: 0,
+            waiters: HashMap::new(),
+            outbox: Vec::new(),
+            sending: false,
+        };
+        AsyncRouter { inner: Arc::new(Mutex::new(inner)), transport: Arc::new(Mutex::new(transport)) }
+    }
+
+    /// Feeds an event (received packet, timer tick, peer up/down) to
+    /// the driver, and performs the resulting actions.
+    pub fn handle_input(&self, input: Input) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.driver.handle_input(input);
+            inner.process_actions();
+        }
+        self.flush();
+    }
+
+    /// Runs a function on the router, eg. to read its node store or
+    /// subscribe to its events.
+    pub fn with_router<F: FnOnce(&mut Router<SendCallback>) -> R, R>(&self, f: F) -> R {
+        let res = {
+            let mut inner = self.inner.lock().unwrap();
+            let res = f(inner.driver.router_mut());
+            inner.process_actions();
+            res
+        };
+        self.flush();
+        res
+    }
+
+    /// Looks for the node with this address. The future resolves when
+    /// the node is found, or when there is no node left to ask.
+    pub fn lookup(&self, target: Address) -> LookupFuture {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_waiter_id;
+            inner.next_waiter_id += 1;
+            // Register before starting the lookup, as it may complete
+            // right away.
+            inner.waiters.insert(id, Waiter { target: target.clone(), result: None, waker: None });
+            inner.driver.handle_input(Input::Lookup { target: target });
+            inner.process_actions();
+            id
+        };
+        self.flush();
+        LookupFuture { inner: self.inner.clone(), id: id }
+    }
+
+    /// Sends the packets of the outbox, unless another call is already
+    /// doing it (possibly further up the stack, if the transport fed a
+    /// reply back); it will then send them too.
+    fn flush(&self) {
+        loop {
+            let outgoing = {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.sending || inner.outbox.is_empty() {
+                    return;
+                }
+                inner.sending = true;
+                ::std::mem::replace(&mut inner.outbox, Vec::new())
+            };
+            {
+                let mut transport = self.transport.lock().unwrap();
+                for packet in outgoing {
+                    match packet {
+                        Outgoing::ToNode(node, packet) => transport.send_to_node(&node, packet),
+                        Outgoing::ToLabel(label, packet) => transport.send_to_label(&label, packet),
+                    }
+                }
+            }
+            self.inner.lock().unwrap().sending = false;
+        }
+    }
+}
+
+/// Future returned by `AsyncRouter::lookup`.
+pub struct LookupFuture {
+    inner: Arc<Mutex<Inner>>,
+    id: u64,
+}
+
+impl Future for LookupFuture {
+    type Output = Result<Node, LookupError>;
+
+    /// Once the result was returned, polling again returns
+    /// `Poll::Pending` forever.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Node, LookupError>> {
+        let mut inner = self.inner.lock().unwrap();
+        let done = match inner.waiters.get_mut(&self.id) {
+            Some(waiter) => match waiter.result.take() {
+                Some(result) => Some(result),
+                None => {
+                    waiter.waker = Some(cx.waker().clone());
+                    None
+                }
+            },
+            None => return Poll::Pending,
+        };
+        match done {
+            Some(result) => {
+                inner.waiters.remove(&self.id);
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for LookupFuture {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.waiters.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+    use std::task::{RawWaker, RawWakerVTable};
+
+    use driver::DriverConfig;
+    use event::{NodeStoreEvent, Observers};
+    use label::path_from_u64;
+
+    fn new_driver(public_key: [u8; 32]) -> Driver<SendCallback> {
+        let router = Router::with_observers(Address::from_public_key(&public_key), Observers::default());
+        Driver::new(router, DriverConfig::default())
+    }
+
+    fn noop_clone(_: *const ()) -> RawWaker {
+        RawWaker::new(ptr::null(), &NOOP_VTABLE)
+    }
+    fn noop(_: *const ()) {
+    }
+    static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = unsafe { Waker::from_raw(noop_clone(ptr::null())) };
+        let mut cx = Context::from_waker(&waker);
+        Pin::new(future).poll(&mut cx)
+    }
+
+    /// Stores sent packets, so the test can deliver them.
+    struct RecordingTransport {
+        sent: Arc<Mutex<Vec<RoutePacket>>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn send_to_node(&mut self, _node: &Node, packet: RoutePacket) {
+            self.sent.lock().unwrap().push(packet);
+        }
+        fn send_to_label(&mut self, _label: &Label, packet: RoutePacket) {
+            self.sent.lock().unwrap().push(packet);
+        }
+    }
+
+    fn new_router(public_key: [u8; 32]) -> (AsyncRouter<RecordingTransport>, Arc<Mutex<Vec<RoutePacket>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        (AsyncRouter::new(new_driver(public_key), RecordingTransport { sent: sent.clone() }), sent)
+    }
+
+    #[test]
+    fn test_lookup() {
+        let (pk_a, pk_b, pk_c) = ([1; 32], [2; 32], [3; 32]);
+        let (a, sent_a) = new_router(pk_a);
+        let (b, sent_b) = new_router(pk_b);
+        let nb_added = Arc::new(Mutex::new(0));
+        {
+            let nb_added = nb_added.clone();
+            a.with_router(|router| router.subscribe(Box::new(move |event| {
+                if let NodeStoreEvent::NodeAdded { .. } = *event {
+                    *nb_added.lock().unwrap() += 1;
+                }
+            })));
+        }
+        a.handle_input(Input::PeerUp { node: Node::new(pk_b, path_from_u64(0b1_011), 18) });
+        b.handle_input(Input::PeerUp { node: Node::new(pk_c, path_from_u64(0b1_101), 18) });
+
+        let mut lookup = a.lookup(Address::from_public_key(&pk_c));
+        assert!(poll_once(&mut lookup).is_pending());
+
+        let query = sent_a.lock().unwrap().pop().unwrap();
+        b.handle_input(Input::RoutePacket { label: path_from_u64(0b1_110), public_key: pk_a, packet: query, now: 0 });
+        let reply = sent_b.lock().unwrap().pop().unwrap();
+        a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: reply, now: 0 });
+
+        match poll_once(&mut lookup) {
+            Poll::Ready(Ok(node)) => assert_eq!(node.public_key(), &pk_c),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // B, then C.
+        assert_eq!(*nb_added.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_lookup_empty_table() {
+        let (a, _sent_a) = new_router([1; 32]);
+        let mut lookup = a.lookup(Address::from_public_key(&[3; 32]));
+        match poll_once(&mut lookup) {
+            Poll::Ready(Err(LookupError::NoNodes)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    /// Delivers packets to the other router right away.
+    struct LoopbackTransport {
+        /// Other router, and the path and public key it sees us with.
+        peer: Arc<Mutex<Option<(AsyncRouter<LoopbackTransport>, Label, [u8; 32])>>>,
+    }
+
+    impl LoopbackTransport {
+        fn deliver(&mut self, packet: RoutePacket) {
+            let peer = self.peer.lock().unwrap().clone();
+            if let Some((router, label, public_key)) = peer {
+                router.handle_input(Input::RoutePacket { label: label, public_key: public_key, packet: packet, now: 0 });
+            }
+        }
+    }
+
+    impl Transport for LoopbackTransport {
+        fn send_to_node(&mut self, _node: &Node, packet: RoutePacket) {
+            self.deliver(packet);
+        }
+        fn send_to_label(&mut self, _label: &Label, packet: RoutePacket) {
+            self.deliver(packet);
+        }
+    }
+
+    #[test]
+    fn test_synchronous_transport() {
+        fn assert_send<T: Send>() {}
+        assert_send::<AsyncRouter<LoopbackTransport>>();
+        assert_send::<LookupFuture>();
+
+        let (pk_a, pk_b, pk_c) = ([1; 32], [2; 32], [3; 32]);
+        let (peer_a, peer_b) = (Arc::new(Mutex::new(None)), Arc::new(Mutex::new(None)));
+        let new_router = |public_key: [u8; 32], peer| AsyncRouter::new(new_driver(public_key), LoopbackTransport { peer: peer });
+        let a = new_router(pk_a, peer_a.clone());
+        let b = new_router(pk_b, peer_b.clone());
+        *peer_a.lock().unwrap() = Some((b.clone(), path_from_u64(0b1_110), pk_a));
+        *peer_b.lock().unwrap() = Some((a.clone(), path_from_u64(0b1_011), pk_b));
+        a.handle_input(Input::PeerUp { node: Node::new(pk_b, path_from_u64(0b1_011), 18) });
+        b.handle_input(Input::PeerUp { node: Node::new(pk_c, path_from_u64(0b1_101), 18) });
+
+        // B replies while A is still sending its query.
+        let mut lookup = a.lookup(Address::from_public_key(&pk_c));
+        match poll_once(&mut lookup) {
+            Poll::Ready(Ok(node)) => assert_eq!(node.public_key(), &pk_c),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(poll_once(&mut lookup).is_pending());
+        *peer_a.lock().unwrap() = None;
+        *peer_b.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,72 @@
+//! Base32 encoding used by cjdns for public keys. It uses its own
+//! alphabet, and reads bits starting from the least significant one.
+//! See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/util/Base32.h
+
+const ALPHABET: &[u8; 32] = b"0123456789bcdfghjklmnpqrstuvwxyz";
+
+/// Returns the value of a base32 character, if it is in the alphabet.
+fn char_value(c: u8) -> Option<u32> {
+    let c = c.to_ascii_lowercase();
+    ALPHABET.iter().position(|x| *x == c).map(|pos| pos as u32)
+}
+
+/// Encodes bytes in cjdns' base32.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity((bytes.len()*8+4)/5);
+    let mut work = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        work |= (*byte as u32) << bits;
+        bits += 8;
+        while bits >= 5 {
+            res.push(ALPHABET[(work & 31) as usize] as char);
+            work >>= 5;
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        res.push(ALPHABET[(work & 31) as usize] as char);
+    }
+    res
+}
+
+/// Decodes a string encoded with cjdns' base32. Fails if it contains
+/// characters out of the alphabet, or if its trailing bits are not
+/// a valid padding.
+pub fn decode(s: &str) -> Result<Vec<u8>, ()> {
+    let mut res = Vec::with_capacity(s.len()*5/8);
+    let mut work = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        work |= char_value(c).ok_or(())? << bits;
+        bits += 5;
+        if bits >= 8 {
+            res.push((work & 0xff) as u8);
+            work >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits >= 5 || work != 0 {
+        return Err(());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let key = "2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0";
+        let bytes = decode(key).unwrap();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(encode(&bytes), key);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(decode("2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvva"), Err(()));
+        assert_eq!(decode("2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvvz"), Err(()));
+    }
+}
@@ -0,0 +1,183 @@
+//! Minimal bencode encoder/decoder, used by the admin interface.
+//! See http://www.bittorrent.org/beps/bep_0003.html#bencoding
+
+use std::collections::BTreeMap;
+
+/// Nesting depth above which decoding fails, to avoid overflowing
+/// the stack on malicious input.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// Builds a byte string from a string.
+    pub fn string(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    /// Builds a dictionary from a list of `(key, value)` pairs.
+    pub fn dict(items: Vec<(&str, Value)>) -> Value {
+        Value::Dict(items.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect())
+    }
+
+    /// Returns the value of a key, if this is a dictionary containing it.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Dict(ref d) => d.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::Bytes(ref b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| ::std::str::from_utf8(b).ok())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.encode_to(&mut res);
+        res
+    }
+
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            Value::Int(i) => out.extend(format!("i{}e", i).into_bytes()),
+            Value::Bytes(ref b) => {
+                out.extend(format!("{}:", b.len()).into_bytes());
+                out.extend(b);
+            }
+            Value::List(ref l) => {
+                out.push(b'l');
+                for item in l {
+                    item.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(ref d) => {
+                out.push(b'd');
+                for (key, value) in d {
+                    Value::Bytes(key.clone()).encode_to(out);
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// Decodes a bencoded value. Fails if the input is invalid or has
+    /// trailing bytes.
+    pub fn decode(input: &[u8]) -> Result<Value, ()> {
+        let (value, rest) = decode_value(input, 0)?;
+        if rest.is_empty() { Ok(value) } else { Err(()) }
+    }
+
+    /// Decodes the bencoded value at the start of the input, and
+    /// returns it with the bytes following it.
+    pub fn decode_prefix(input: &[u8]) -> Result<(Value, &[u8]), ()> {
+        decode_value(input, 0)
+    }
+}
+
+/// Reads digits (and an optional minus sign) up to the terminator.
+fn read_number(input: &[u8], terminator: u8) -> Result<(i64, &[u8]), ()> {
+    let end = input.iter().position(|c| *c == terminator).ok_or(())?;
+    let s = ::std::str::from_utf8(&input[..end]).map_err(|_| ())?;
+    if s.is_empty() || s.starts_with('+') {
+        return Err(())
+    }
+    let number = s.parse().map_err(|_| ())?;
+    Ok((number, &input[end+1..]))
+}
+
+fn decode_value(input: &[u8], depth: usize) -> Result<(Value, &[u8]), ()> {
+    if depth > MAX_DEPTH {
+        return Err(())
+    }
+    match input.first() {
+        Some(&b'i') => {
+            let (i, rest) = read_number(&input[1..], b'e')?;
+            Ok((Value::Int(i), rest))
+        }
+        Some(&b'l') => {
+            let mut items = Vec::new();
+            let mut rest = &input[1..];
+            while rest.first() != Some(&b'e') {
+                let (item, new_rest) = decode_value(rest, depth+1)?;
+                items.push(item);
+                rest = new_rest;
+            }
+            Ok((Value::List(items), &rest[1..]))
+        }
+        Some(&b'd') => {
+            let mut items = BTreeMap::new();
+            let mut rest = &input[1..];
+            while rest.first() != Some(&b'e') {
+                let (key, new_rest) = decode_value(rest, depth+1)?;
+                let key = match key {
+                    Value::Bytes(key) => key,
+                    _ => return Err(()),
+                };
+                let (value, new_rest) = decode_value(new_rest, depth+1)?;
+                items.insert(key, value);
+                rest = new_rest;
+            }
+            Ok((Value::Dict(items), &rest[1..]))
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (len, rest) = read_number(input, b':')?;
+            if len < 0 || len as usize > rest.len() {
+                return Err(())
+            }
+            let len = len as usize;
+            Ok((Value::Bytes(rest[..len].to_vec()), &rest[len..]))
+        }
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let value = Value::dict(vec![
+            ("q", Value::string("NodeStore_dumpTable")),
+            ("args", Value::dict(vec![("page", Value::Int(-2))])),
+            ("list", Value::List(vec![Value::Int(1), Value::string("")])),
+            ]);
+        let encoded = value.encode();
+        assert_eq!(encoded, b"d4:argsd4:pagei-2ee4:listli1e0:e1:q19:NodeStore_dumpTablee".to_vec());
+        assert_eq!(Value::decode(&encoded), Ok(value));
+        assert_eq!(Value::decode_prefix(b"i1ei2e"), Ok((Value::Int(1), &b"i2e"[..])));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Value::decode(b"i12"), Err(()));
+        assert_eq!(Value::decode(b"5:abc"), Err(()));
+        assert_eq!(Value::decode(b"di1ei2ee"), Err(()));
+        assert_eq!(Value::decode(b"i1ei2e"), Err(()));
+        assert_eq!(Value::decode(b"l"), Err(()));
+        assert_eq!(Value::decode(&[b'l'; 100]), Err(()));
+    }
+}
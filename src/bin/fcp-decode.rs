@@ -0,0 +1,89 @@
+//! Decodes captured routing traffic, and prints the content of each
+//! frame down to route packets (see the `decoder` module).
+//!
+//! Usage: `fcp-decode [--layer udp|switch|data|route] [--key <secret key>]... [--password <password>]... <file>`
+//!
+//! The file is either a pcap file, whose UDP datagrams are decoded as
+//! outer CryptoAuth packets, or a hex dump with frames separated by
+//! blank lines, decoded as switch packets unless `--layer` is given.
+//! `-` reads the hex dump from the standard input.
+//! `--key` takes the secret key of a node (as in `fcp-routerd`'s
+//! configuration) and `--password` a password of its peers, to decrypt
+//! the handshakes they take part in.
+
+extern crate fcp_routing;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::str::FromStr;
+
+use fcp_routing::decoder::{self, Decoder, Layer, PcapError};
+use fcp_routing::node::secret_key_from_hex;
+
+fn usage() -> ! {
+    eprintln!("Usage: fcp-decode [--layer udp|switch|data|route] [--key <secret key>]... [--password <password>]... <file>");
+    process::exit(1);
+}
+
+fn main() {
+    let mut decoder = Decoder::new();
+    let mut layer = None;
+    let mut filename = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--layer" => layer = match Layer::from_str(&args.next().unwrap_or_else(|| usage())) {
+                Ok(layer) => Some(layer),
+                Err(()) => usage(),
+            },
+            "--key" => match secret_key_from_hex(&args.next().unwrap_or_else(|| usage())) {
+                Some(secret_key) => decoder.add_secret_key(secret_key),
+                None => {
+                    eprintln!("Invalid secret key, expected 64 hexadecimal digits.");
+                    process::exit(1);
+                }
+            },
+            "--password" => decoder.add_password(&args.next().unwrap_or_else(|| usage())),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let filename = filename.unwrap_or_else(|| usage());
+
+    let mut data = Vec::new();
+    let res = if filename == "-" {
+        io::stdin().read_to_end(&mut data).map(|_| ())
+    }
+    else {
+        fs::read(&filename).map(|content| data = content)
+    };
+    if let Err(e) = res {
+        eprintln!("Could not read {}: {}", filename, e);
+        process::exit(1);
+    }
+
+    match decoder::parse_pcap(&data) {
+        Ok(frames) => {
+            for frame in frames {
+                println!("{}.{:06} {} -> {}", frame.timestamp / 1_000_000, frame.timestamp % 1_000_000,
+                        frame.source, frame.destination);
+                print!("{}", decoder.decode(&frame.payload, layer.unwrap_or(Layer::Udp)));
+                println!();
+            }
+        }
+        Err(PcapError::InvalidMagic) => {
+            let dump = String::from_utf8_lossy(&data);
+            for (i, frame) in decoder::parse_hex_dump(&dump).iter().enumerate() {
+                println!("Frame {}", i + 1);
+                print!("{}", decoder.decode(frame, layer.unwrap_or(Layer::Switch)));
+                println!();
+            }
+        }
+        Err(e) => {
+            eprintln!("Could not read {}: {}", filename, e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Generates a keypair whose address is in fc00::/8, and prints it in
+//! the format of `fcp-routerd`'s configuration.
+//!
+//! Usage: `fcp-keygen [--prefix <hexadecimal digits>] [--threads <number>]`
+//!
+//! With `--prefix`, keys are generated until the address starts with
+//! these digits (eg. `fc12:34`), on all CPU cores unless `--threads`
+//! is given. Each digit makes the search 16 times longer.
+
+extern crate fcp_routing;
+
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+
+use fcp_routing::keygen::{self, AddressPrefix};
+
+fn usage() -> ! {
+    eprintln!("Usage: fcp-keygen [--prefix <hexadecimal digits>] [--threads <number>]");
+    process::exit(1);
+}
+
+fn main() {
+    let mut prefix = AddressPrefix::fc00();
+    let mut nb_threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_ref() {
+            "--prefix" => prefix = match AddressPrefix::from_str(&value) {
+                Ok(prefix) => prefix,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            },
+            "--threads" => nb_threads = match value.parse() {
+                Ok(n) if n > 0 => n,
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    if prefix.len() > AddressPrefix::fc00().len() {
+        eprintln!("Searching for a {}-digit prefix on {} threads...", prefix.len(), nb_threads);
+    }
+    let keypair = keygen::search(&prefix, nb_threads);
+    println!("// Address: {}", keypair.address());
+    println!("\"privateKey\": \"{}\",", keypair.secret_key_hex());
+    println!("\"publicKey\": \"{}\",", keypair.public_key_base32());
+}
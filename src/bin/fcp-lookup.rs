@@ -0,0 +1,118 @@
+//! Looks up an address through the admin interface of a running
+//! router (see `fcp-routerd`), and prints the progress of the lookup.
+//!
+//! Usage: `fcp-lookup [--admin <address:port>] [--password <password>] <fc00::/8 address>`
+
+extern crate fcp_routing;
+
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use fcp_routing::admin::AdminClient;
+use fcp_routing::bencode::Value;
+use fcp_routing::node::Address;
+
+const DEFAULT_ADMIN: &str = "127.0.0.1:11234";
+
+fn usage() -> ! {
+    eprintln!("Usage: fcp-lookup [--admin <address:port>] [--password <password>] <fc00::/8 address>");
+    process::exit(1);
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).unwrap_or("?")
+}
+
+fn list_field<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    match value.get(key) {
+        Some(&Value::List(ref items)) => items,
+        _ => &[],
+    }
+}
+
+/// Prints a node of the reply: its name, address, and distance to the
+/// target.
+fn print_node(indent: &str, node: &Value) {
+    println!("{}{} ({})", indent, str_field(node, "addr"), str_field(node, "ip"));
+    println!("{}    distance {}", indent, str_field(node, "distance"));
+}
+
+fn main() {
+    let mut admin = DEFAULT_ADMIN.to_owned();
+    let mut password = None;
+    let mut target = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--admin" => admin = args.next().unwrap_or_else(|| usage()),
+            "--password" => password = Some(args.next().unwrap_or_else(|| usage())),
+            _ if target.is_none() => target = Some(arg),
+            _ => usage(),
+        }
+    }
+    let target = match target.as_ref().map(|target| Address::from_str(target)) {
+        Some(Ok(ref address)) if address.is_valid() => address.clone(),
+        Some(_) => {
+            eprintln!("Not an fc00::/8 address: {}", target.unwrap());
+            process::exit(1);
+        }
+        None => usage(),
+    };
+
+    let reply = AdminClient::connect(&*admin, password).and_then(|mut client| {
+        // Lookups end when their queries time out, which can take a while.
+        client.set_timeout(Some(Duration::from_secs(120)))?;
+        client.call("RouterModule_traceLookup", Value::dict(vec![("address", Value::string(&target.to_string()))]))
+    });
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("Could not call the admin interface at {}: {}", admin, e);
+            process::exit(1);
+        }
+    };
+
+    println!("Looking up {}", target);
+    for (i, round) in list_field(&reply, "rounds").iter().enumerate() {
+        println!("Round {}:", i + 1);
+        if let Value::List(ref queried) = *round {
+            for node in queried {
+                print_node("    queried ", node);
+                if node.get("timeout").is_some() {
+                    println!("        no reply");
+                }
+                else {
+                    let candidates = list_field(node, "candidates");
+                    println!("        returned {} candidates:", candidates.len());
+                    for candidate in candidates {
+                        print_node("            ", candidate);
+                    }
+                }
+            }
+        }
+    }
+
+    match str_field(&reply, "error") {
+        "none" => {
+            let result = reply.get("result").unwrap_or(&reply);
+            println!("Found {}:", target);
+            println!("    public key: {}", str_field(result, "key"));
+            println!("    path: {}", str_field(result, "routeLabel"));
+            println!("    version: {}", result.get("protocolVersion").and_then(Value::as_int).unwrap_or(0));
+        }
+        "not found" => {
+            println!("Not found: all nodes close to the target were queried, and none of them knew it.");
+            process::exit(2);
+        }
+        "no nodes" => {
+            println!("Not found: the routing table is empty.");
+            process::exit(2);
+        }
+        error => {
+            println!("Lookup failed: {}", error);
+            process::exit(2);
+        }
+    }
+}
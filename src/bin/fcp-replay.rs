@@ -0,0 +1,36 @@
+//! Replays a recording of a router (see `fcp-routerd`'s `record`
+//! option) into a fresh router, and prints the actions triggered by
+//! each event and the final routing table, so runs can be diffed.
+//!
+//! Usage: `fcp-replay <recording>`
+
+extern crate fcp_routing;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use fcp_routing::recording::Recording;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 1 {
+        eprintln!("Usage: fcp-replay <recording>");
+        process::exit(1);
+    }
+    let data = match fs::read(&args[0]) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
+    let recording = match Recording::parse(&data) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
+    print!("{}", recording.replay().transcript());
+}
@@ -0,0 +1,339 @@
+//! Configuration file of the daemon, in JSON (like cjdroute.conf).
+//!
+//! ```json
+//! {
+//!     "privateKey": "<64 hexadecimal digits>",
+//!     "publicKey": "<base32>.k",
+//!     "listen": ["[::1]:12345"],
+//!     "authorizedPasswords": [{"login": "foo", "password": "bar"}],
+//!     "peers": [
+//!         {"address": "[::1]:12346", "publicKey": "<base32>.k", "login": "foo", "password": "bar"}
+//!     ],
+//!     "bootstrap": ["fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6"],
+//!     "router": {"queryTimeout": 5000, "parallelism": 3, "nbClosest": 8, "pingInterval": 10000,
+//!                "mode": "subnode", "supernode": "fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6"},
+//!     "admin": {"bind": "127.0.0.1:11234", "password": "secret"},
+//!     "crawler": {"state": "crawl.json", "dot": "crawl.dot", "queriesPerSecond": 10},
+//!     "record": "router.rec"
+//! }
+//! ```
+//!
+//! Only `privateKey`, `publicKey` and `listen` are required.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+
+use serde_json;
+
+use fcp_routing::node::{Address, PUBLIC_KEY_LENGTH};
+use fcp_routing::driver::DriverConfig;
+use fcp_routing::janitor::JanitorConfig;
+use fcp_routing::keygen::KeyPair;
+use fcp_routing::router::RoutingMode;
+use fcp_routing::crawler;
+
+/// Number of peers our switch can have: it uses 3-bit directors, and
+/// directors 0 and 1 are not used for peers.
+pub const MAX_PEERS: usize = 6;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Json(ref e) => write!(f, "invalid JSON: {}", e),
+            ConfigError::Invalid(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> ConfigError {
+        ConfigError::Json(e)
+    }
+}
+
+/// Login and password a peer can use to connect to us.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuthorizedPassword {
+    pub login: String,
+    pub password: String,
+}
+
+/// Peer we connect to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerConfig {
+    /// UDP address of the peer.
+    pub address: SocketAddr,
+    #[serde(with = "::fcp_routing::serialization::public_key")]
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    pub login: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RouterConfig {
+    /// See `DriverConfig::query_timeout`.
+    pub query_timeout: u64,
+    /// See `DriverConfig::parallelism`.
+    pub parallelism: usize,
+    /// See `DriverConfig::nb_closest`.
+    pub nb_closest: usize,
+    /// Milliseconds between lookups of bootstrap nodes and pings of
+    /// the nodes found.
+    pub ping_interval: u64,
+    /// Routing mode: `dht`, `subnode` or `supernode` (see
+    /// `fcp_routing::router::RoutingMode`).
+    pub mode: String,
+    /// Address of the supernode, in subnode mode.
+    pub supernode: Option<Address>,
+    /// See `DriverConfig::announce_interval`.
+    pub announce_interval: u64,
+    /// Whether to refresh the routing table in the background (see
+    /// `fcp_routing::janitor`). Disabled by default, like in
+    /// `DriverConfig`.
+    pub janitor: bool,
+    /// See `JanitorConfig::max_queries`.
+    pub janitor_max_queries: usize,
+}
+
+impl Default for RouterConfig {
+    fn default() -> RouterConfig {
+        let driver_config = DriverConfig::default();
+        RouterConfig {
+            query_timeout: driver_config.query_timeout,
+            parallelism: driver_config.parallelism,
+            nb_closest: driver_config.nb_closest,
+            ping_interval: 10000,
+            mode: "dht".to_owned(),
+            supernode: None,
+            announce_interval: driver_config.announce_interval,
+            janitor: driver_config.janitor.is_some(),
+            janitor_max_queries: JanitorConfig::default().max_queries,
+        }
+    }
+}
+
+impl RouterConfig {
+    pub fn driver_config(&self) -> DriverConfig {
+        DriverConfig {
+            query_timeout: self.query_timeout,
+            parallelism: self.parallelism,
+            nb_closest: self.nb_closest,
+            announce_interval: self.announce_interval,
+            janitor: if self.janitor {
+                Some(JanitorConfig { max_queries: self.janitor_max_queries, ..JanitorConfig::default() })
+            }
+            else {
+                None
+            },
+        }
+    }
+
+    pub fn routing_mode(&self) -> Result<RoutingMode, ConfigError> {
+        match (self.mode.as_ref(), self.supernode.as_ref()) {
+            ("dht", None) => Ok(RoutingMode::Dht),
+            ("supernode", None) => Ok(RoutingMode::Supernode),
+            ("subnode", Some(supernode)) => Ok(RoutingMode::Subnode { supernode: supernode.clone() }),
+            ("subnode", None) => Err(ConfigError::Invalid("the subnode mode needs the address of a supernode".to_owned())),
+            ("dht", Some(_)) | ("supernode", Some(_)) => Err(ConfigError::Invalid("supernode is only used in subnode mode".to_owned())),
+            _ => Err(ConfigError::Invalid("mode must be dht, subnode or supernode".to_owned())),
+        }
+    }
+}
+
+/// Admin interface (see `fcp_routing::admin`).
+#[derive(Clone, Debug, Deserialize)]
+pub struct AdminConfig {
+    pub bind: SocketAddr,
+    pub password: Option<String>,
+}
+
+/// Crawler mapping the network (see `fcp_routing::crawler`).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CrawlerConfig {
+    /// File the map is saved to, in JSON. If it exists at startup,
+    /// the crawl is resumed from it.
+    pub state: String,
+    /// File the map is exported to, as a Graphviz graph.
+    pub dot: Option<String>,
+    /// See `crawler::CrawlerConfig::queries_per_second`.
+    pub queries_per_second: u64,
+    /// See `crawler::CrawlerConfig::max_in_flight`.
+    pub max_in_flight: usize,
+    /// Milliseconds between saves of the map.
+    pub save_interval: u64,
+    /// Milliseconds to wait after a crawl is over before crawling
+    /// again. If not set, the network is crawled only once.
+    pub recrawl_interval: Option<u64>,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> CrawlerConfig {
+        let crawler_config = crawler::CrawlerConfig::default();
+        CrawlerConfig {
+            state: "crawl.json".to_owned(),
+            dot: None,
+            queries_per_second: crawler_config.queries_per_second,
+            max_in_flight: crawler_config.max_in_flight,
+            save_interval: 10000,
+            recrawl_interval: None,
+        }
+    }
+}
+
+impl CrawlerConfig {
+    pub fn crawler_config(&self) -> crawler::CrawlerConfig {
+        crawler::CrawlerConfig {
+            queries_per_second: self.queries_per_second,
+            max_in_flight: self.max_in_flight,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Secret key, in hexadecimal.
+    pub private_key: String,
+    #[serde(with = "::fcp_routing::serialization::public_key")]
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    /// UDP addresses to listen on. Peers are contacted from the first
+    /// one.
+    pub listen: Vec<SocketAddr>,
+    #[serde(default)]
+    pub authorized_passwords: Vec<AuthorizedPassword>,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    /// Addresses of nodes to look up at startup, and ping regularly.
+    #[serde(default)]
+    pub bootstrap: Vec<Address>,
+    #[serde(default)]
+    pub router: RouterConfig,
+    pub admin: Option<AdminConfig>,
+    pub crawler: Option<CrawlerConfig>,
+    /// File everything fed to the router is recorded to, to replay it
+    /// with `fcp-replay`. Overwritten at startup.
+    pub record: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        Config::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Config, ConfigError> {
+        let config: Config = serde_json::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let secret_key = self.secret_key()?;
+        if KeyPair::from_secret_key(secret_key).public_key != self.public_key {
+            return Err(ConfigError::Invalid("publicKey is not the public key of privateKey".to_owned()))
+        }
+        if self.listen.is_empty() {
+            return Err(ConfigError::Invalid("at least one listen address is required".to_owned()))
+        }
+        if self.peers.len() > MAX_PEERS {
+            return Err(ConfigError::Invalid(format!("at most {} peers are supported", MAX_PEERS)))
+        }
+        if !Address::from_public_key(&self.public_key).is_valid() {
+            return Err(ConfigError::Invalid("the address of the public key is not in fc00::/8".to_owned()))
+        }
+        if self.router.parallelism == 0 || self.router.nb_closest == 0 {
+            return Err(ConfigError::Invalid("parallelism and nbClosest must be positive".to_owned()))
+        }
+        self.router.routing_mode()?;
+        if let Some(ref crawler) = self.crawler {
+            if crawler.queries_per_second == 0 || crawler.max_in_flight < 2 {
+                return Err(ConfigError::Invalid("the crawler needs queriesPerSecond > 0 and maxInFlight >= 2".to_owned()))
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes `private_key`.
+    pub fn secret_key(&self) -> Result<[u8; 32], ConfigError> {
+        KeyPair::from_secret_key_hex(&self.private_key)
+                .map(|key_pair| key_pair.secret_key)
+                .ok_or_else(|| ConfigError::Invalid("privateKey must be 64 hexadecimal digits".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mesh_configs() {
+        for s in &[
+                include_str!("../../../conf/mesh/node-a.json"),
+                include_str!("../../../conf/mesh/node-b.json"),
+                include_str!("../../../conf/mesh/node-c.json"),
+                ] {
+            let config = Config::parse(s).unwrap();
+            assert_eq!(config.router.query_timeout, 5000);
+        }
+        let config = Config::parse(include_str!("../../../conf/mesh/node-a.json")).unwrap();
+        let crawler = config.crawler.unwrap();
+        assert_eq!(crawler.state, "node-a-crawl.json");
+        assert_eq!(crawler.queries_per_second, 10);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let config = r#"{
+            "privateKey": "ac3e53b518e68449692b0b2f2926ef2fdc1eac5b9dbd10a48114263b8c8ed12e",
+            "publicKey": "2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k",
+            "listen": []
+        }"#;
+        match Config::parse(config) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match Config::parse(&config.replace("ac3e", "zz3e")) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Valid key of another node.
+        let wrong_key = config
+                .replace("2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0", "dwzr0g0srh6gm4x0zmb84sr30kc7cqg0pxck6d3tht7sht6dbv80")
+                .replace(r#""listen": []"#, r#""listen": ["[::1]:12345"]"#);
+        match Config::parse(&wrong_key) {
+            Err(ConfigError::Invalid(ref message)) if message.contains("publicKey") => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let subnode = config.replace(r#""listen": []"#, r#""listen": ["[::1]:12345"], "router": {"mode": "subnode"}"#);
+        match Config::parse(&subnode) {
+            Err(ConfigError::Invalid(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let subnode = subnode.replace(r#""subnode""#, r#""subnode", "supernode": "fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6""#);
+        assert!(Config::parse(&subnode).unwrap().router.routing_mode().is_ok());
+        match Config::parse("{}") {
+            Err(ConfigError::Json(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,686 @@
+//! The daemon's switch, and the router running on top of it.
+
+use std::io::{self, BufWriter, Write};
+use std::fs::File;
+use std::net::{UdpSocket, SocketAddr};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fcp_cryptoauth::wrapper::*;
+
+use fcp_switching::switch_packet::SwitchPacket;
+use fcp_switching::switch_packet::Payload as SwitchPayload;
+use fcp_switching::operation::{RoutingDecision, reverse_label};
+use fcp_switching::control::ControlPacket;
+use fcp_switching::data_packet::DataPacket;
+use fcp_switching::data_packet::Payload as DataPayload;
+
+use fcp_routing::node::{Address, Node, DisplayPath};
+use fcp_routing::router::{Router, encoding_scheme_forms};
+use fcp_routing::driver::{Driver, DriverConfig, Input, Action};
+use fcp_routing::session::{SessionManager, SessionAction};
+use fcp_routing::event::NodeStoreEvent;
+use fcp_routing::admin::UdpAdminServer;
+use fcp_routing::crawler::{Crawler, CrawlState};
+use fcp_routing::traceroute::{Traceroute, traceroute_id};
+
+use serde_json;
+
+use config::{Config, ConfigError, CrawlerConfig, MAX_PEERS};
+
+/// Director of the self interface.
+const SELF_INTERFACE: u8 = 0b001;
+
+/// First director used for peers (0 and 1 are not).
+const FIRST_INTERFACE: u8 = 0b010;
+
+/// Milliseconds to sleep when no packet was received.
+const IDLE_SLEEP: u64 = 5;
+
+/// Milliseconds to wait for the pongs of a traceroute.
+const TRACEROUTE_TIMEOUT: u64 = 5000;
+
+/// Used to represent a connection to a *direct peer* of this switch.
+struct Interface {
+    /// Used for routing -- it is the Director.
+    id: u8,
+    /// A point-to-point (aka outer) CryptoAuth session.
+    ca_session: Wrapper<String>,
+    /// The address where to send the UDP packets to.
+    addr: SocketAddr,
+    /// Index of the socket used to talk to this peer.
+    socket: usize,
+}
+
+/// Crawler, and where to save its map.
+struct CrawlerTask {
+    crawler: Crawler,
+    config: CrawlerConfig,
+    next_save: u64,
+    /// When the last crawl was over, if it is.
+    done_at: Option<u64>,
+}
+
+impl CrawlerTask {
+    /// Creates the crawler, resuming from its saved state if any.
+    fn new(config: CrawlerConfig, my_address: Address) -> Result<CrawlerTask, ConfigError> {
+        let crawler = match File::open(&config.state) {
+            Ok(file) => {
+                let state: CrawlState = serde_json::from_reader(file)?;
+                println!("Resuming crawl from {} ({} nodes left).", config.state, state.frontier.len());
+                Crawler::resume(config.crawler_config(), my_address, state)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Crawler::new(config.crawler_config(), my_address),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(CrawlerTask { crawler: crawler, config: config, next_save: 0, done_at: None })
+    }
+
+    /// Writes the map to the state file, and the Graphviz file.
+    fn save(&self) -> io::Result<()> {
+        let state_file = File::create(&self.config.state)?;
+        serde_json::to_writer_pretty(state_file, &self.crawler.state())?;
+        if let Some(ref dot) = self.config.dot {
+            File::create(dot)?.write_all(self.crawler.to_dot().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Sends queries, saves the map when it is time to, and starts a
+    /// new crawl after the previous one.
+    fn tick(&mut self, driver: &mut Driver, now: u64) {
+        self.crawler.tick(driver);
+
+        // The crawl starts when we have peers.
+        let done = self.crawler.is_done() && !self.crawler.nodes().is_empty();
+        let just_done = done && self.done_at.is_none();
+        if just_done {
+            println!("Crawl over: {} nodes, {} links.", self.crawler.nodes().len(), self.crawler.links().len());
+            self.done_at = Some(now);
+        }
+        if just_done || now >= self.next_save {
+            self.next_save = now + self.config.save_interval;
+            if let Err(e) = self.save() {
+                println!("Could not save the crawl: {}", e);
+            }
+        }
+        if let (Some(done_at), Some(interval)) = (self.done_at, self.config.recrawl_interval) {
+            if now >= done_at + interval {
+                self.crawler.recrawl();
+                self.done_at = None;
+            }
+        }
+    }
+}
+
+/// Main data structure of the daemon: a switch, with a router on
+/// its self interface.
+pub struct Daemon {
+    /// The sockets used for receiving and sending UDP packets to peers.
+    sockets: Vec<UdpSocket>,
+    /// Peers
+    interfaces: Vec<Interface>,
+    /// My public key, both for outer and inner CryptoAuth sessions.
+    my_pk: PublicKey,
+    /// My secret key, both for outer and inner CryptoAuth sessions.
+    my_sk: SecretKey,
+    /// CryptoAuth sessions used to talk to switches/routers. Their packets
+    /// themselves are wrapped in SwitchPackets, which are wrapped in the
+    /// outer CryptoAuth sessions.
+    inner_conns: HashMap<u32, Wrapper<String>>,
+    /// Credentials of peers which are allowed to connect to us.
+    allowed_peers: HashMap<Credentials, String>,
+
+    /// Nodes to look up, and ping once found.
+    bootstrap: Vec<Address>,
+    found_nodes: HashMap<Address, Node>,
+    /// Milliseconds between lookups and pings of bootstrap nodes.
+    ping_interval: u64,
+    next_ping: u64,
+
+    /// Router, and the addresses and paths of inner sessions.
+    sessions: SessionManager<DataPacket>,
+    admin: Option<UdpAdminServer>,
+    crawler: Option<CrawlerTask>,
+    /// Traceroutes to nodes that did not answer pings, by id.
+    traceroutes: HashMap<u32, Traceroute>,
+    next_traceroute_id: u32,
+    /// Used to compute timestamps given to the driver.
+    started: Instant,
+}
+
+impl Daemon {
+    /// Binds the sockets, and starts connecting to peers.
+    pub fn new(config: Config) -> Result<Daemon, ConfigError> {
+        let my_sk = SecretKey::from_slice(&config.secret_key()?).unwrap();
+        let my_pk = PublicKey::from_slice(&config.public_key).unwrap();
+
+        let mut allowed_peers = HashMap::new();
+        for authorized in config.authorized_passwords.iter() {
+            let credentials = Credentials::LoginPassword {
+                login: authorized.login.clone().into_bytes(),
+                password: authorized.password.clone().into_bytes(),
+            };
+            allowed_peers.insert(credentials, authorized.login.clone());
+        }
+
+        let mut sockets = Vec::new();
+        for addr in config.listen.iter() {
+            let socket = UdpSocket::bind(addr)?;
+            socket.set_nonblocking(true)?;
+            println!("Listening on {}", addr);
+            sockets.push(socket);
+        }
+
+        let mut interfaces = Vec::new();
+        for (i, peer) in config.peers.iter().enumerate() {
+            let credentials = Credentials::LoginPassword {
+                login: peer.login.clone().into_bytes(),
+                password: peer.password.clone().into_bytes(),
+            };
+            let their_pk = PublicKey::from_slice(&peer.public_key).unwrap();
+            let conn = Wrapper::new_outgoing_connection(
+                    my_pk, my_sk.clone(), their_pk, credentials, Some(allowed_peers.clone()), peer.address.to_string(), None);
+            interfaces.push(Interface { id: FIRST_INTERFACE + i as u8, ca_session: conn, addr: peer.address, socket: 0 });
+        }
+
+        let admin = match config.admin {
+            Some(ref admin_config) => {
+                let admin = UdpAdminServer::bind(admin_config.bind, admin_config.password.clone())?;
+                admin.socket().set_nonblocking(true)?;
+                println!("Admin interface on {}", admin_config.bind);
+                Some(admin)
+            }
+            None => None,
+        };
+
+        let my_address = Address::from_public_key(&config.public_key);
+        let crawler = match config.crawler {
+            Some(ref crawler_config) => Some(CrawlerTask::new(crawler_config.clone(), my_address.clone())?),
+            None => None,
+        };
+
+        let mut router = Router::new(my_address);
+        router.set_mode(config.router.routing_mode()?);
+        let mut driver = Daemon::new_driver(router, config.router.driver_config());
+        if let Some(ref path) = config.record {
+            driver.start_recording(Box::new(BufWriter::new(File::create(path)?)))?;
+            println!("Recording the router's inputs to {}", path);
+        }
+        Ok(Daemon {
+            sockets: sockets,
+            interfaces: interfaces,
+            inner_conns: HashMap::new(),
+            my_pk: my_pk,
+            my_sk: my_sk,
+            allowed_peers: allowed_peers,
+            bootstrap: config.bootstrap.clone(),
+            found_nodes: HashMap::new(),
+            ping_interval: config.router.ping_interval,
+            next_ping: 0,
+            sessions: SessionManager::new(driver),
+            admin: admin,
+            crawler: crawler,
+            traceroutes: HashMap::new(),
+            next_traceroute_id: 0,
+            started: Instant::now(),
+        })
+    }
+
+    /// Creates the router driver, and logs changes of its node store.
+    fn new_driver(mut router: Router, driver_config: DriverConfig) -> Driver {
+        router.subscribe(Box::new(|event| {
+            match *event {
+                NodeStoreEvent::NodeAdded { ref address, ref node } =>
+                    println!("Added {} to store, with path {}.", address, DisplayPath(node.path())),
+                NodeStoreEvent::NodeEvicted { ref address, .. } =>
+                    println!("Evicted {} from store.", address),
+                NodeStoreEvent::PathChanged { ref address, ref new_path, .. } =>
+                    println!("New path for {}: {}", address, DisplayPath(new_path)),
+                NodeStoreEvent::NodeUnreachable { ref address } =>
+                    println!("{} is unreachable.", address),
+            }
+        }));
+        Driver::new(router, driver_config)
+    }
+
+    /// Takes a 3-bit interface id, and reverse its bits.
+    /// Used to compute reverse paths.
+    fn reverse_iface_id(&self, iface_id: u8) -> u8 {
+        match iface_id {
+            0b000 => 0b000,
+            0b001 => 0b100,
+            0b010 => 0b010,
+            0b011 => 0b110,
+            0b100 => 0b001,
+            0b101 => 0b101,
+            0b110 => 0b011,
+            0b111 => 0b111,
+            _ => panic!("Iface id greater than 0b111"),
+        }
+    }
+
+    /// Send a packet to the appropriate interface.
+    fn send(&mut self, packet: &mut SwitchPacket, from_interface: u8) {
+        // Logically advance the packet through an interface.
+        let routing_decision = packet.switch(3, &(self.reverse_iface_id(from_interface) as u64));
+        match routing_decision {
+            RoutingDecision::SelfInterface(_) => {
+                // Packet is sent to myself
+                self.on_self_interface_switch_packet(packet);
+            }
+            RoutingDecision::Forward(iface_id) => {
+                // Packet is sent to a peer.
+                let mut sent = false;
+                for interface in self.interfaces.iter_mut() {
+                    if interface.id as u64 == iface_id {
+                        sent = true;
+                        // Wrap the packet with the outer CryptoAuth session
+                        // of this peer, and send it.
+                        for packet in interface.ca_session.wrap_message(&packet.raw) {
+                            if let Err(e) = self.sockets[interface.socket].send_to(&packet, interface.addr) {
+                                println!("Could not send to {}: {}", interface.addr, e);
+                            }
+                        }
+                    }
+                }
+                if !sent {
+                    println!("Iface {} not found for packet: {:?}, dropping it.", iface_id, packet);
+                }
+            }
+        }
+    }
+
+    /// Creates an inner CryptoAuth session with a node.
+    fn open_session(&mut self, handle: u32, node: &Node) {
+        let addr = node.address();
+        println!("Creating CA session for node {}", addr);
+        let node_pk = PublicKey::from_slice(node.public_key()).unwrap();
+        let conn = Wrapper::new_outgoing_connection(
+                self.my_pk.clone(), self.my_sk.clone(),
+                node_pk,
+                Credentials::None, None,
+                format!("outgoing inner {}", addr), Some(handle));
+        self.inner_conns.insert(handle, conn);
+    }
+
+    fn send_message_to_handle(&mut self, handle: u32, path: [u8; 8], message: DataPacket) {
+        let mut packets = Vec::new();
+        {
+            let inner_conn = match self.inner_conns.get_mut(&handle) {
+                Some(inner_conn) => inner_conn,
+                None => {
+                    println!("No CA session with handle {}, dropping message.", handle);
+                    return;
+                }
+            };
+            for packet_response in inner_conn.wrap_message_immediately(&message.raw) {
+                let switch_packet = SwitchPacket::new(&path, SwitchPayload::CryptoAuthData(inner_conn.peer_session_handle().unwrap(), packet_response));
+                packets.push(switch_packet);
+            }
+        }
+        for mut packet in packets {
+            self.send(&mut packet, SELF_INTERFACE);
+        }
+    }
+
+    /// Performs the actions requested by the session manager and the
+    /// router driver.
+    fn process_session_actions(&mut self) {
+        while let Some(action) = self.sessions.poll_action() {
+            if let SessionAction::Router(ref action) = action {
+                if let Some(ref mut admin) = self.admin {
+                    if let Err(e) = admin.on_action(action) {
+                        println!("Could not reply to admin request: {}", e);
+                    }
+                }
+                if let Some(ref mut task) = self.crawler {
+                    task.crawler.on_action(action);
+                }
+            }
+            match action {
+                SessionAction::Router(Action::SendToNode { node, packet }) => {
+                    let message = DataPacket::new(1, &DataPayload::RoutePacket(packet));
+                    self.sessions.send(node.address(), message);
+                }
+                SessionAction::Router(Action::SendToLabel { label, packet }) => {
+                    let message = DataPacket::new(1, &DataPayload::RoutePacket(packet));
+                    let handle_opt = self.sessions.session_by_path(&label).map(|session| session.handle());
+                    match handle_opt {
+                        Some(handle) => self.send_message_to_handle(handle, label, message),
+                        None => println!("No session with path {}, dropping message.", DisplayPath(&label)),
+                    }
+                }
+                SessionAction::Router(Action::LookupCompleted { target, result: Ok(node) }) => {
+                    println!("Found node {}. pk: {}", target, node.public_key_base32());
+                    self.found_nodes.insert(target, node);
+                }
+                SessionAction::Router(Action::LookupCompleted { target, result: Err(e) }) => {
+                    println!("Could not find node {}: {:?}", target, e);
+                }
+                SessionAction::Router(Action::NodeDiscovered { .. }) => (), // Already logged by the event callback
+                SessionAction::Router(Action::PingCompleted { node, rtt: Some(rtt) }) =>
+                    println!("Pong from {} after {}ms", node.address(), rtt),
+                SessionAction::Router(Action::PingCompleted { node, rtt: None }) => {
+                    println!("Ping to {} timed out, tracing its path {}", node.address(), DisplayPath(node.path()));
+                    self.start_traceroute(&node);
+                }
+                SessionAction::Router(Action::GetPeersCompleted { .. }) |
+                SessionAction::Router(Action::FindNodeCompleted { .. }) |
+                SessionAction::Router(Action::LookupQueried { .. }) |
+                SessionAction::Router(Action::LookupReplied { .. }) => (),
+                SessionAction::OpenSession { handle, node } => self.open_session(handle, &node),
+                SessionAction::SendMessage { handle, path, message } => self.send_message_to_handle(handle, path, message),
+                SessionAction::CloseSession { handle } => {
+                    self.inner_conns.remove(&handle);
+                }
+                SessionAction::PathChanged { handle, path } =>
+                    println!("Session {} now uses path {}", handle, DisplayPath(&path)),
+                SessionAction::Unreachable { address, messages, .. } =>
+                    println!("Dropping {} messages to unreachable node {}", messages.len(), address),
+            }
+        }
+    }
+
+    /// Pings each hop on the path to the node, to find where packets
+    /// are lost. Switches of this daemon all use 3-bit directors; we
+    /// assume the other ones do too.
+    fn start_traceroute(&mut self, node: &Node) {
+        let id = self.next_traceroute_id;
+        self.next_traceroute_id = self.next_traceroute_id.wrapping_add(1);
+        let mut traceroute = match Traceroute::new(id, node.path(), &[encoding_scheme_forms()], self.sessions.driver().router()) {
+            Some(traceroute) => traceroute,
+            None => {
+                println!("Cannot trace path {}: not a 3-bit label.", DisplayPath(node.path()));
+                return;
+            }
+        };
+        let now = self.now();
+        for ping in traceroute.start(now, TRACEROUTE_TIMEOUT) {
+            let control = ControlPacket::Ping { version: 18, opaque_data: ping.opaque_data };
+            let mut packet = SwitchPacket::new(&ping.label, SwitchPayload::Control(control));
+            self.send(&mut packet, SELF_INTERFACE);
+        }
+        self.traceroutes.insert(id, traceroute);
+    }
+
+    /// Prints and forgets traceroutes which are over.
+    fn check_traceroutes(&mut self, now: u64) {
+        let mut done = Vec::new();
+        for (id, traceroute) in self.traceroutes.iter_mut() {
+            traceroute.on_tick(now);
+            if traceroute.is_done() {
+                done.push(*id);
+            }
+        }
+        for id in done {
+            let traceroute = self.traceroutes.remove(&id).unwrap();
+            println!("Traceroute {}:", id);
+            for hop in traceroute.hops() {
+                println!("    {}", hop);
+            }
+            if let Some(hop) = traceroute.first_failing_hop() {
+                println!("    Packets are lost before {}", DisplayPath(&hop.label));
+            }
+        }
+    }
+
+    /// Milliseconds since the daemon started.
+    fn now(&self) -> u64 {
+        let elapsed = self.started.elapsed();
+        elapsed.as_secs()*1000 + (elapsed.subsec_nanos()/1000000) as u64
+    }
+
+    /// Looks up bootstrap nodes we have no session with, and pings
+    /// those we found.
+    fn ping_bootstrap_nodes(&mut self) {
+        for address in self.bootstrap.clone() {
+            if self.sessions.session(&address).is_none() {
+                println!("Looking up {}", address);
+                self.sessions.handle_input(Input::Lookup { target: address });
+            }
+        }
+        let nodes: Vec<Node> = self.found_nodes.values().cloned().collect();
+        for node in nodes {
+            println!("Pinging node {}", node.address());
+            self.sessions.driver_mut().ping(node);
+        }
+        self.process_session_actions();
+    }
+
+    /// Called when a CryptoAuth message is received through an end-to-end
+    /// session.
+    fn on_inner_ca_message(&mut self, handle: u32, ca_message: Vec<u8>) {
+        let data_packet = DataPacket { raw: ca_message };
+
+        // Give it to the router, which replies to queries and handles
+        // replies to its own queries.
+        match data_packet.payload() {
+            Some(DataPayload::RoutePacket(route_packet)) => {
+                let (path, public_key) = match self.sessions.session_by_handle(handle) {
+                    Some(session) => (*session.path(), *session.node().public_key()),
+                    None => {
+                        println!("Message on unknown handle {}, dropping it.", handle);
+                        return;
+                    }
+                };
+                let now = self.now();
+                self.sessions.handle_input(Input::RoutePacket { label: path, public_key: public_key, packet: route_packet, now: now });
+                self.process_session_actions();
+            }
+            _ => println!("Unexpected data packet from handle {}, dropping it.", handle),
+        }
+    }
+
+    /// Called when a switch packet is sent to the self interface
+    fn on_self_interface_switch_packet(&mut self, switch_packet: &SwitchPacket) {
+        match switch_packet.payload() {
+            Some(SwitchPayload::Control(ControlPacket::Ping { opaque_data, .. })) => {
+                // If it is a ping packet, just reply to it.
+                let control_response = ControlPacket::Pong { version: 18, opaque_data: opaque_data };
+                let mut packet_response = SwitchPacket::new_reply(switch_packet, SwitchPayload::Control(control_response));
+                self.send(&mut packet_response, SELF_INTERFACE);
+            },
+            Some(SwitchPayload::Control(ControlPacket::Pong { version, opaque_data })) => {
+                // We only send pings for traceroutes.
+                let now = self.now();
+                let recorded = match traceroute_id(&opaque_data).and_then(|id| self.traceroutes.get_mut(&id)) {
+                    Some(traceroute) => traceroute.on_pong(&opaque_data, version as u64, now),
+                    None => false,
+                };
+                if !recorded {
+                    println!("Received unexpected pong: {:?}", opaque_data);
+                }
+            },
+            Some(SwitchPayload::CryptoAuthHandshake(handshake)) => {
+                // If it is a CryptoAuth handshake packet (ie. if someone is
+                // connecting to us), create a new session for this node.
+                // All CA handshake we receive will be sessions started by
+                // other peers, because this switch never starts sessions
+                // (routers do, not switches).
+                let handle = self.sessions.gen_handle();
+                let (inner_conn, inner_packet) = match Wrapper::new_incoming_connection(self.my_pk, self.my_sk.clone(), Credentials::None, None, Some(handle), handshake.clone()) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("Invalid CA handshake: {:?}", e);
+                        return;
+                    }
+                };
+                let path = {
+                    let mut path = switch_packet.label();
+                    reverse_label(&mut path);
+                    path
+                };
+                let peer = Node::new(inner_conn.their_pk().0, path, 18);
+                self.inner_conns.insert(handle, inner_conn);
+                self.sessions.on_incoming_session(handle, peer.clone());
+                self.sessions.handle_input(Input::PeerUp { node: peer });
+                self.process_session_actions();
+                self.on_inner_ca_message(handle, inner_packet);
+            },
+            Some(SwitchPayload::CryptoAuthData(handle, ca_message)) => {
+                // If it is a CryptoAuth data packet, first read the session
+                // handle to know which CryptoAuth session to use to
+                // decrypt it.
+                let inner_packets = match self.inner_conns.get_mut(&handle) {
+                    Some(inner_conn) => {
+                        match inner_conn.unwrap_message(ca_message) {
+                            Ok(inner_packets) => inner_packets,
+                            Err(e) => {
+                                println!("CA error: {:?}", e);
+                                return;
+                            }
+                        }
+                    }
+                    None => {
+                        println!("Received unknown handle {}, dropping packet.", handle);
+                        return;
+                    }
+                };
+                for inner_packet in inner_packets {
+                    self.on_inner_ca_message(handle, inner_packet)
+                }
+            }
+            _ => println!("Can only handle Pings, Pongs, and CA; dropping packet."),
+        }
+    }
+
+    // Find what interface a UDP packet is coming from, using its emitted
+    // IP address.
+    // Returns `None` if the packet cannot be decrypted.
+    fn get_incoming_iface_and_open(&mut self, socket: usize, from_addr: SocketAddr, buf: Vec<u8>) -> Option<(&Interface, Vec<Vec<u8>>)> {
+        let position = self.interfaces.iter().position(|iface| iface.addr == from_addr);
+        match position {
+            Some(position) => {
+                let interface = &mut self.interfaces[position];
+                match interface.ca_session.unwrap_message(buf) {
+                    Ok(messages) => Some((interface, messages)),
+                    Err(e) => {
+                        println!("CA error from {}: {:?}", from_addr, e);
+                        None
+                    }
+                }
+            }
+            None => {
+                // Not a known interface; create one
+                let next_iface_id = match (FIRST_INTERFACE..0b1000).find(|candidate| self.interfaces.iter().all(|iface| iface.id != *candidate)) {
+                    Some(iface_id) => iface_id,
+                    None => {
+                        println!("No interface left for {} (at most {} peers), dropping packet.", from_addr, MAX_PEERS);
+                        return None;
+                    }
+                };
+                let (ca_session, message) = match Wrapper::new_incoming_connection(self.my_pk.clone(), self.my_sk.clone(), Credentials::None, Some(self.allowed_peers.clone()), None, buf) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("Invalid CA handshake from {}: {:?}", from_addr, e);
+                        return None;
+                    }
+                };
+                println!("New peer {} on interface {}", from_addr, next_iface_id);
+                let new_iface = Interface { id: next_iface_id, ca_session: ca_session, addr: from_addr, socket: socket };
+                self.interfaces.push(new_iface);
+                let interface = self.interfaces.last().unwrap();
+                Some((interface, vec![message]))
+            }
+        }
+    }
+
+    /// Called when a UDP packet is received.
+    fn on_outer_ca_message(&mut self, socket: usize, from_addr: SocketAddr, buf: Vec<u8>) {
+        let (iface_id, messages) = match self.get_incoming_iface_and_open(socket, from_addr, buf) {
+            Some((interface, messages)) => (interface.id, messages),
+            None => return,
+        };
+        for message in messages {
+            let mut switch_packet = SwitchPacket { raw: message };
+            self.send(&mut switch_packet, iface_id)
+        }
+    }
+
+    /// Reads all packets waiting on a socket. Returns whether there
+    /// was any.
+    fn recv_all(&mut self, socket: usize) -> bool {
+        let mut received = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.sockets[socket].recv_from(&mut buf) {
+                Ok((nb_bytes, addr)) => {
+                    received = true;
+                    self.on_outer_ca_message(socket, addr, buf[..nb_bytes].to_vec());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return received,
+                Err(e) => {
+                    println!("Error while receiving: {}", e);
+                    return received;
+                }
+            }
+        }
+    }
+
+    /// Handles all requests waiting on the admin socket. Returns whether
+    /// there was any.
+    fn recv_admin_requests(&mut self) -> bool {
+        let mut received = false;
+        loop {
+            let res = match self.admin {
+                Some(ref mut admin) => admin.recv_request(self.sessions.driver_mut()),
+                None => return false,
+            };
+            match res {
+                Ok(()) => {
+                    received = true;
+                    // Requests may have sent queries.
+                    self.process_session_actions();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return received,
+                Err(e) => {
+                    println!("Error while receiving admin request: {}", e);
+                    return received;
+                }
+            }
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            for interface in self.interfaces.iter_mut() {
+                for packet in interface.ca_session.upkeep() {
+                    if let Err(e) = self.sockets[interface.socket].send_to(&packet, interface.addr) {
+                        println!("Could not send to {}: {}", interface.addr, e);
+                    }
+                }
+            }
+
+            let now = self.now();
+            self.sessions.handle_input(Input::Tick { now: now });
+            self.process_session_actions();
+
+            if now >= self.next_ping {
+                self.next_ping = now + self.ping_interval;
+                self.ping_bootstrap_nodes();
+            }
+
+            if let Some(ref mut task) = self.crawler {
+                task.tick(self.sessions.driver_mut(), now);
+            }
+            self.process_session_actions();
+            self.check_traceroutes(now);
+
+            let mut received = false;
+            for socket in 0..self.sockets.len() {
+                received |= self.recv_all(socket);
+            }
+            received |= self.recv_admin_requests();
+
+            self.sessions.driver_mut().flush_recording();
+            if let Some(e) = self.sessions.driver_mut().take_recording_error() {
+                println!("Recording stopped: {}", e);
+            }
+            if !received {
+                thread::sleep(Duration::from_millis(IDLE_SLEEP));
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Routing daemon: a switch with a router on top of it, configured by
+//! a JSON file (see `config.rs`).
+//!
+//! Usage: `fcp-routerd <config file>`
+//!
+//! Several instances can be launched locally to form a test mesh; see
+//! `conf/mesh/`.
+
+extern crate fcp_cryptoauth;
+extern crate fcp_switching;
+extern crate fcp_routing;
+extern crate serde_json;
+#[macro_use]
+extern crate serde;
+
+mod config;
+mod daemon;
+
+use std::env;
+use std::process;
+
+use config::Config;
+use daemon::Daemon;
+
+pub fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: fcp-routerd <config file>");
+            process::exit(1);
+        }
+    };
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    fcp_cryptoauth::init();
+
+    let mut daemon = match Daemon::new(config) {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Could not start: {}", e);
+            process::exit(1);
+        }
+    };
+    daemon.run();
+}
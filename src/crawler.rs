@@ -0,0 +1,356 @@
+//! Crawler mapping the network, built on a `Driver`.
+//!
+//! Starting from our peers, it sends a `gp` query to every node it
+//! discovers (to learn its links) and a `fn` query for the node's own
+//! address (to learn the nodes around it in the keyspace), and queues
+//! the nodes in the replies to be queried in turn, until no new node
+//! is found.
+//!
+//! Like the `Driver`, it does no I/O: call `Crawler::tick` regularly
+//! to send queries, and give it the driver's actions with
+//! `Crawler::on_action`. It sends at most `queries_per_second` queries
+//! per second, so it can run on a live node.
+//!
+//! The result can be exported as a Graphviz graph with
+//! `Crawler::to_dot`, or as a `CrawlState` which can be serialized
+//! (with the `serde` feature) and given to `Crawler::resume` to
+//! continue an interrupted crawl.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+use node::{Address, Node};
+use driver::{Driver, Action};
+
+/// Queries sent to each node: `gp` and `fn`.
+const QUERIES_PER_NODE: usize = 2;
+
+/// Parameters of the `Crawler`.
+#[derive(Clone, Debug)]
+pub struct CrawlerConfig {
+    /// Maximum number of queries sent per second, on average.
+    pub queries_per_second: u64,
+    /// Maximum number of queries waiting for a reply.
+    pub max_in_flight: usize,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> CrawlerConfig {
+        CrawlerConfig {
+            queries_per_second: 10,
+            max_in_flight: 16,
+        }
+    }
+}
+
+/// What a crawler found, and the nodes it did not query yet.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CrawlState {
+    /// Nodes discovered, with paths from the crawling node.
+    pub nodes: Vec<Node>,
+    /// Links between nodes, reported by their `gp` replies.
+    pub links: Vec<(Address, Address)>,
+    /// Nodes not queried yet.
+    pub frontier: Vec<Node>,
+    /// Nodes that did not reply to their `gp` query.
+    pub unresponsive: Vec<Address>,
+}
+
+pub struct Crawler {
+    config: CrawlerConfig,
+    my_address: Address,
+    nodes: BTreeMap<Address, Node>,
+    /// Links, with the lowest address first.
+    links: BTreeSet<(Address, Address)>,
+    frontier: VecDeque<Node>,
+    /// Nodes queried or in the frontier.
+    seen: HashSet<Address>,
+    unresponsive: BTreeSet<Address>,
+    /// Nodes we are waiting for, with the number of their queries
+    /// not completed yet.
+    pending: HashMap<Address, (Node, usize)>,
+    in_flight: usize,
+    /// Number of queries we may send now, in thousandths.
+    budget: u64,
+    last_refill: Option<u64>,
+}
+
+impl Crawler {
+    /// Creates a crawler for the node with this address.
+    pub fn new(config: CrawlerConfig, my_address: Address) -> Crawler {
+        Crawler {
+            budget: Crawler::max_budget(&config),
+            config: config,
+            my_address: my_address,
+            nodes: BTreeMap::new(),
+            links: BTreeSet::new(),
+            frontier: VecDeque::new(),
+            seen: HashSet::new(),
+            unresponsive: BTreeSet::new(),
+            pending: HashMap::new(),
+            in_flight: 0,
+            last_refill: None,
+        }
+    }
+
+    /// Creates a crawler continuing from a saved state: nodes of the
+    /// frontier will be queried, but not the other known nodes.
+    pub fn resume(config: CrawlerConfig, my_address: Address, state: CrawlState) -> Crawler {
+        let mut crawler = Crawler::new(config, my_address);
+        for node in state.nodes {
+            crawler.seen.insert(node.address());
+            crawler.nodes.insert(node.address(), node);
+        }
+        for (a, b) in state.links {
+            crawler.add_link(a, b);
+        }
+        for node in state.frontier {
+            crawler.seen.remove(&node.address());
+            crawler.discover(node);
+        }
+        crawler.unresponsive = state.unresponsive.into_iter().collect();
+        crawler
+    }
+
+    /// Burst size of the rate limit (at least enough to query one node).
+    fn max_budget(config: &CrawlerConfig) -> u64 {
+        cmp::max(config.queries_per_second, QUERIES_PER_NODE as u64) * 1000
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<Address, Node> {
+        &self.nodes
+    }
+
+    /// Links between nodes, with the lowest address first.
+    pub fn links(&self) -> &BTreeSet<(Address, Address)> {
+        &self.links
+    }
+
+    /// Number of nodes not queried yet.
+    pub fn frontier_len(&self) -> usize {
+        self.frontier.len()
+    }
+
+    /// Number of queries waiting for a reply.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Returns whether all nodes found were queried, and all queries
+    /// completed.
+    pub fn is_done(&self) -> bool {
+        self.frontier.is_empty() && self.in_flight == 0
+    }
+
+    /// Queues all known nodes to be queried again, to update the map.
+    pub fn recrawl(&mut self) {
+        let queued: HashSet<Address> = self.frontier.iter().map(Node::address).collect();
+        for (address, node) in &self.nodes {
+            if *address != self.my_address && !self.pending.contains_key(address)
+                    && !queued.contains(address) {
+                self.frontier.push_back(node.clone());
+            }
+        }
+        self.unresponsive.clear();
+    }
+
+    /// Adds the driver's peers to the map, and sends as many queries
+    /// as the rate limit allows. Call it regularly.
+    pub fn tick(&mut self, driver: &mut Driver) {
+        let now = driver.now();
+        if let Some(last_refill) = self.last_refill {
+            let refill = now.saturating_sub(last_refill).saturating_mul(self.config.queries_per_second);
+            self.budget = cmp::min(self.budget.saturating_add(refill), Crawler::max_budget(&self.config));
+        }
+        self.last_refill = Some(now);
+
+        let peers: Vec<Node> = driver.router().peers().values().cloned().collect();
+        for peer in peers {
+            let my_address = self.my_address.clone();
+            self.add_link(my_address, peer.address());
+            self.discover(peer);
+        }
+
+        while self.in_flight + QUERIES_PER_NODE <= self.config.max_in_flight
+                && self.budget >= QUERIES_PER_NODE as u64 * 1000 {
+            let node = match self.frontier.pop_front() {
+                Some(node) => node,
+                None => break,
+            };
+            let address = node.address();
+            driver.get_peers(node.clone());
+            driver.find_node(node.clone(), address.clone());
+            self.pending.insert(address, (node, QUERIES_PER_NODE));
+            self.in_flight += QUERIES_PER_NODE;
+            self.budget -= QUERIES_PER_NODE as u64 * 1000;
+        }
+    }
+
+    /// Records the result of our queries. Other actions are ignored.
+    pub fn on_action(&mut self, action: &Action) {
+        match *action {
+            Action::GetPeersCompleted { ref node, ref peers } => {
+                if !self.query_done(node) {
+                    return;
+                }
+                match *peers {
+                    Some(ref peers) => {
+                        for peer in peers {
+                            self.add_link(node.address(), peer.address());
+                            self.discover(peer.clone());
+                        }
+                    }
+                    None => {
+                        self.unresponsive.insert(node.address());
+                    }
+                }
+            }
+            Action::FindNodeCompleted { ref node, nodes: ref found, .. } => {
+                if !self.query_done(node) {
+                    return;
+                }
+                for found_node in found.iter().flat_map(|nodes| nodes.iter()) {
+                    self.discover(found_node.clone());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Counts a completed query to the node. Returns `false` if we
+    /// were not waiting for it.
+    fn query_done(&mut self, node: &Node) -> bool {
+        let address = node.address();
+        let remaining = match self.pending.get_mut(&address) {
+            Some(&mut (_, ref mut remaining)) => {
+                *remaining -= 1;
+                *remaining
+            }
+            None => return false,
+        };
+        if remaining == 0 {
+            self.pending.remove(&address);
+        }
+        self.in_flight -= 1;
+        true
+    }
+
+    /// Adds a node to the map, and queues it if it was never queried.
+    fn discover(&mut self, node: Node) {
+        let address = node.address();
+        self.nodes.entry(address.clone()).or_insert_with(|| node.clone());
+        if address != self.my_address && self.seen.insert(address) {
+            self.frontier.push_back(node);
+        }
+    }
+
+    fn add_link(&mut self, a: Address, b: Address) {
+        if a != b {
+            self.links.insert(if a < b { (a, b) } else { (b, a) });
+        }
+    }
+
+    /// Returns what was found so far. Nodes with queries in flight are
+    /// put back in the frontier, so they are queried again on resume.
+    pub fn state(&self) -> CrawlState {
+        let mut frontier: Vec<Node> = self.pending.values().map(|&(ref node, _)| node.clone()).collect();
+        frontier.sort();
+        frontier.extend(self.frontier.iter().cloned());
+        CrawlState {
+            nodes: self.nodes.values().cloned().collect(),
+            links: self.links.iter().cloned().collect(),
+            frontier: frontier,
+            unresponsive: self.unresponsive.iter().cloned().collect(),
+        }
+    }
+
+    /// Renders the map as an undirected Graphviz graph. Nodes are
+    /// named by their address; unresponsive ones are dashed.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "graph fcp {{").unwrap();
+        for (address, node) in &self.nodes {
+            let style = if self.unresponsive.contains(address) { ", style=dashed" } else { "" };
+            writeln!(out, "    \"{}\" [label=\"{}\\nv{}\"{}];", address, address, node.version(), style).unwrap();
+        }
+        for &(ref a, ref b) in &self.links {
+            writeln!(out, "    \"{}\" -- \"{}\";", a, b).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simulator::{Simulator, SimulatorConfig, Topology};
+
+    /// Runs the crawler on node 0 until it is done.
+    fn crawl(sim: &mut Simulator, crawler: &mut Crawler) {
+        for _ in 0..1000 {
+            sim.with_driver(0, |driver| crawler.tick(driver));
+            for action in sim.take_actions(0) {
+                crawler.on_action(&action);
+            }
+            if crawler.is_done() {
+                return;
+            }
+            let now = sim.now();
+            sim.run_until(now + 100);
+        }
+        panic!("The crawl did not finish.");
+    }
+
+    /// Checks the crawler found all nodes and links of the topology.
+    fn check_map(sim: &Simulator, topology: &Topology, crawler: &Crawler) {
+        assert_eq!(crawler.nodes().len(), topology.nb_nodes());
+        let mut links: Vec<(usize, usize)> = crawler.links().iter().map(|&(ref a, ref b)| {
+            let (a, b) = (sim.node_index(a).unwrap(), sim.node_index(b).unwrap());
+            (cmp::min(a, b), cmp::max(a, b))
+        }).collect();
+        links.sort();
+        let mut expected: Vec<(usize, usize)> = topology.links().iter().map(|&(a, b)| (cmp::min(a, b), cmp::max(a, b))).collect();
+        expected.sort();
+        assert_eq!(links, expected);
+    }
+
+    #[test]
+    fn test_crawl() {
+        let topology = Topology::grid(3, 3);
+        let mut sim = Simulator::new(&topology, SimulatorConfig::default());
+        let mut crawler = Crawler::new(CrawlerConfig::default(), sim.address(0));
+        crawl(&mut sim, &mut crawler);
+        check_map(&sim, &topology, &crawler);
+
+        let dot = crawler.to_dot();
+        assert!(dot.starts_with("graph fcp {\n"));
+        assert_eq!(dot.lines().filter(|line| line.contains(" -- ")).count(), topology.links().len());
+    }
+
+    #[test]
+    fn test_rate_limit_and_resume() {
+        let topology = Topology::random(10, 5, 1);
+        let mut sim = Simulator::new(&topology, SimulatorConfig::default());
+        let config = CrawlerConfig { queries_per_second: 2, max_in_flight: 16 };
+        let mut crawler = Crawler::new(config.clone(), sim.address(0));
+
+        // Only one node can be queried each second.
+        sim.with_driver(0, |driver| crawler.tick(driver));
+        assert_eq!(crawler.in_flight(), 2);
+        sim.run_until(500);
+        sim.with_driver(0, |driver| crawler.tick(driver));
+        for action in sim.take_actions(0) {
+            crawler.on_action(&action);
+        }
+        assert_eq!(crawler.in_flight(), 0);
+        assert!(crawler.frontier_len() > 0);
+
+        let state = crawler.state();
+        let mut crawler = Crawler::resume(config, sim.address(0), state);
+        crawl(&mut sim, &mut crawler);
+        check_map(&sim, &topology, &crawler);
+    }
+}
@@ -0,0 +1,702 @@
+//! Offline decoder of captured traffic, enabled by the `decoder`
+//! feature (see the `fcp-decode` binary).
+//!
+//! Frames are read from pcap files (UDP datagrams) or hex dumps, and
+//! decoded layer by layer: the outer CryptoAuth session between peers,
+//! the switch header, control messages, the inner CryptoAuth session
+//! between routers, the data header, and finally the route packet.
+//!
+//! CryptoAuth data packets are encrypted with temporary keys, which
+//! never leave the nodes, so they cannot be decrypted offline. Hello
+//! and key packets are encrypted with the permanent key of a node
+//! (and the password of the peer, for outer sessions), so given that
+//! key, they are decrypted, along with the message they carry (usually
+//! the first messages of a session).
+//! See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/crypto/CryptoAuth.c
+
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use crypto_box::{SalsaBox, PublicKey as BoxPublicKey, SecretKey as BoxSecretKey};
+use crypto_secretbox::XSalsa20Poly1305;
+use crypto_secretbox::aead::{AeadInPlace, KeyInit};
+use crypto_secretbox::aead::generic_array::GenericArray;
+use x25519_dalek::{StaticSecret, PublicKey as X25519PublicKey};
+use sha2::{Sha256, Digest};
+
+use bencode::Value;
+use node::{Address, DisplayPath, Path, PUBLIC_KEY_LENGTH, public_key_to_base32, hex_to_bytes};
+
+const SWITCH_HEADER_LENGTH: usize = 12;
+
+/// Length of the header of CryptoAuth hello and key packets.
+const HANDSHAKE_HEADER_LENGTH: usize = 120;
+
+/// Handle of switch packets carrying control messages.
+const CONTROL_HANDLE: u32 = 0xffff_ffff;
+
+/// Content type of data packets carrying route packets.
+const CONTENT_TYPE_CJDHT: u16 = 256;
+
+/// Layer a frame starts with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layer {
+    /// UDP payload: outer CryptoAuth packet, containing a switch packet.
+    Udp,
+    /// Switch packet.
+    Switch,
+    /// Data packet (decrypted content of an inner CryptoAuth session).
+    Data,
+    /// Bencoded route packet.
+    Route,
+}
+
+impl FromStr for Layer {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Layer, ()> {
+        match s {
+            "udp" => Ok(Layer::Udp),
+            "switch" => Ok(Layer::Switch),
+            "data" => Ok(Layer::Data),
+            "route" => Ok(Layer::Route),
+            _ => Err(()),
+        }
+    }
+}
+
+/// UDP datagram read from a pcap file.
+#[derive(Clone, Debug)]
+pub struct UdpFrame {
+    /// Capture time, in microseconds since the epoch.
+    pub timestamp: u64,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PcapError {
+    /// Not a pcap file (pcapng is not supported).
+    InvalidMagic,
+    Truncated,
+    UnsupportedLinkType(u32),
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PcapError::InvalidMagic => write!(f, "not a pcap file (pcapng files have to be converted)"),
+            PcapError::Truncated => write!(f, "truncated pcap file"),
+            PcapError::UnsupportedLinkType(link_type) => write!(f, "unsupported link type {}", link_type),
+        }
+    }
+}
+
+impl Error for PcapError {
+}
+
+fn u16_be(data: &[u8]) -> u16 {
+    ((data[0] as u16) << 8) | data[1] as u16
+}
+
+fn u32_be(data: &[u8]) -> u32 {
+    data[..4].iter().fold(0, |acc, byte| (acc << 8) | *byte as u32)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the UDP datagrams of a pcap file. Other packets are skipped.
+pub fn parse_pcap(data: &[u8]) -> Result<Vec<UdpFrame>, PcapError> {
+    if data.len() < 24 {
+        return Err(PcapError::Truncated)
+    }
+    let magic = u32_be(data);
+    let (little_endian, nanoseconds) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xd4c3b2a1 => (true, false),
+        0xa1b23c4d => (false, true),
+        0x4d3cb2a1 => (true, true),
+        _ => return Err(PcapError::InvalidMagic),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        if little_endian { u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) } else { u32_be(bytes) }
+    };
+    let link_type = read_u32(&data[20..24]);
+    let mut frames = Vec::new();
+    let mut rest = &data[24..];
+    while !rest.is_empty() {
+        if rest.len() < 16 {
+            return Err(PcapError::Truncated)
+        }
+        let seconds = read_u32(&rest[0..4]) as u64;
+        let fraction = read_u32(&rest[4..8]) as u64;
+        let length = read_u32(&rest[8..12]) as usize;
+        if rest.len() - 16 < length {
+            return Err(PcapError::Truncated)
+        }
+        let packet = &rest[16..16+length];
+        rest = &rest[16+length..];
+        let timestamp = seconds*1_000_000 + if nanoseconds { fraction / 1000 } else { fraction };
+
+        let ip_packet = match link_type {
+            // BSD loopback: address family in host order.
+            0 if packet.len() >= 4 => Some(&packet[4..]),
+            // Ethernet, possibly with a VLAN tag.
+            1 if packet.len() >= 14 => {
+                if u16_be(&packet[12..14]) == 0x8100 && packet.len() >= 18 { Some(&packet[18..]) } else { Some(&packet[14..]) }
+            }
+            // Raw IP.
+            12 | 101 => Some(packet),
+            // Linux cooked capture, v1 and v2.
+            113 if packet.len() >= 16 => Some(&packet[16..]),
+            276 if packet.len() >= 20 => Some(&packet[20..]),
+            0 | 1 | 113 | 276 => None,
+            _ => return Err(PcapError::UnsupportedLinkType(link_type)),
+        };
+        if let Some(frame) = ip_packet.and_then(|ip_packet| parse_ip_udp(ip_packet, timestamp)) {
+            frames.push(frame);
+        }
+    }
+    Ok(frames)
+}
+
+/// Reads an IPv4 or IPv6 packet, if it contains a UDP datagram.
+fn parse_ip_udp(packet: &[u8], timestamp: u64) -> Option<UdpFrame> {
+    let (source, destination, udp) = match packet.first().map(|byte| byte >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_length = ((packet[0] & 0xf) as usize) * 4;
+            if packet[9] != 17 || packet.len() < header_length {
+                return None
+            }
+            let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+            let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+            (IpAddr::V4(source), IpAddr::V4(destination), &packet[header_length..])
+        }
+        Some(6) if packet.len() >= 40 => {
+            if packet[6] != 17 {
+                return None
+            }
+            let mut source = [0u8; 16];
+            source.copy_from_slice(&packet[8..24]);
+            let mut destination = [0u8; 16];
+            destination.copy_from_slice(&packet[24..40]);
+            (IpAddr::V6(Ipv6Addr::from(source)), IpAddr::V6(Ipv6Addr::from(destination)), &packet[40..])
+        }
+        _ => return None,
+    };
+    if udp.len() < 8 {
+        return None
+    }
+    let length = u16_be(&udp[4..6]) as usize;
+    let end = if length >= 8 && length <= udp.len() { length } else { udp.len() };
+    Some(UdpFrame {
+        timestamp: timestamp,
+        source: SocketAddr::new(source, u16_be(&udp[0..2])),
+        destination: SocketAddr::new(destination, u16_be(&udp[2..4])),
+        payload: udp[8..end].to_vec(),
+    })
+}
+
+/// Reads frames from a hex dump. Frames are separated by blank lines;
+/// on each line, a leading offset (ending with `:`) is skipped, and
+/// so is anything after the hexadecimal bytes (eg. the ASCII column of
+/// `xxd` or `tcpdump -X`). Lines starting with `#` are ignored.
+pub fn parse_hex_dump(s: &str) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut frame = Vec::new();
+    for line in s.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            if !frame.is_empty() {
+                frames.push(frame);
+                frame = Vec::new();
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut tokens = line.split_whitespace().peekable();
+        if tokens.peek().map_or(false, |token| token.ends_with(':')) {
+            tokens.next();
+        }
+        for token in tokens {
+            match hex_to_bytes(token) {
+                Some(bytes) => frame.extend(bytes),
+                None => break,
+            }
+        }
+    }
+    if !frame.is_empty() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Decodes frames, remembering the temporary keys found in handshakes.
+pub struct Decoder {
+    /// Secret keys of nodes, with their public keys.
+    keys: Vec<([u8; 32], [u8; PUBLIC_KEY_LENGTH])>,
+    /// SHA-256 of passwords peers may use.
+    password_hashes: Vec<[u8; 32]>,
+    /// Temporary public keys of the senders of decrypted hellos.
+    temp_keys: Vec<[u8; PUBLIC_KEY_LENGTH]>,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { keys: Vec::new(), password_hashes: Vec::new(), temp_keys: Vec::new() }
+    }
+
+    /// Adds the secret key of a node, to decrypt handshakes sent to it
+    /// (hellos) and by it (keys).
+    pub fn add_secret_key(&mut self, secret_key: [u8; 32]) {
+        let public_key = X25519PublicKey::from(&StaticSecret::from(secret_key));
+        self.keys.push((secret_key, *public_key.as_bytes()));
+    }
+
+    /// Adds a password peers may use to connect (see
+    /// `authorizedPasswords` in the daemon's configuration).
+    pub fn add_password(&mut self, password: &str) {
+        let mut password_hash = [0u8; 32];
+        password_hash.copy_from_slice(&Sha256::digest(password.as_bytes()));
+        self.password_hashes.push(password_hash);
+    }
+
+    /// Decodes a frame starting with this layer, and returns a
+    /// description of its contents, one field per line.
+    pub fn decode(&mut self, frame: &[u8], layer: Layer) -> String {
+        let mut out = String::new();
+        self.decode_layer(&mut out, 0, frame, layer);
+        out
+    }
+
+    fn decode_layer(&mut self, out: &mut String, depth: usize, data: &[u8], layer: Layer) {
+        match layer {
+            Layer::Udp => self.decode_crypto_auth(out, depth, data, false),
+            Layer::Switch => self.decode_switch(out, depth, data),
+            Layer::Data => self.decode_data(out, depth, data),
+            Layer::Route => decode_route(out, depth, data),
+        }
+    }
+
+    fn decode_switch(&mut self, out: &mut String, depth: usize, data: &[u8]) {
+        let pad = "  ".repeat(depth);
+        if data.len() < SWITCH_HEADER_LENGTH + 4 {
+            writeln!(out, "{}Truncated switch packet: {}", pad, to_hex(data)).unwrap();
+            return;
+        }
+        let mut label: Path = [0; 8];
+        label.copy_from_slice(&data[0..8]);
+        writeln!(out, "{}Switch packet: label {}, version {}, label shift {}, penalty {}{}",
+                pad, DisplayPath(&label), data[9] >> 6, data[9] & 0x3f, u16_be(&data[10..12]),
+                if data[8] & 1 == 1 { ", errors suppressed" } else { "" }).unwrap();
+        let payload = &data[SWITCH_HEADER_LENGTH..];
+        let handle = u32_be(payload);
+        if handle == CONTROL_HANDLE {
+            decode_control(out, depth + 1, &payload[4..]);
+        }
+        else if handle < 4 {
+            // Handshakes have no handle; the first field is the stage.
+            self.decode_crypto_auth(out, depth + 1, payload, true);
+        }
+        else {
+            writeln!(out, "{}  Session handle {}", pad, handle).unwrap();
+            self.decode_crypto_auth(out, depth + 1, &payload[4..], true);
+        }
+    }
+
+    /// Decodes a CryptoAuth packet, and its content if it can be
+    /// decrypted. Inner sessions (between routers) carry data packets,
+    /// outer ones (between peers) carry switch packets.
+    fn decode_crypto_auth(&mut self, out: &mut String, depth: usize, data: &[u8], inner: bool) {
+        let pad = "  ".repeat(depth);
+        if data.len() < 4 {
+            writeln!(out, "{}Truncated CryptoAuth packet: {}", pad, to_hex(data)).unwrap();
+            return;
+        }
+        let stage = u32_be(data);
+        if stage >= 4 {
+            writeln!(out, "{}CryptoAuth data: nonce {}, {} bytes (encrypted with temporary keys, cannot be decrypted)",
+                    pad, stage, data.len() - 4).unwrap();
+            return;
+        }
+        if data.len() < HANDSHAKE_HEADER_LENGTH {
+            writeln!(out, "{}Truncated CryptoAuth handshake: {}", pad, to_hex(data)).unwrap();
+            return;
+        }
+        let stage_name = ["hello", "repeated hello", "key", "repeated key"][stage as usize];
+        let auth_type = data[4];
+        let mut sender = [0u8; PUBLIC_KEY_LENGTH];
+        sender.copy_from_slice(&data[40..72]);
+        writeln!(out, "{}CryptoAuth {}: auth type {}, from {} ({})",
+                pad, stage_name, auth_type, public_key_to_base32(&sender), Address::from_public_key(&sender)).unwrap();
+
+        let plaintext = match self.open_handshake(data, stage, auth_type, &sender) {
+            Some(plaintext) => plaintext,
+            None => {
+                writeln!(out, "{}  Cannot decrypt: no matching key or password", pad).unwrap();
+                return;
+            }
+        };
+        let mut temp_key = [0u8; PUBLIC_KEY_LENGTH];
+        temp_key.copy_from_slice(&plaintext[..32]);
+        writeln!(out, "{}  Temporary key: {}", pad, public_key_to_base32(&temp_key)).unwrap();
+        if stage < 2 && !self.temp_keys.contains(&temp_key) {
+            self.temp_keys.push(temp_key);
+        }
+        let content = &plaintext[32..];
+        if content.is_empty() {
+            return;
+        }
+        if inner {
+            if content.len() < 4 {
+                writeln!(out, "{}  Truncated content: {}", pad, to_hex(content)).unwrap();
+                return;
+            }
+            writeln!(out, "{}  Sender's session handle {}", pad, u32_be(content)).unwrap();
+            self.decode_data(out, depth + 1, &content[4..]);
+        }
+        else {
+            self.decode_switch(out, depth + 1, content);
+        }
+    }
+
+    /// Decrypts the temporary key and content of a handshake packet.
+    /// Hellos are encrypted with the permanent keys of both nodes, and
+    /// keys with the permanent key of their sender and the temporary
+    /// key of the hello's sender.
+    fn open_handshake(&self, data: &[u8], stage: u32, auth_type: u8, sender: &[u8; PUBLIC_KEY_LENGTH]) -> Option<Vec<u8>> {
+        let mut candidates = Vec::new();
+        for &(ref secret_key, ref public_key) in &self.keys {
+            if stage < 2 {
+                candidates.push((*secret_key, *sender));
+            }
+            else if public_key == sender {
+                for temp_key in &self.temp_keys {
+                    candidates.push((*secret_key, *temp_key));
+                }
+            }
+        }
+        let password_hashes: Vec<Option<[u8; 32]>> = if auth_type == 0 {
+            vec![None]
+        }
+        else {
+            self.password_hashes.iter().cloned().map(Some).collect()
+        };
+        let nonce = GenericArray::from_slice(&data[16..40]);
+        let tag = GenericArray::from_slice(&data[72..88]);
+        for (secret_key, public_key) in candidates {
+            for password_hash in &password_hashes {
+                let mut buffer = data[88..].to_vec();
+                let opened = match *password_hash {
+                    None => {
+                        let salsa_box = SalsaBox::new(&BoxPublicKey::from(public_key), &BoxSecretKey::from(secret_key));
+                        salsa_box.decrypt_in_place_detached(nonce, b"", &mut buffer, tag).is_ok()
+                    }
+                    Some(ref password_hash) => {
+                        // Like cjdns: SHA-256 of the shared point and
+                        // of the password's hash.
+                        let shared = StaticSecret::from(secret_key).diffie_hellman(&X25519PublicKey::from(public_key));
+                        let mut hasher = Sha256::new();
+                        hasher.update(shared.as_bytes());
+                        hasher.update(password_hash);
+                        let cipher = XSalsa20Poly1305::new(&hasher.finalize());
+                        cipher.decrypt_in_place_detached(nonce, b"", &mut buffer, tag).is_ok()
+                    }
+                };
+                if opened {
+                    return Some(buffer)
+                }
+            }
+        }
+        None
+    }
+
+    fn decode_data(&mut self, out: &mut String, depth: usize, data: &[u8]) {
+        let pad = "  ".repeat(depth);
+        if data.len() < 4 {
+            writeln!(out, "{}Truncated data packet: {}", pad, to_hex(data)).unwrap();
+            return;
+        }
+        let content_type = u16_be(&data[2..4]);
+        writeln!(out, "{}Data packet: version {}, content type {}", pad, data[0] >> 4, content_type).unwrap();
+        if content_type == CONTENT_TYPE_CJDHT {
+            decode_route(out, depth + 1, &data[4..]);
+        }
+        else {
+            writeln!(out, "{}  Content: {}", pad, to_hex(&data[4..])).unwrap();
+        }
+    }
+}
+
+fn decode_control(out: &mut String, depth: usize, data: &[u8]) {
+    let pad = "  ".repeat(depth);
+    if data.len() < 4 {
+        writeln!(out, "{}Truncated control message: {}", pad, to_hex(data)).unwrap();
+        return;
+    }
+    let content = &data[4..];
+    match u16_be(&data[2..4]) {
+        2 if content.len() >= 4 => writeln!(out, "{}Control error: type {}", pad, u32_be(content)).unwrap(),
+        type_ @ 3..=6 if content.len() >= 8 => {
+            let name = ["ping", "pong", "key ping", "key pong"][type_ as usize - 3];
+            let (key, opaque_data) = if type_ >= 5 && content.len() >= 40 {
+                let mut key = [0u8; PUBLIC_KEY_LENGTH];
+                key.copy_from_slice(&content[8..40]);
+                (format!(", key {}", public_key_to_base32(&key)), &content[40..])
+            }
+            else {
+                (String::new(), &content[8..])
+            };
+            writeln!(out, "{}Control {}: version {}{}, data {}", pad, name, u32_be(&content[4..8]), key, to_hex(opaque_data)).unwrap();
+        }
+        type_ => writeln!(out, "{}Control message of type {}: {}", pad, type_, to_hex(content)).unwrap(),
+    }
+}
+
+/// Prints the fields of a route packet, decoding addresses, nodes and
+/// their versions.
+fn decode_route(out: &mut String, depth: usize, data: &[u8]) {
+    let pad = "  ".repeat(depth);
+    let dict = match Value::decode(data) {
+        Ok(Value::Dict(dict)) => dict,
+        _ => {
+            writeln!(out, "{}Invalid route packet: {}", pad, to_hex(data)).unwrap();
+            return;
+        }
+    };
+    writeln!(out, "{}Route packet:", pad).unwrap();
+    let versions: Vec<u64> = match dict.get(&b"np"[..]).and_then(Value::as_bytes) {
+        Some(np) if !np.is_empty() && np[0] > 0 => {
+            np[1..].chunks(np[0] as usize).map(|chunk| chunk.iter().fold(0, |acc, byte| (acc << 8) | *byte as u64)).collect()
+        }
+        _ => Vec::new(),
+    };
+    for (key, value) in &dict {
+        let bytes = value.as_bytes();
+        match (&key[..], bytes) {
+            (b"q", Some(query)) => writeln!(out, "{}  query: {}", pad, String::from_utf8_lossy(query)).unwrap(),
+            (b"txid", Some(txid)) => writeln!(out, "{}  transaction id: {}", pad, to_hex(txid)).unwrap(),
+            (b"tar", Some(target)) if target.len() == 16 => {
+                let mut address = [0u8; 16];
+                address.copy_from_slice(target);
+                writeln!(out, "{}  target: {}", pad, Address::new(&address)).unwrap();
+            }
+            (b"n", Some(nodes)) => {
+                writeln!(out, "{}  nodes:", pad).unwrap();
+                for (i, node) in nodes.chunks(40).enumerate() {
+                    if node.len() < 40 {
+                        writeln!(out, "{}    truncated: {}", pad, to_hex(node)).unwrap();
+                        break;
+                    }
+                    let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+                    public_key.copy_from_slice(&node[..32]);
+                    let mut path: Path = [0; 8];
+                    path.copy_from_slice(&node[32..]);
+                    let version = versions.get(i).map(|v| format!("v{}", v)).unwrap_or_else(|| "v?".to_owned());
+                    writeln!(out, "{}    {}.{}.{} ({})", pad, version, DisplayPath(&path),
+                            public_key_to_base32(&public_key), Address::from_public_key(&public_key)).unwrap();
+                }
+            }
+            (b"np", Some(_)) => writeln!(out, "{}  node versions: {:?}", pad, versions).unwrap(),
+            (b"p", None) => writeln!(out, "{}  protocol version: {}", pad, value.as_int().unwrap_or(-1)).unwrap(),
+            (b"ei", None) => writeln!(out, "{}  encoding index: {}", pad, value.as_int().unwrap_or(-1)).unwrap(),
+            (b"es", Some(scheme)) => writeln!(out, "{}  encoding scheme: {}", pad, to_hex(scheme)).unwrap(),
+            (key, _) => writeln!(out, "{}  {}: {:?}", pad, String::from_utf8_lossy(key), value).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::secret_key_from_hex;
+
+    const SECRET_KEY_A: &str = "ac3e53b518e68449692b0b2f2926ef2fdc1eac5b9dbd10a48114263b8c8ed12e";
+    const SECRET_KEY_B: &str = "10380b7f5ea09ecdc15c098b411584aa35a2a3f1b9579b2667b4bb1a179191a0";
+
+    fn secret_key(s: &str) -> [u8; 32] {
+        secret_key_from_hex(s).unwrap()
+    }
+
+    fn public_key(secret_key: [u8; 32]) -> [u8; 32] {
+        *X25519PublicKey::from(&StaticSecret::from(secret_key)).as_bytes()
+    }
+
+    /// `fn` query from A, looking for A's address.
+    fn route_packet() -> Vec<u8> {
+        let public_key_a = public_key(secret_key(SECRET_KEY_A));
+        let public_key_b = public_key(secret_key(SECRET_KEY_B));
+        let mut nodes = public_key_b.to_vec();
+        nodes.extend(&[0, 0, 0, 0, 0, 0, 0, 0x13]);
+        Value::dict(vec![
+            ("q", Value::string("fn")),
+            ("txid", Value::Bytes(vec![1, 2])),
+            ("tar", Value::Bytes(Address::from_public_key(&public_key_a).bytes().to_vec())),
+            ("n", Value::Bytes(nodes)),
+            ("np", Value::Bytes(vec![1, 18])),
+            ("p", Value::Int(18)),
+            ]).encode()
+    }
+
+    #[test]
+    fn test_route_packet() {
+        let decoded = Decoder::new().decode(&route_packet(), Layer::Route);
+        for line in &[
+                "  query: fn",
+                "  transaction id: 0102",
+                "  target: fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9",
+                "    v18.0000.0000.0000.0013.dwzr0g0srh6gm4x0zmb84sr30kc7cqg0pxck6d3tht7sht6dbv80.k (fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6)",
+                "  protocol version: 18",
+                ] {
+            assert!(decoded.lines().any(|l| l == *line), "Missing line {:?} in:\n{}", line, decoded);
+        }
+    }
+
+    /// CryptoAuth handshake packet from the owner of `secret_key`,
+    /// encrypted for `their_key`, optionally with a password.
+    fn handshake(stage: u32, auth_type: u8, secret_key: [u8; 32], their_key: [u8; 32],
+            password: Option<&str>, mut plaintext: Vec<u8>) -> Vec<u8> {
+        let nonce = GenericArray::clone_from_slice(&[7u8; 24]);
+        let tag = match password {
+            None => {
+                let salsa_box = SalsaBox::new(&BoxPublicKey::from(their_key), &BoxSecretKey::from(secret_key));
+                salsa_box.encrypt_in_place_detached(&nonce, b"", &mut plaintext).unwrap()
+            }
+            Some(password) => {
+                let shared = StaticSecret::from(secret_key).diffie_hellman(&X25519PublicKey::from(their_key));
+                let mut hasher = Sha256::new();
+                hasher.update(shared.as_bytes());
+                hasher.update(Sha256::digest(password.as_bytes()));
+                let cipher = XSalsa20Poly1305::new(&hasher.finalize());
+                cipher.encrypt_in_place_detached(&nonce, b"", &mut plaintext).unwrap()
+            }
+        };
+        let mut packet = stage.to_be_bytes().to_vec();
+        packet.push(auth_type);
+        packet.extend(&[0; 11]);
+        packet.extend(nonce.as_slice());
+        packet.extend(&public_key(secret_key));
+        packet.extend(tag.as_slice());
+        packet.extend(plaintext);
+        packet
+    }
+
+    /// Switch packet with this label, carrying a handshake of an inner
+    /// session: temporary key, handle, data header, and route packet.
+    fn inner_handshake(stage: u32, secret_key: [u8; 32], their_key: [u8; 32], temp_key: [u8; 32], handle: u8) -> Vec<u8> {
+        let mut plaintext = temp_key.to_vec();
+        plaintext.extend(&[0, 0, 0, handle, 0x10, 0, 0x01, 0x00]);
+        plaintext.extend(route_packet());
+        let mut packet = vec![0, 0, 0, 0, 0, 0, 0, 0x13, 0, 0x40, 0, 0];
+        packet.extend(handshake(stage, 0, secret_key, their_key, None, plaintext));
+        packet
+    }
+
+    #[test]
+    fn test_inner_hello() {
+        let (secret_key_a, secret_key_b) = (secret_key(SECRET_KEY_A), secret_key(SECRET_KEY_B));
+        let packet = inner_handshake(0, secret_key_a, public_key(secret_key_b), public_key([5; 32]), 42);
+
+        let decoded = Decoder::new().decode(&packet, Layer::Switch);
+        assert!(decoded.contains("Cannot decrypt"), "Decrypted without key:\n{}", decoded);
+
+        let mut decoder = Decoder::new();
+        decoder.add_secret_key(secret_key_b);
+        let decoded = decoder.decode(&packet, Layer::Switch);
+        for line in &[
+                "Switch packet: label 0000.0000.0000.0013, version 1, label shift 0, penalty 0",
+                "  CryptoAuth hello: auth type 0, from 2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k (fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9)",
+                "    Sender's session handle 42",
+                "    Data packet: version 1, content type 256",
+                "        query: fn",
+                ] {
+            assert!(decoded.lines().any(|l| l == *line), "Missing line {:?} in:\n{}", line, decoded);
+        }
+    }
+
+    #[test]
+    fn test_inner_key() {
+        let (secret_key_a, secret_key_b) = (secret_key(SECRET_KEY_A), secret_key(SECRET_KEY_B));
+        let hello = inner_handshake(0, secret_key_a, public_key(secret_key_b), public_key([5; 32]), 42);
+        // B's reply, encrypted with B's key and A's temporary key.
+        let key = inner_handshake(2, secret_key_b, public_key([5; 32]), public_key([6; 32]), 43);
+
+        // The temporary key is only known once the hello is decrypted.
+        let mut decoder = Decoder::new();
+        decoder.add_secret_key(secret_key_b);
+        let decoded = decoder.decode(&key, Layer::Switch);
+        assert!(decoded.contains("Cannot decrypt"), "Decrypted without temporary key:\n{}", decoded);
+        decoder.decode(&hello, Layer::Switch);
+        let decoded = decoder.decode(&key, Layer::Switch);
+        let temp_key_line = format!("    Temporary key: {}", public_key_to_base32(&public_key([6; 32])));
+        for line in &[
+                "  CryptoAuth key: auth type 0, from dwzr0g0srh6gm4x0zmb84sr30kc7cqg0pxck6d3tht7sht6dbv80.k (fc28:c96b:11c7:8fa3:62ad:f8c6:efdf:6f6)",
+                &temp_key_line[..],
+                "    Sender's session handle 43",
+                "        query: fn",
+                ] {
+            assert!(decoded.lines().any(|l| l == *line), "Missing line {:?} in:\n{}", line, decoded);
+        }
+    }
+
+    #[test]
+    fn test_outer_hello_with_password() {
+        let (secret_key_a, secret_key_b) = (secret_key(SECRET_KEY_A), secret_key(SECRET_KEY_B));
+        // Temporary key, and a switch packet carrying a ping.
+        let mut plaintext = public_key([5; 32]).to_vec();
+        plaintext.extend(&[0, 0, 0, 0, 0, 0, 0, 0x13, 0, 0x40, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        plaintext.extend(&[0, 0, 0, 3, 0x09, 0xf9, 0x11, 0xf7, 0, 0, 0, 18, 1, 2]);
+        let packet = handshake(0, 1, secret_key_a, public_key(secret_key_b), Some("bar"), plaintext);
+
+        let mut decoder = Decoder::new();
+        decoder.add_secret_key(secret_key_b);
+        let decoded = decoder.decode(&packet, Layer::Udp);
+        assert!(decoded.contains("Cannot decrypt"), "Decrypted without password:\n{}", decoded);
+        decoder.add_password("foo");
+        decoder.add_password("bar");
+        let decoded = decoder.decode(&packet, Layer::Udp);
+        for line in &[
+                "CryptoAuth hello: auth type 1, from 2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k (fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9)",
+                "  Switch packet: label 0000.0000.0000.0013, version 1, label shift 0, penalty 0",
+                "    Control ping: version 18, data 0102",
+                ] {
+            assert!(decoded.lines().any(|l| l == *line), "Missing line {:?} in:\n{}", line, decoded);
+        }
+    }
+
+    #[test]
+    fn test_pcap_and_hex_dump() {
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 1, 0, 0, 0];
+        let mut frame = vec![0; 12];
+        frame.extend(&[0x08, 0x00]);
+        frame.extend(&[0x45, 0, 0, 33, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend(&[0x30, 0x39, 0x30, 0x3a, 0, 13, 0, 0]);
+        frame.extend(b"hello");
+        pcap.extend(&[1, 0, 0, 0, 5, 0, 0, 0, frame.len() as u8, 0, 0, 0, frame.len() as u8, 0, 0, 0]);
+        pcap.extend(&frame);
+
+        let frames = parse_pcap(&pcap).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].timestamp, 1_000_005);
+        assert_eq!(frames[0].source, SocketAddr::from_str("10.0.0.1:12345").unwrap());
+        assert_eq!(frames[0].destination, SocketAddr::from_str("10.0.0.2:12346").unwrap());
+        assert_eq!(frames[0].payload, b"hello");
+        assert_eq!(parse_pcap(&pcap[..30]), Err(PcapError::Truncated));
+
+        let dump = "# Two frames\n0000: 00 01 02 03  ....\n0004: 0405\n\nffff\n";
+        assert_eq!(parse_hex_dump(dump), vec![vec![0, 1, 2, 3, 4, 5], vec![0xff, 0xff]]);
+    }
+}
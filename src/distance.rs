@@ -0,0 +1,212 @@
+//! XOR metric between addresses.
+//! See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#the-router
+
+use std::fmt;
+
+use node::{Address, ADDRESS_BITS, rotate_64};
+
+/// XOR of two (rotated) addresses. Distances are ordered like
+/// unsigned 128-bit integers.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Distance {
+    bytes: [u8; 16],
+}
+
+impl Distance {
+    /// Distance between two addresses. It is symmetric, and zero only
+    /// if both addresses are equal.
+    pub fn between(a: &Address, b: &Address) -> Distance {
+        let a = rotate_64(&a.bytes());
+        let b = rotate_64(&b.bytes());
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = a[i] ^ b[i];
+        }
+        Distance { bytes: bytes }
+    }
+
+    /// Builds a distance from its big-endian representation.
+    pub fn from_bytes(bytes: [u8; 16]) -> Distance {
+        Distance { bytes: bytes }
+    }
+
+    /// Big-endian representation of the distance.
+    pub fn bytes(&self) -> [u8; 16] {
+        self.bytes
+    }
+
+    pub fn zero() -> Distance {
+        Distance { bytes: [0; 16] }
+    }
+
+    pub fn max() -> Distance {
+        Distance { bytes: [0xff; 16] }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.bytes.iter().all(|b| *b == 0)
+    }
+
+    /// Number of leading zero bits, ie. length of the prefix the two
+    /// addresses have in common.
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for byte in self.bytes.iter() {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+
+    /// Index of the k-bucket containing addresses at this distance,
+    /// which is the number of significant bits of the distance
+    /// (0 for a zero distance, `ADDRESS_BITS` for the farthest half
+    /// of the keyspace).
+    pub fn bucket_index(&self) -> usize {
+        ADDRESS_BITS - self.leading_zeros() as usize
+    }
+
+    /// Returns the smallest and the largest distances of the bucket.
+    ///
+    /// Panics if `bucket_index > ADDRESS_BITS`.
+    pub fn bucket_bounds(bucket_index: usize) -> (Distance, Distance) {
+        assert!(bucket_index <= ADDRESS_BITS, "Bucket index out of range.");
+        if bucket_index == 0 {
+            return (Distance::zero(), Distance::zero())
+        }
+        let mut min = [0u8; 16];
+        let mut max = [0u8; 16];
+        let top_bit = bucket_index - 1; // Position from the least significant bit.
+        for bit in 0..(top_bit+1) {
+            let byte = 15 - bit/8;
+            max[byte] |= 1 << (bit % 8);
+        }
+        min[15 - top_bit/8] = 1 << (top_bit % 8);
+        (Distance { bytes: min }, Distance { bytes: max })
+    }
+}
+
+impl fmt::Debug for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Distance(")?;
+        for byte in self.bytes.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl Address {
+    /// XOR distance to another address.
+    pub fn distance(&self, other: &Address) -> Distance {
+        Distance::between(self, other)
+    }
+
+    /// Returns whether this address is strictly closer to the target
+    /// than the other one.
+    pub fn is_closer_than(&self, other: &Address, target: &Address) -> bool {
+        self.distance(target) < other.distance(target)
+    }
+
+    /// Returns the address at the given distance from this one.
+    pub fn at_distance(&self, distance: &Distance) -> Address {
+        let rotated = rotate_64(&self.bytes());
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = rotated[i] ^ distance.bytes[i];
+        }
+        Address::new(&rotate_64(&bytes))
+    }
+
+    /// Returns the first and last addresses of a bucket of a table
+    /// centered on this address. The bucket contains all addresses
+    /// between them in the keyspace (ie. after rotating them 64 bits,
+    /// see `node::rotate_64`).
+    ///
+    /// Panics if `bucket_index` is 0 or greater than `ADDRESS_BITS`.
+    pub fn bucket_range(&self, bucket_index: usize) -> (Address, Address) {
+        assert!(bucket_index > 0 && bucket_index <= ADDRESS_BITS, "Bucket index out of range.");
+        let mut first = rotate_64(&self.bytes());
+        let mut last = first;
+        let top_bit = bucket_index - 1;
+        for bit in 0..top_bit {
+            let byte = 15 - bit/8;
+            first[byte] &= !(1 << (bit % 8));
+            last[byte] |= 1 << (bit % 8);
+        }
+        first[15 - top_bit/8] ^= 1 << (top_bit % 8);
+        last[15 - top_bit/8] ^= 1 << (top_bit % 8);
+        (Address::new(&rotate_64(&first)), Address::new(&rotate_64(&last)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_distance() {
+        let a = Address::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap();
+        let b = Address::from_str("fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc").unwrap();
+        let c = Address::from_str("fcb9:326d:37d5:c57b:7ee5:28b5:7aa5:525").unwrap();
+        assert!(a.distance(&a).is_zero());
+        assert_eq!(a.distance(&b), b.distance(&a));
+        assert_eq!(a.distance(&a).bucket_index(), 0);
+        // The last 64 bits come first: b0cb ^ d3c2 = 6309
+        assert_eq!(a.distance(&b).leading_zeros(), 1);
+        assert_eq!(a.distance(&b).bucket_index(), 127);
+        // b0cb ^ 7ee5 = cec2 > 6309
+        assert!(b.is_closer_than(&c, &a));
+        assert!(!c.is_closer_than(&b, &a));
+        assert_eq!(a.at_distance(&a.distance(&b)), b);
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        let (min, max) = Distance::bucket_bounds(1);
+        assert_eq!(min.bytes(), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(max.bytes(), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let (min, max) = Distance::bucket_bounds(10);
+        assert_eq!(min.bytes(), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0b10, 0]);
+        assert_eq!(max.bytes(), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0b11, 0xff]);
+        assert_eq!(min.bucket_index(), 10);
+        assert_eq!(max.bucket_index(), 10);
+        let (min, max) = Distance::bucket_bounds(128);
+        assert_eq!(min.bytes()[0], 0x80);
+        assert_eq!(max, Distance::max());
+    }
+
+    #[test]
+    fn test_bucket_range() {
+        let a = Address::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap();
+        let (first, last) = a.bucket_range(16);
+        // Bits of the keyspace are those of the rotated address, so the
+        // least significant ones are at the end of the first half.
+        assert_eq!(first, Address::from_str("fc8f:a188:1b5:8000:b0cb:5729:23a1:60f9").unwrap());
+        assert_eq!(last, Address::from_str("fc8f:a188:1b5:ffff:b0cb:5729:23a1:60f9").unwrap());
+        assert_eq!(a.distance(&first).bucket_index(), 16);
+        assert_eq!(a.distance(&last).bucket_index(), 16);
+    }
+
+    fn to_u128(distance: &Distance) -> u128 {
+        distance.bytes().iter().fold(0, |acc, byte| (acc << 8) | *byte as u128)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_xor_metric(a in any::<[u8; 16]>(), b in any::<[u8; 16]>(), c in any::<[u8; 16]>()) {
+            let (a, b, c) = (Address::new(&a), Address::new(&b), Address::new(&c));
+            prop_assert!(a.distance(&a).is_zero());
+            prop_assert_eq!(a.distance(&b).is_zero(), a == b);
+            prop_assert_eq!(a.distance(&b), b.distance(&a));
+            let (ab, bc, ac) = (to_u128(&a.distance(&b)), to_u128(&b.distance(&c)), to_u128(&a.distance(&c)));
+            if let Some(sum) = ab.checked_add(bc) {
+                prop_assert!(ac <= sum);
+            }
+        }
+    }
+}
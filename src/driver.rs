@@ -0,0 +1,853 @@
+//! Sans-IO wrapper of the `Router`: the application feeds it `Input`s
+//! (packets received, time passing, lookups requested, links going
+//! up or down), and polls it for `Action`s to perform (packets to send,
+//! lookups completed, nodes discovered).
+//!
+//! It does not do any I/O itself, nor does it read the clock, so it
+//! can be plugged on any transport and tested deterministically.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use fcp_switching::route_packet::{RoutePacket, NodeData};
+use fcp_switching::operation::Label;
+
+use node::{Address, Node, PUBLIC_KEY_LENGTH};
+use node_store::{GetNodeResult, OwnedGetNodeResult};
+use router::{Router, RoutingMode, find_node_query, ping_query, get_peers_query};
+use supernode::{announce_query, get_route_query, ANNOUNCE_QUERY, GET_ROUTE_QUERY, MAX_LINKS_PER_ANNOUNCER};
+use label::{splice, path_to_u64};
+use recording::{Event, Recorder};
+use janitor::{Janitor, JanitorConfig, Task};
+use event::{NodeStoreEvent, Callback};
+
+/// Event the application feeds to the `Driver`.
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// A route packet was received from a node through an end-to-end
+    /// session. `label` is the path from us to that node (ie. the
+    /// label of the received switch packet, reversed). `now` is when it
+    /// was received, on the same clock as `Tick`.
+    RoutePacket { label: Label, public_key: [u8; PUBLIC_KEY_LENGTH], packet: RoutePacket, now: u64 },
+    /// Time passed. `now` is a monotonic timestamp in milliseconds.
+    Tick { now: u64 },
+    /// The application wants to know the path to this address.
+    Lookup { target: Address },
+    /// A direct link with this node was established.
+    PeerUp { node: Node },
+    /// The direct link with this node went down.
+    PeerDown { address: Address },
+}
+
+/// Reason of the failure of a lookup.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LookupError {
+    /// All nodes close to the target were queried, and none of them
+    /// knew it.
+    NotFound,
+    /// The routing table is empty; we need peers first.
+    NoNodes,
+}
+
+/// Something the application should do, returned by `Driver::poll_action`.
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Send the packet to the node, through an end-to-end session.
+    SendToNode { node: Node, packet: RoutePacket },
+    /// Send the packet back through the end-to-end session of the node
+    /// at this path (used for replies).
+    SendToLabel { label: Label, packet: RoutePacket },
+    /// A lookup requested with `Input::Lookup` is over.
+    LookupCompleted { target: Address, result: Result<Node, LookupError> },
+    /// A step of a lookup: `fn` queries were sent to these nodes.
+    LookupQueried { target: Address, nodes: Vec<Node> },
+    /// A node queried by a lookup replied with these nodes (with paths
+    /// from us), or timed out (`nodes` is `None`).
+    LookupReplied { target: Address, node: Node, nodes: Option<Vec<Node>> },
+    /// A node was added to the routing table.
+    NodeDiscovered { address: Address, node: Node },
+    /// A ping sent with `Driver::ping` got a reply after `rtt`
+    /// milliseconds, or timed out (`rtt` is `None`).
+    PingCompleted { node: Node, rtt: Option<u64> },
+    /// A query sent with `Driver::get_peers` got a reply, containing
+    /// these nodes (with paths from us), or timed out (`peers` is `None`).
+    GetPeersCompleted { node: Node, peers: Option<Vec<Node>> },
+    /// A query sent with `Driver::find_node` got a reply, containing
+    /// these nodes (with paths from us), or timed out (`nodes` is `None`).
+    FindNodeCompleted { node: Node, target: Address, nodes: Option<Vec<Node>> },
+}
+
+/// Parameters of the `Driver`.
+#[derive(Clone, Debug)]
+pub struct DriverConfig {
+    /// Milliseconds to wait for a reply before considering a node
+    /// unreachable.
+    pub query_timeout: u64,
+    /// Maximum number of queries in flight for a lookup.
+    pub parallelism: usize,
+    /// Number of closest nodes considered at each step of a lookup.
+    pub nb_closest: usize,
+    /// Milliseconds between announcements of our peers to our
+    /// supernode, in subnode mode (see `RoutingMode::Subnode`).
+    pub announce_interval: u64,
+    /// Background maintenance of the routing table (see the `janitor`
+    /// module). Disabled by default.
+    pub janitor: Option<JanitorConfig>,
+}
+
+impl Default for DriverConfig {
+    fn default() -> DriverConfig {
+        DriverConfig {
+            query_timeout: 5000,
+            parallelism: 3,
+            nb_closest: 8,
+            announce_interval: 60000,
+            janitor: None,
+        }
+    }
+}
+
+/// Why we sent a query.
+#[derive(Clone, Debug)]
+enum QueryKind {
+    /// `fn` query, part of a lookup of this target.
+    FindNode { target: Address, lookup_id: u64 },
+    /// `fn` query sent with `Driver::find_node`, outside lookups.
+    FindNodeQuery { target: Address },
+    /// `pn` query.
+    Ping,
+    /// `gp` query.
+    GetPeers,
+    /// `pn` query sent by the janitor.
+    Refresh,
+    /// `ann` query, announcing our peers to our supernode.
+    Announce,
+    /// `gr` query to our supernode, part of a lookup of this target.
+    GetRoute { target: Address, lookup_id: u64 },
+}
+
+impl QueryKind {
+    /// Name of the query, as sent on the wire.
+    fn name(&self) -> &'static str {
+        match *self {
+            QueryKind::FindNode { .. } | QueryKind::FindNodeQuery { .. } => "fn",
+            QueryKind::Ping | QueryKind::Refresh => "pn",
+            QueryKind::GetPeers => "gp",
+            QueryKind::Announce => ANNOUNCE_QUERY,
+            QueryKind::GetRoute { .. } => GET_ROUTE_QUERY,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PendingQuery {
+    kind: QueryKind,
+    to: Node,
+    sent_at: u64,
+    deadline: u64,
+}
+
+/// State of an iterative lookup.
+struct Lookup {
+    /// Distinguishes successive lookups of the same target.
+    id: u64,
+    /// Nodes we already sent a query to.
+    queried: HashSet<Address>,
+    /// Number of queries waiting for a reply.
+    in_flight: usize,
+    /// Timestamp of the start of the lookup.
+    started_at: u64,
+    /// Started by the janitor; its progress and result are not
+    /// reported to the application.
+    background: bool,
+}
+
+pub struct Driver<C: ?Sized = Callback> {
+    router: Router<C>,
+    config: DriverConfig,
+    now: u64,
+    next_transaction_id: u64,
+    next_lookup_id: u64,
+    pending_queries: HashMap<Vec<u8>, PendingQuery>,
+    lookups: HashMap<Address, Lookup>,
+    actions: VecDeque<Action>,
+    /// When to announce our peers to our supernode, in subnode mode.
+    next_announce: u64,
+    recorder: Option<Recorder>,
+    recording_error: Option<io::Error>,
+    janitor: Option<Janitor>,
+}
+
+impl<C: ?Sized + FnMut(&NodeStoreEvent)> Driver<C> {
+    pub fn new(router: Router<C>, config: DriverConfig) -> Driver<C> {
+        let janitor = config.janitor.clone().map(|janitor_config| Janitor::new(janitor_config, router.my_address().clone()));
+        Driver {
+            router: router,
+            config: config,
+            now: 0,
+            next_transaction_id: 0,
+            next_lookup_id: 0,
+            pending_queries: HashMap::new(),
+            lookups: HashMap::new(),
+            actions: VecDeque::new(),
+            next_announce: 0,
+            recorder: None,
+            recording_error: None,
+            janitor: janitor,
+        }
+    }
+
+    pub fn router(&self) -> &Router<C> {
+        &self.router
+    }
+
+    pub fn router_mut(&mut self) -> &mut Router<C> {
+        &mut self.router
+    }
+
+    /// Last timestamp given with `Input::Tick`.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Writes everything fed to the driver from now on to `writer`, so
+    /// it can be replayed (see the `recording` module). This should be
+    /// called before feeding anything, so the replay starts from the
+    /// same state. A buffered `writer` should be flushed regularly
+    /// with `flush_recording`.
+    pub fn start_recording(&mut self, writer: Box<dyn Write + Send>) -> io::Result<()> {
+        self.recorder = Some(Recorder::new(writer, self.router.my_address(), self.router.mode(), &self.config)?);
+        Ok(())
+    }
+
+    /// Flushes the writer of the recording, if any. Errors stop the
+    /// recording, like write errors.
+    pub fn flush_recording(&mut self) {
+        let res = match self.recorder {
+            Some(ref mut recorder) => recorder.flush(),
+            None => return,
+        };
+        if let Err(e) = res {
+            self.recorder = None;
+            self.recording_error = Some(e);
+        }
+    }
+
+    /// Returns the error that stopped the recording, if writing failed.
+    pub fn take_recording_error(&mut self) -> Option<io::Error> {
+        self.recording_error.take()
+    }
+
+    fn record<F: FnOnce() -> Event>(&mut self, event: F) {
+        let res = match self.recorder {
+            Some(ref mut recorder) => recorder.record(&event()),
+            None => return,
+        };
+        if let Err(e) = res {
+            self.recorder = None;
+            self.recording_error = Some(e);
+        }
+    }
+
+    /// Returns the next action to perform, if any.
+    pub fn poll_action(&mut self) -> Option<Action> {
+        self.actions.pop_front()
+    }
+
+    /// Feeds an event to the driver. Actions it triggers can then be
+    /// fetched with `poll_action`.
+    pub fn handle_input(&mut self, input: Input) {
+        self.record(|| Event::Input(input.clone()));
+        match input {
+            Input::RoutePacket { label, public_key, packet, now } => {
+                // So round-trip times are not rounded to ticks.
+                self.now = self.now.max(now);
+                self.on_route_packet(label, public_key, packet)
+            }
+            Input::Tick { now } => self.on_tick(now),
+            Input::Lookup { target } => self.start_lookup(target, false),
+            Input::PeerUp { node } => {
+                let address = node.address();
+                let is_new = !self.router.node_store().contains(&address);
+                self.router.add_peer(node.clone());
+                if is_new {
+                    self.actions.push_back(Action::NodeDiscovered { address: address, node: node });
+                }
+                // Tell our supernode, if any, at the next tick.
+                self.next_announce = self.now;
+            }
+            Input::PeerDown { address } => {
+                self.router.remove_peer(&address);
+                self.next_announce = self.now;
+            }
+        }
+    }
+
+    /// Sends a `pn` query to the node. When it replies, it is marked
+    /// as reachable; if it does not, it is marked as unreachable.
+    pub fn ping(&mut self, node: Node) {
+        self.record(|| Event::Ping { node: node.clone() });
+        let transaction_id = self.gen_transaction_id();
+        let packet = ping_query(transaction_id.clone());
+        self.send_query(transaction_id, QueryKind::Ping, node, packet);
+    }
+
+    /// Sends a `gp` query to the node, asking about its peers. They
+    /// will be added to the routing table.
+    pub fn get_peers(&mut self, node: Node) {
+        self.record(|| Event::GetPeers { node: node.clone() });
+        let transaction_id = self.gen_transaction_id();
+        let packet = get_peers_query(transaction_id.clone());
+        self.send_query(transaction_id, QueryKind::GetPeers, node, packet);
+    }
+
+    /// Sends a `fn` query to the node, asking about the nodes it knows
+    /// closest to the target. They will be added to the routing table.
+    pub fn find_node(&mut self, node: Node, target: Address) {
+        self.record(|| Event::FindNode { node: node.clone(), target: target.clone() });
+        let transaction_id = self.gen_transaction_id();
+        let packet = find_node_query(&target, transaction_id.clone());
+        self.send_query(transaction_id, QueryKind::FindNodeQuery { target: target }, node, packet);
+    }
+
+    fn gen_transaction_id(&mut self) -> Vec<u8> {
+        let id = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        (0..8).map(|i| (id >> (8*(7-i))) as u8).collect()
+    }
+
+    fn send_query(&mut self, transaction_id: Vec<u8>, kind: QueryKind, node: Node, packet: RoutePacket) {
+        let pending_query = PendingQuery {
+            kind: kind,
+            to: node.clone(),
+            sent_at: self.now,
+            deadline: self.now.saturating_add(self.config.query_timeout),
+        };
+        self.pending_queries.insert(transaction_id, pending_query);
+        self.actions.push_back(Action::SendToNode { node: node, packet: packet });
+    }
+
+    /// Inserts a node in the routing table, and tells the application
+    /// if it was not known.
+    fn learn_node(&mut self, address: Address, node: Node) {
+        if address == *self.router.my_address() {
+            return;
+        }
+        let is_new = !self.router.node_store().contains(&address);
+        self.router.update(address.clone(), node.clone());
+        if is_new && self.router.node_store().contains(&address) {
+            self.actions.push_back(Action::NodeDiscovered { address: address, node: node });
+        }
+    }
+
+    fn on_route_packet(&mut self, label: Label, public_key: [u8; PUBLIC_KEY_LENGTH], packet: RoutePacket) {
+        if path_to_u64(&label) <= 1 {
+            // Not a path to another node; we could not reply anyway.
+            return;
+        }
+        let version = match u64::try_from(packet.protocol_version) {
+            Ok(version) => version,
+            Err(_) => return, // Negative version; not a valid packet
+        };
+        let sender = Node::new(public_key, label, version);
+        let sender_address = sender.address();
+        self.learn_node(sender_address.clone(), sender.clone());
+        if let Some(ref mut janitor) = self.janitor {
+            janitor.heard_from(sender_address.clone(), self.now);
+        }
+
+        match packet.query.as_ref().map(String::as_str) {
+            Some(ANNOUNCE_QUERY) | Some(GET_ROUTE_QUERY) => {
+                if let Ok(reply) = self.router.on_supernode_query(&sender, &packet) {
+                    self.actions.push_back(Action::SendToLabel { label: label, packet: reply });
+                }
+                return;
+            }
+            Some(_) => {
+                if let Ok(replies) = self.router.on_route_packet(&sender, &packet) {
+                    for (label, reply) in replies {
+                        self.actions.push_back(Action::SendToLabel { label: label, packet: reply });
+                    }
+                }
+                return;
+            }
+            None => (),
+        }
+
+        // This is a reply to one of our queries.
+        let pending_query = match self.pending_queries.remove(&packet.transaction_id) {
+            Some(pending_query) => pending_query,
+            None => return,
+        };
+        if pending_query.to.address() != sender_address {
+            // Someone else replied to this transaction id; ignore it.
+            self.pending_queries.insert(packet.transaction_id, pending_query);
+            return;
+        }
+        self.router.node_store_mut().mark_reachable(&sender_address);
+        let node_data = packet.nodes.unwrap_or_default();
+        let nodes = match pending_query.kind {
+            QueryKind::GetRoute { .. } => self.learn_routes(node_data),
+            _ => self.learn_nodes(&label, node_data),
+        };
+        match pending_query.kind {
+            QueryKind::Ping => {
+                let rtt = self.now.saturating_sub(pending_query.sent_at);
+                self.actions.push_back(Action::PingCompleted { node: pending_query.to, rtt: Some(rtt) });
+            }
+            QueryKind::GetPeers => {
+                self.actions.push_back(Action::GetPeersCompleted { node: pending_query.to, peers: Some(nodes) });
+            }
+            QueryKind::FindNodeQuery { target } => {
+                self.actions.push_back(Action::FindNodeCompleted { node: pending_query.to, target: target, nodes: Some(nodes) });
+            }
+            QueryKind::Announce | QueryKind::Refresh => (),
+            QueryKind::FindNode { target, lookup_id } | QueryKind::GetRoute { target, lookup_id } => {
+                if self.is_current_lookup(&target, lookup_id) && !self.is_background_lookup(&target) {
+                    self.actions.push_back(Action::LookupReplied { target: target.clone(), node: pending_query.to, nodes: Some(nodes) });
+                }
+                self.on_lookup_query_done(target, lookup_id);
+            }
+        }
+    }
+
+    fn is_current_lookup(&self, target: &Address, lookup_id: u64) -> bool {
+        self.lookups.get(target).map(|lookup| lookup.id) == Some(lookup_id)
+    }
+
+    fn is_background_lookup(&self, target: &Address) -> bool {
+        self.lookups.get(target).map_or(false, |lookup| lookup.background)
+    }
+
+    /// Adds nodes sent by the node at this path to the routing table,
+    /// and returns them with paths from us.
+    fn learn_nodes(&mut self, label: &Label, nodes: Vec<NodeData>) -> Vec<Node> {
+        let mut learned = Vec::new();
+        for node_data in nodes {
+            let path = match splice(&node_data.path, label) {
+                Some(path) => path,
+                None => continue,
+            };
+            let node = Node::new(node_data.public_key, path, node_data.version);
+            self.learn_node(node.address(), node.clone());
+            learned.push(node);
+        }
+        learned
+    }
+
+    /// Adds nodes sent by our supernode, whose paths start from us, to
+    /// the routing table.
+    fn learn_routes(&mut self, nodes: Vec<NodeData>) -> Vec<Node> {
+        let mut learned = Vec::new();
+        for node_data in nodes {
+            if path_to_u64(&node_data.path) <= 1 {
+                continue;
+            }
+            let node = Node::new(node_data.public_key, node_data.path, node_data.version);
+            self.learn_node(node.address(), node.clone());
+            learned.push(node);
+        }
+        learned
+    }
+
+    /// Called when a query of a lookup got a reply or timed out.
+    fn on_lookup_query_done(&mut self, target: Address, lookup_id: u64) {
+        match self.lookups.get_mut(&target) {
+            Some(ref mut lookup) if lookup.id == lookup_id => lookup.in_flight -= 1,
+            _ => return, // This lookup is already over
+        }
+        self.continue_lookup(target);
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        self.now = now;
+        let mut expired: Vec<Vec<u8>> = self.pending_queries.iter()
+                .filter(|&(_, query)| query.deadline <= now)
+                .map(|(transaction_id, _)| transaction_id.clone())
+                .collect();
+        // In the order they were sent, so replays give the same actions.
+        expired.sort();
+        for transaction_id in expired {
+            let query = self.pending_queries.remove(&transaction_id).unwrap();
+            self.router.node_store_mut().mark_unreachable(&query.to.address());
+            self.router.metrics_mut().record_query_timeout(query.kind.name());
+            match query.kind {
+                QueryKind::Ping =>
+                    self.actions.push_back(Action::PingCompleted { node: query.to, rtt: None }),
+                QueryKind::GetPeers =>
+                    self.actions.push_back(Action::GetPeersCompleted { node: query.to, peers: None }),
+                QueryKind::FindNodeQuery { target } =>
+                    self.actions.push_back(Action::FindNodeCompleted { node: query.to, target: target, nodes: None }),
+                QueryKind::Announce | QueryKind::Refresh => (),
+                QueryKind::FindNode { target, lookup_id } | QueryKind::GetRoute { target, lookup_id } => {
+                    if self.is_current_lookup(&target, lookup_id) && !self.is_background_lookup(&target) {
+                        self.actions.push_back(Action::LookupReplied { target: target.clone(), node: query.to, nodes: None });
+                    }
+                    self.on_lookup_query_done(target, lookup_id);
+                }
+            }
+        }
+        self.announce_if_due();
+        self.run_janitor();
+    }
+
+    /// Runs the janitor's tasks, if it is time for a round.
+    fn run_janitor(&mut self) {
+        let tasks = match self.janitor {
+            Some(ref mut janitor) => janitor.tick(self.now, self.router.node_store(), self.config.parallelism),
+            None => return,
+        };
+        for task in tasks {
+            match task {
+                Task::Lookup { target } => self.start_lookup(target, true),
+                Task::Ping { node } => {
+                    let transaction_id = self.gen_transaction_id();
+                    let packet = ping_query(transaction_id.clone());
+                    self.send_query(transaction_id, QueryKind::Refresh, node, packet);
+                }
+            }
+        }
+    }
+
+    /// Our supernode, if we are a subnode and know a path to it.
+    fn supernode(&self) -> Option<Node> {
+        let address = match *self.router.mode() {
+            RoutingMode::Subnode { ref supernode } => supernode,
+            _ => return None,
+        };
+        if let Some(peer) = self.router.peers().get(address) {
+            return Some(peer.clone())
+        }
+        match self.router.node_store().get_node(address, 1) {
+            GetNodeResult::FoundNode(node) => Some(node.clone()),
+            _ => None,
+        }
+    }
+
+    /// In subnode mode, announces our peers to our supernode when it is
+    /// time to, or looks the supernode up if we do not know a path to
+    /// it yet.
+    fn announce_if_due(&mut self) {
+        let supernode_address = match *self.router.mode() {
+            RoutingMode::Subnode { ref supernode } => supernode.clone(),
+            _ => return,
+        };
+        if self.now < self.next_announce {
+            return;
+        }
+        self.next_announce = self.now.saturating_add(self.config.announce_interval);
+        match self.supernode() {
+            Some(supernode) => {
+                let transaction_id = self.gen_transaction_id();
+                let packet = {
+                    let mut peers: Vec<&Node> = self.router.peers().values().collect();
+                    peers.sort_by_key(|node| *node.path());
+                    peers.truncate(MAX_LINKS_PER_ANNOUNCER);
+                    announce_query(peers, transaction_id.clone())
+                };
+                self.send_query(transaction_id, QueryKind::Announce, supernode, packet);
+            }
+            None => self.start_lookup(supernode_address, false),
+        }
+    }
+
+    fn start_lookup(&mut self, target: Address, background: bool) {
+        if let Some(lookup) = self.lookups.get_mut(&target) {
+            // Already looking for it; report the result if the
+            // application asked for it too.
+            lookup.background &= background;
+            return;
+        }
+        let lookup = Lookup {
+            id: self.next_lookup_id,
+            queried: HashSet::new(),
+            in_flight: 0,
+            started_at: self.now,
+            background: background,
+        };
+        self.next_lookup_id += 1;
+        self.lookups.insert(target.clone(), lookup);
+        let is_known = self.router.node_store().contains(&target);
+        match self.supernode() {
+            Some(supernode) if !is_known => self.ask_supernode(target, supernode),
+            _ => self.continue_lookup(target),
+        }
+    }
+
+    /// Asks our supernode for a path to the target of a lookup. If it
+    /// does not answer with one, the lookup goes on with the DHT.
+    fn ask_supernode(&mut self, target: Address, supernode: Node) {
+        let lookup_id = {
+            let lookup = self.lookups.get_mut(&target).unwrap();
+            lookup.queried.insert(supernode.address());
+            lookup.in_flight += 1;
+            lookup.id
+        };
+        let transaction_id = self.gen_transaction_id();
+        let packet = get_route_query(&target, transaction_id.clone());
+        let kind = QueryKind::GetRoute { target: target.clone(), lookup_id: lookup_id };
+        self.send_query(transaction_id, kind, supernode.clone(), packet);
+        if !self.is_background_lookup(&target) {
+            self.actions.push_back(Action::LookupQueried { target: target, nodes: vec![supernode] });
+        }
+    }
+
+    /// Sends queries to the closest nodes not queried yet, or ends the
+    /// lookup if the target was found or there is no node left to query.
+    fn continue_lookup(&mut self, target: Address) {
+        if !self.lookups.contains_key(&target) {
+            // Lookup already completed
+            return;
+        }
+        let result = self.router.node_store().get_node(&target, self.config.nb_closest).into_owned();
+        let candidates: Vec<(Address, Node)> = match result {
+            OwnedGetNodeResult::FoundNode(node) => {
+                self.complete_lookup(target, Ok(node));
+                return;
+            }
+            OwnedGetNodeResult::Nothing => {
+                self.complete_lookup(target, Err(LookupError::NoNodes));
+                return;
+            }
+            OwnedGetNodeResult::ClosestNodes(nodes) => {
+                let lookup = &self.lookups[&target];
+                nodes.into_iter().filter(|&(ref addr, _)| !lookup.queried.contains(addr)).collect()
+            }
+        };
+
+        let (lookup_id, mut nb_queries, background) = {
+            let lookup = &self.lookups[&target];
+            (lookup.id, self.config.parallelism.saturating_sub(lookup.in_flight), lookup.background)
+        };
+        if background {
+            // Lookups of the janitor are bounded by its budget.
+            if let Some(ref mut janitor) = self.janitor {
+                nb_queries = janitor.take_queries(nb_queries.min(candidates.len()));
+            }
+        }
+        let mut queried = Vec::new();
+        for (address, node) in candidates.into_iter().take(nb_queries) {
+            queried.push(node.clone());
+            {
+                let lookup = self.lookups.get_mut(&target).unwrap();
+                lookup.queried.insert(address);
+                lookup.in_flight += 1;
+            }
+            let transaction_id = self.gen_transaction_id();
+            let packet = find_node_query(&target, transaction_id.clone());
+            let kind = QueryKind::FindNode { target: target.clone(), lookup_id: lookup_id };
+            self.send_query(transaction_id, kind, node, packet);
+        }
+        if !queried.is_empty() && !background {
+            self.actions.push_back(Action::LookupQueried { target: target.clone(), nodes: queried });
+        }
+
+        if self.lookups[&target].in_flight == 0 {
+            self.complete_lookup(target, Err(LookupError::NotFound));
+        }
+    }
+
+    fn complete_lookup(&mut self, target: Address, result: Result<Node, LookupError>) {
+        let background = match self.lookups.remove(&target) {
+            Some(lookup) => {
+                if !lookup.background {
+                    let duration = self.now.saturating_sub(lookup.started_at);
+                    self.router.metrics_mut().record_lookup(&result, duration);
+                }
+                lookup.background
+            }
+            None => false,
+        };
+        if result.is_ok() && *self.router.mode() == (RoutingMode::Subnode { supernode: target.clone() }) {
+            // Found our supernode; announce ourselves at the next tick.
+            self.next_announce = self.now;
+        }
+        if !background {
+            self.actions.push_back(Action::LookupCompleted { target: target, result: result });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use label::path_from_u64;
+
+    fn new_driver(public_key: [u8; PUBLIC_KEY_LENGTH]) -> Driver {
+        let router = Router::new(Address::from_public_key(&public_key));
+        Driver::new(router, DriverConfig::default())
+    }
+
+    #[test]
+    fn test_lookup_through_peer() {
+        let (pk_a, pk_b, pk_c) = ([1; 32], [2; 32], [3; 32]);
+        let mut a = new_driver(pk_a);
+        let mut b = new_driver(pk_b);
+        // A reaches B through interface 0b011, and B reaches A through 0b110.
+        a.handle_input(Input::PeerUp { node: Node::new(pk_b, path_from_u64(0b1_011), 18) });
+        // B reaches C through interface 0b101.
+        b.handle_input(Input::PeerUp { node: Node::new(pk_c, path_from_u64(0b1_101), 18) });
+
+        let target = Address::from_public_key(&pk_c);
+        a.handle_input(Input::Lookup { target: target.clone() });
+        match a.poll_action() {
+            Some(Action::NodeDiscovered { .. }) => (),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        let query = match a.poll_action() {
+            Some(Action::SendToNode { node, packet }) => {
+                assert_eq!(node.public_key(), &pk_b);
+                packet
+            }
+            action => panic!("Unexpected action: {:?}", action),
+        };
+        match a.poll_action() {
+            Some(Action::LookupQueried { nodes, .. }) => assert_eq!(nodes, vec![Node::new(pk_b, path_from_u64(0b1_011), 18)]),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        assert!(a.poll_action().is_none());
+
+        while b.poll_action().is_some() {}
+        b.handle_input(Input::RoutePacket { label: path_from_u64(0b1_110), public_key: pk_a, packet: query, now: 0 });
+        let reply = loop {
+            match b.poll_action() {
+                Some(Action::SendToLabel { label, packet }) => {
+                    assert_eq!(path_to_u64(&label), 0b1_110);
+                    break packet
+                }
+                Some(_) => continue,
+                None => panic!("B did not reply."),
+            }
+        };
+
+        a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: reply, now: 0 });
+        let mut result = None;
+        while let Some(action) = a.poll_action() {
+            if let Action::LookupCompleted { target: ref completed, result: ref res } = action {
+                assert_eq!(*completed, target);
+                result = Some(res.clone());
+            }
+        }
+        let node = result.expect("Lookup not completed.").unwrap();
+        assert_eq!(node.public_key(), &pk_c);
+        assert_eq!(path_to_u64(node.path()), 0b1_101_011);
+    }
+
+    #[test]
+    fn test_ping_rtt() {
+        let (pk_a, pk_b) = ([1; 32], [2; 32]);
+        let mut a = new_driver(pk_a);
+        let mut b = new_driver(pk_b);
+        a.handle_input(Input::Tick { now: 1000 });
+        a.ping(Node::new(pk_b, path_from_u64(0b1_011), 18));
+        let query = match a.poll_action() {
+            Some(Action::SendToNode { packet, .. }) => packet,
+            action => panic!("Unexpected action: {:?}", action),
+        };
+        b.handle_input(Input::RoutePacket { label: path_from_u64(0b1_110), public_key: pk_a, packet: query, now: 0 });
+        let reply = loop {
+            match b.poll_action() {
+                Some(Action::SendToLabel { packet, .. }) => break packet,
+                Some(_) => continue,
+                None => panic!("B did not reply."),
+            }
+        };
+        // No tick between the query and the reply.
+        a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: reply, now: 1042 });
+        let rtt = loop {
+            match a.poll_action() {
+                Some(Action::PingCompleted { rtt, .. }) => break rtt,
+                Some(_) => continue,
+                None => panic!("No reply to the ping."),
+            }
+        };
+        assert_eq!(rtt, Some(42));
+    }
+
+    #[test]
+    fn test_get_peers() {
+        let (pk_a, pk_b, pk_c) = ([1; 32], [2; 32], [3; 32]);
+        let mut a = new_driver(pk_a);
+        a.handle_input(Input::PeerUp { node: Node::new(pk_b, path_from_u64(0b1_011), 18) });
+        a.handle_input(Input::PeerUp { node: Node::new(pk_c, path_from_u64(0b1_101), 18) });
+        while a.poll_action().is_some() {}
+
+        // B is not given its own address.
+        let query = get_peers_query(b"gp".to_vec());
+        a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: query.clone(), now: 0 });
+        let reply = loop {
+            match a.poll_action() {
+                Some(Action::SendToLabel { label, packet }) => {
+                    assert_eq!(path_to_u64(&label), 0b1_011);
+                    break packet
+                }
+                Some(_) => continue,
+                None => panic!("A did not reply."),
+            }
+        };
+        let peers: Vec<[u8; 32]> = reply.nodes.unwrap().iter().map(|node| node.public_key).collect();
+        assert_eq!(peers, vec![pk_c]);
+
+        // Packets with a negative version are dropped.
+        let mut query = query;
+        query.protocol_version = -1;
+        a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: query, now: 0 });
+        assert!(a.poll_action().is_none());
+    }
+
+    #[test]
+    fn test_lookup_timeout() {
+        let mut a = new_driver([1; 32]);
+        a.handle_input(Input::PeerUp { node: Node::new([2; 32], path_from_u64(0b1_011), 18) });
+        let target = Address::from_public_key(&[3; 32]);
+        a.handle_input(Input::Lookup { target: target.clone() });
+        while a.poll_action().is_some() {}
+        a.handle_input(Input::Tick { now: 10000 });
+        match a.poll_action() {
+            Some(Action::LookupReplied { node, nodes, .. }) => assert_eq!((node.public_key(), nodes), (&[2; 32], None)),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        match a.poll_action() {
+            Some(Action::LookupCompleted { result, .. }) => assert_eq!(result, Err(LookupError::NotFound)),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        let peer_address = Address::from_public_key(&[2; 32]);
+        assert_eq!(a.router().node_store().reachability(&peer_address), Some(::node_store::Reachability::Unreachable));
+        let metrics = a.router().metrics();
+        assert_eq!((metrics.query_timeouts("fn"), metrics.lookups_failed()), (1, 1));
+        assert_eq!(metrics.lookup_duration().sum(), 10000);
+    }
+
+    #[test]
+    fn test_janitor() {
+        let config = DriverConfig {
+            janitor: Some(JanitorConfig { max_queries: 3, ..JanitorConfig::default() }),
+            ..DriverConfig::default()
+        };
+        let mut a = Driver::new(Router::new(Address::from_public_key(&[1; 32])), config);
+        a.handle_input(Input::PeerUp { node: Node::new([2; 32], path_from_u64(0b1_011), 18) });
+        a.handle_input(Input::Tick { now: 0 });
+        let actions: Vec<Action> = ::std::iter::from_fn(|| a.poll_action()).collect();
+        // The self-lookup uses the whole budget, and is not reported.
+        assert_eq!(actions.len(), 2);
+        match actions[1] {
+            Action::SendToNode { ref node, ref packet } => {
+                assert_eq!(node.public_key(), &[2; 32]);
+                assert_eq!(packet.target_address, Some(a.router().my_address().bytes().to_vec()));
+            }
+            ref action => panic!("Unexpected action: {:?}", action),
+        }
+
+        // It times out silently, and a bucket is refreshed.
+        a.handle_input(Input::Tick { now: 10000 });
+        while let Some(action) = a.poll_action() {
+            match action {
+                Action::SendToNode { .. } => (),
+                action => panic!("Unexpected action: {:?}", action),
+            }
+        }
+        let metrics = a.router().metrics();
+        assert_eq!((metrics.query_timeouts("fn"), metrics.lookups_failed()), (1, 0));
+    }
+}
@@ -0,0 +1,79 @@
+use node::{Address, Node, Path};
+
+/// Change of the content of a `NodeStore`, sent to its observers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NodeStoreEvent {
+    /// A node, which was not known before, has been inserted.
+    NodeAdded { address: Address, node: Node },
+    /// A node has been popped from a full bucket to make room for
+    /// another one.
+    NodeEvicted { address: Address, node: Node },
+    /// A known node has been updated with a different path.
+    PathChanged {
+        address: Address,
+        #[cfg_attr(feature = "serde", serde(with = "::serialization::path"))]
+        old_path: Path,
+        #[cfg_attr(feature = "serde", serde(with = "::serialization::path"))]
+        new_path: Path,
+    },
+    /// A known node has been reported as not answering.
+    NodeUnreachable { address: Address },
+}
+
+/// Identifies an observer, so it can be unsubscribed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// Callback of an observer of a `NodeStore`.
+pub type Callback = dyn FnMut(&NodeStoreEvent);
+
+/// Callback that can be sent to another thread. Stores whose callbacks
+/// are all of this type (eg. `NodeStore<SendCallback>`) are `Send`.
+pub type SendCallback = dyn FnMut(&NodeStoreEvent) + Send;
+
+/// List of callbacks interested in `NodeStoreEvent`s.
+pub struct Observers<C: ?Sized = Callback> {
+    next_id: u64,
+    callbacks: Vec<(SubscriptionId, Box<C>)>,
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers::default()
+    }
+}
+
+impl<C: ?Sized + FnMut(&NodeStoreEvent)> Observers<C> {
+    /// Registers a callback, which will be called for every event
+    /// emitted after this call.
+    pub fn subscribe(&mut self, callback: Box<C>) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.callbacks.push((id, callback));
+        id
+    }
+
+    /// Removes a callback. Returns whether it was subscribed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len_before = self.callbacks.len();
+        self.callbacks.retain(|&(callback_id, _)| callback_id != id);
+        self.callbacks.len() != len_before
+    }
+
+    /// Calls all callbacks with the event.
+    pub fn emit(&mut self, event: NodeStoreEvent) {
+        for &mut (_, ref mut callback) in self.callbacks.iter_mut() {
+            callback(&event);
+        }
+    }
+}
+
+impl<C: ?Sized> Default for Observers<C> {
+    fn default() -> Observers<C> {
+        Observers {
+            next_id: 0,
+            callbacks: Vec::new(),
+        }
+    }
+}
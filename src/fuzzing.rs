@@ -0,0 +1,187 @@
+//! Entry points for fuzzers (see the `fuzz/` directory), enabled by
+//! the `fuzzing` feature. Each function takes arbitrary bytes, feeds
+//! them to the code parsing network or user input, and must never
+//! panic.
+
+use std::str::{self, FromStr};
+
+use fcp_switching::route_packet::{RoutePacketBuilder, NodeData};
+use fcp_switching::data_packet::DataPacket;
+use fcp_switching::data_packet::Payload as DataPayload;
+
+use node::{Address, Node, parse_path, public_key_from_base32, path_length, PUBLIC_KEY_LENGTH};
+use label::{splice, routes_through, path_from_u64};
+use router::Router;
+use driver::{Driver, DriverConfig, Input};
+use bencode::Value;
+use base32;
+
+/// Reads the fuzzer's input as a sequence of values.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((byte, rest)) => {
+                self.data = rest;
+                *byte
+            }
+            None => 0,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let len = ::std::cmp::min(len, self.data.len());
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        bytes
+    }
+
+    fn u64(&mut self) -> u64 {
+        (0..8).fold(0, |acc, _| (acc << 8) | self.byte() as u64)
+    }
+
+    fn array<T: Default + AsMut<[u8]>>(&mut self) -> T {
+        let mut array = T::default();
+        for byte in array.as_mut().iter_mut() {
+            *byte = self.byte();
+        }
+        array
+    }
+
+    /// Byte string prefixed with its length, or `None` if the length
+    /// is 255.
+    fn optional_bytes(&mut self) -> Option<Vec<u8>> {
+        match self.byte() {
+            255 => None,
+            len => Some(self.bytes(len as usize).to_vec()),
+        }
+    }
+}
+
+/// Builds a route packet and a switch label from the input, and gives
+/// them to a `Driver` (which gives queries to its `Router`). The
+/// driver has a peer with a lookup in progress, so replies to it are
+/// handled too.
+pub fn route_packet(data: &[u8]) {
+    let mut reader = Reader { data: data };
+    let (mut driver, peer) = driver_with_lookup();
+
+    while !reader.data.is_empty() {
+        let label = path_from_u64(reader.u64());
+        // Either the peer (so replies match the pending query) or
+        // anyone.
+        let public_key = if reader.byte() % 2 == 0 { *peer.public_key() } else { reader.array() };
+        let protocol_version = reader.u64() as i64;
+        let transaction_id = reader.optional_bytes().unwrap_or_default();
+        let query = match reader.byte() {
+            0 => None,
+            1 => Some("fn".to_owned()),
+            2 => Some("gp".to_owned()),
+            3 => Some("pn".to_owned()),
+            _ => reader.optional_bytes().map(|query| String::from_utf8_lossy(&query).into_owned()),
+        };
+        let target_address = reader.optional_bytes();
+        let nb_nodes = reader.byte();
+        let nodes = if nb_nodes == 255 { None } else {
+            Some((0..nb_nodes).map(|_| NodeData {
+                public_key: reader.array(),
+                path: reader.array(),
+                version: reader.u64(),
+            }).collect())
+        };
+
+        let mut packet = RoutePacketBuilder::new(protocol_version, transaction_id).finalize();
+        packet.query = query;
+        packet.target_address = target_address;
+        packet.nodes = nodes;
+
+        driver.handle_input(Input::RoutePacket { label: label, public_key: public_key, packet: packet, now: 0 });
+        driver.handle_input(Input::Tick { now: reader.u64() });
+        while driver.poll_action().is_some() {}
+    }
+}
+
+/// Decodes the input as a route packet in a data packet, like the
+/// daemon does with packets of end-to-end sessions, and gives it to a
+/// `Driver` as if the peer sent it.
+pub fn decode_route_packet(data: &[u8]) {
+    // Data header: version 1, content type 256 (CJDHT).
+    let mut raw = vec![0x10, 0, 0x01, 0x00];
+    raw.extend(data);
+    let packet = match (DataPacket { raw: raw }).payload() {
+        Some(DataPayload::RoutePacket(packet)) => packet,
+        _ => return,
+    };
+    let (mut driver, peer) = driver_with_lookup();
+    driver.handle_input(Input::RoutePacket { label: *peer.path(), public_key: *peer.public_key(), packet: packet, now: 0 });
+    driver.handle_input(Input::Tick { now: 1 });
+    while driver.poll_action().is_some() {}
+}
+
+/// Driver with a peer, and a lookup in progress.
+fn driver_with_lookup() -> (Driver, Node) {
+    let peer = Node::new([2; PUBLIC_KEY_LENGTH], path_from_u64(0b1_011), 18);
+    let mut driver = Driver::new(Router::new(Address::from_public_key(&[1; PUBLIC_KEY_LENGTH])), DriverConfig::default());
+    driver.handle_input(Input::PeerUp { node: peer.clone() });
+    driver.handle_input(Input::Lookup { target: Address::from_public_key(&[3; PUBLIC_KEY_LENGTH]) });
+    (driver, peer)
+}
+
+/// Parses the input as addresses, paths, keys, nodes and bencoded
+/// values, and uses it as raw addresses and paths.
+pub fn parse(data: &[u8]) {
+    if let Ok(s) = str::from_utf8(data) {
+        let _ = Address::from_str(s);
+        let _ = Address::from_base32_public_key(s);
+        let _ = Node::from_str(s);
+        let _ = parse_path(s);
+        let _ = public_key_from_base32(s);
+        let _ = base32::decode(s);
+    }
+    let _ = Value::decode(data);
+
+    let mut reader = Reader { data: data };
+    let bytes: [u8; 16] = reader.array();
+    let address = Address::new(&bytes);
+    assert_eq!(address.bytes(), bytes);
+    let _ = address.to_string().parse::<Address>();
+    let other = Address::new(&reader.array());
+    let distance = address.distance(&other);
+    if distance.bucket_index() > 0 {
+        let _ = address.bucket_range(distance.bucket_index());
+    }
+
+    let path = reader.array();
+    let via = reader.array();
+    path_length(&path);
+    routes_through(&path, &via);
+    if let Some(spliced) = splice(&path, &via) {
+        assert!(routes_through(&spliced, &via));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the fuzz targets on pseudo-random inputs, so they are
+    /// exercised without a fuzzer.
+    #[test]
+    fn test_targets() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        for len in 0..500 {
+            let data: Vec<u8> = (0..len).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect();
+            route_packet(&data);
+            decode_route_packet(&data);
+            parse(&data);
+        }
+    }
+}
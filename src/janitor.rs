@@ -0,0 +1,242 @@
+//! Background maintenance of the routing table, run by the `Driver` at
+//! each tick when `DriverConfig::janitor` is set.
+//!
+//! Every round, the janitor looks up our own address (so nodes close to
+//! us learn about us), looks up random addresses in buckets that have
+//! few nodes, and pings nodes we have not heard from for a while (so
+//! dead ones get marked as unreachable and evicted first). It sends at
+//! most `max_queries` queries per round.
+//!
+//! Random addresses are derived from our address, so the same inputs
+//! always give the same tasks, and recordings replay identically.
+
+use std::collections::HashMap;
+
+use sha2::{Sha256, Digest};
+
+use node::{Address, Node, ADDRESS_BITS};
+use node_store::NodeStore;
+use event::NodeStoreEvent;
+use distance::Distance;
+
+/// Parameters of the `Janitor`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JanitorConfig {
+    /// Milliseconds between rounds.
+    pub interval: u64,
+    /// Maximum number of queries sent per round, including all the
+    /// steps of the lookups the janitor starts.
+    pub max_queries: usize,
+    /// Buckets with fewer nodes than this are refreshed.
+    pub min_bucket_size: usize,
+    /// Milliseconds between self-lookups, and between refreshes of a
+    /// same bucket.
+    pub refresh_interval: u64,
+    /// Milliseconds without hearing from a node before pinging it.
+    pub stale_after: u64,
+}
+
+impl Default for JanitorConfig {
+    fn default() -> JanitorConfig {
+        JanitorConfig {
+            interval: 10000,
+            max_queries: 8,
+            min_bucket_size: 4,
+            refresh_interval: 300000,
+            stale_after: 600000,
+        }
+    }
+}
+
+/// Something the janitor wants the `Driver` to do.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Task {
+    Lookup { target: Address },
+    Ping { node: Node },
+}
+
+pub struct Janitor {
+    config: JanitorConfig,
+    my_address: Address,
+    next_round: u64,
+    next_self_lookup: u64,
+    /// When each bucket may be refreshed again, indexed by bucket.
+    next_refresh: Vec<u64>,
+    /// When each node was last heard from, or pinged by the janitor.
+    last_contact: HashMap<Address, u64>,
+    /// Number of random addresses generated so far.
+    nb_random: u64,
+    /// Queries left in the current round, for the next steps of the
+    /// janitor's lookups.
+    remaining_queries: usize,
+}
+
+impl Janitor {
+    pub fn new(config: JanitorConfig, my_address: Address) -> Janitor {
+        Janitor {
+            config: config,
+            my_address: my_address,
+            next_round: 0,
+            next_self_lookup: 0,
+            next_refresh: vec![0; ADDRESS_BITS+1],
+            last_contact: HashMap::new(),
+            nb_random: 0,
+            remaining_queries: 0,
+        }
+    }
+
+    pub fn config(&self) -> &JanitorConfig {
+        &self.config
+    }
+
+    /// Records that the node sent us a packet.
+    pub fn heard_from(&mut self, address: Address, now: u64) {
+        self.last_contact.insert(address, now);
+    }
+
+    /// Returns the tasks of this round, if it is time for one.
+    /// `lookup_cost` is the number of queries of the first step of a
+    /// lookup; each step of a lookup then takes its queries with
+    /// `take_queries`.
+    pub fn tick<C>(&mut self, now: u64, node_store: &NodeStore<C>, lookup_cost: usize) -> Vec<Task>
+            where C: ?Sized + FnMut(&NodeStoreEvent) {
+        if now < self.next_round {
+            return Vec::new()
+        }
+        self.next_round = now.saturating_add(self.config.interval);
+        self.remaining_queries = self.config.max_queries;
+        if node_store.is_empty() {
+            // Nobody to query; we need peers first.
+            return Vec::new()
+        }
+        let mut tasks = Vec::new();
+        let mut budget = self.config.max_queries;
+        let lookup_cost = lookup_cost.max(1);
+
+        if now >= self.next_self_lookup && budget >= lookup_cost {
+            self.next_self_lookup = now.saturating_add(self.config.refresh_interval);
+            budget -= lookup_cost;
+            tasks.push(Task::Lookup { target: self.my_address.clone() });
+        }
+
+        // Buckets closer than the closest node are (almost certainly)
+        // empty in the whole network, so only refresh farther ones,
+        // starting from the farthest, which cover more addresses.
+        let bucket_fill = node_store.stats().bucket_fill;
+        let closest_bucket = bucket_fill.iter().position(|fill| *fill > 0).unwrap_or(ADDRESS_BITS);
+        for bucket in (closest_bucket.max(1)..ADDRESS_BITS+1).rev() {
+            if budget < lookup_cost {
+                break;
+            }
+            if bucket_fill[bucket] >= self.config.min_bucket_size || now < self.next_refresh[bucket] {
+                continue;
+            }
+            self.next_refresh[bucket] = now.saturating_add(self.config.refresh_interval);
+            budget -= lookup_cost;
+            let target = self.random_address(bucket);
+            tasks.push(Task::Lookup { target: target });
+        }
+
+        // Nodes we never heard from start being tracked now.
+        let mut last_contact = HashMap::new();
+        let mut stale = Vec::new();
+        for (address, node) in node_store.nodes() {
+            let contact = self.last_contact.get(address).cloned().unwrap_or(now);
+            last_contact.insert(address.clone(), contact);
+            if now.saturating_sub(contact) >= self.config.stale_after {
+                stale.push((contact, address.clone(), node.clone()));
+            }
+        }
+        self.last_contact = last_contact; // Forget evicted nodes
+        // Least recently heard from first; `nodes()` is sorted by
+        // address, and the sort is stable.
+        stale.sort_by_key(|&(contact, _, _)| contact);
+        for (_, address, node) in stale.into_iter().take(budget) {
+            self.last_contact.insert(address, now);
+            self.remaining_queries -= 1;
+            tasks.push(Task::Ping { node: node });
+        }
+        tasks
+    }
+
+    /// Takes up to `wanted` queries from the budget of the current
+    /// round, for a step of one of the janitor's lookups. Returns how
+    /// many can be sent.
+    pub fn take_queries(&mut self, wanted: usize) -> usize {
+        let taken = wanted.min(self.remaining_queries);
+        self.remaining_queries -= taken;
+        taken
+    }
+
+    /// Pseudo-random address in this bucket (which must not be 0).
+    fn random_address(&mut self, bucket: usize) -> Address {
+        let mut hasher = Sha256::new();
+        hasher.update(self.my_address.bytes());
+        hasher.update(self.nb_random.to_be_bytes());
+        self.nb_random += 1;
+        let hash = hasher.finalize();
+        let (min, max) = Distance::bucket_bounds(bucket);
+        let (min, max) = (min.bytes(), max.bytes());
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (hash[i] & max[i]) | min[i];
+        }
+        self.my_address.at_distance(&Distance::from_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use label::path_from_u64;
+
+    #[test]
+    fn test_random_address() {
+        let mut janitor = Janitor::new(JanitorConfig::default(), Address::new(&[0xfc; 16]));
+        for bucket in &[1, 60, 64, 65, ADDRESS_BITS] {
+            let address = janitor.random_address(*bucket);
+            assert_eq!(Address::new(&[0xfc; 16]).distance(&address).bucket_index(), *bucket);
+        }
+    }
+
+    #[test]
+    fn test_tick() {
+        let my_address = Address::new(&[0xfc; 16]);
+        let config = JanitorConfig { max_queries: 9, ..JanitorConfig::default() };
+        let stale_after = config.stale_after;
+        let mut janitor = Janitor::new(config, my_address.clone());
+        let mut node_store = NodeStore::new(my_address.clone());
+        assert_eq!(janitor.tick(0, &node_store, 3), vec![]);
+
+        let address = my_address.at_distance(&Distance::bucket_bounds(ADDRESS_BITS-2).0);
+        let node = Node::new([1; 32], path_from_u64(0b1_011), 18);
+        node_store.update(address.clone(), node.clone());
+        let bucket_of = |task: &Task| match *task {
+            Task::Lookup { ref target } => my_address.distance(target).bucket_index(),
+            _ => panic!("Expected a lookup, got {:?}", task),
+        };
+        // Self-lookup, and the two farthest buckets.
+        let tasks = janitor.tick(10000, &node_store, 3);
+        assert_eq!(tasks[0], Task::Lookup { target: my_address.clone() });
+        assert_eq!(tasks[1..].iter().map(&bucket_of).collect::<Vec<_>>(), vec![ADDRESS_BITS, ADDRESS_BITS-1]);
+        // Their steps share the budget of the round.
+        assert_eq!(janitor.take_queries(3), 3);
+        assert_eq!(janitor.take_queries(3), 3);
+        assert_eq!(janitor.take_queries(5), 3);
+        assert_eq!(janitor.take_queries(1), 0);
+        // Not a round yet.
+        assert_eq!(janitor.tick(15000, &node_store, 3), vec![]);
+        // The bucket of the node; closer ones are not refreshed.
+        let tasks = janitor.tick(20000, &node_store, 4);
+        assert_eq!(tasks.iter().map(&bucket_of).collect::<Vec<_>>(), vec![ADDRESS_BITS-2]);
+        assert_eq!(janitor.tick(30000, &node_store, 4), vec![]);
+
+        // The node is pinged once it is stale (lookups are over budget),
+        // then not until it is stale again.
+        janitor.heard_from(address, 30000);
+        let tasks = janitor.tick(30000 + stale_after, &node_store, 10);
+        assert_eq!(tasks, vec![Task::Ping { node: node.clone() }]);
+        assert_eq!(janitor.take_queries(10), 8);
+        assert_eq!(janitor.tick(40000 + stale_after, &node_store, 10), vec![]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Generation of keypairs whose address is in fc00::/8, like cjdns'
+//! `makekeys`, optionally with a chosen address prefix. Enabled by the
+//! `keygen` feature.
+//!
+//! Public keys are X25519 public keys; most of them give an address
+//! outside fc00::/8, so keys are generated until one fits (about 256
+//! tries, and 16 times more for each additional hexadecimal digit of
+//! prefix).
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use rand_core::{OsRng, RngCore};
+use x25519_dalek::{StaticSecret, PublicKey};
+
+use node::{Address, PUBLIC_KEY_LENGTH, public_key_to_base32, secret_key_from_hex};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyPair {
+    pub secret_key: [u8; 32],
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+}
+
+impl KeyPair {
+    /// Computes the public key of this secret key.
+    pub fn from_secret_key(secret_key: [u8; 32]) -> KeyPair {
+        let public_key = PublicKey::from(&StaticSecret::from(secret_key));
+        KeyPair { secret_key: secret_key, public_key: *public_key.as_bytes() }
+    }
+
+    /// Parses a secret key written as 64 hexadecimal digits (like in
+    /// `fcp-routerd`'s configuration), and computes its public key.
+    pub fn from_secret_key_hex(s: &str) -> Option<KeyPair> {
+        secret_key_from_hex(s).map(KeyPair::from_secret_key)
+    }
+
+    /// Secret key, as 64 hexadecimal digits.
+    pub fn secret_key_hex(&self) -> String {
+        self.secret_key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Public key, in base32 with the `.k` suffix.
+    pub fn public_key_base32(&self) -> String {
+        public_key_to_base32(&self.public_key)
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public_key)
+    }
+}
+
+/// Error returned when parsing an `AddressPrefix`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PrefixError {
+    InvalidDigit,
+    /// Addresses always start with `fc`.
+    NotInFc00,
+    TooLong,
+}
+
+impl fmt::Display for PrefixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let message = match *self {
+            PrefixError::InvalidDigit => "invalid prefix, expected hexadecimal digits",
+            PrefixError::NotInFc00 => "invalid prefix, addresses start with fc",
+            PrefixError::TooLong => "invalid prefix, longer than an address",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl Error for PrefixError {
+}
+
+/// First hexadecimal digits of an address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AddressPrefix {
+    nibbles: Vec<u8>,
+}
+
+impl AddressPrefix {
+    /// Prefix of all valid addresses: `fc`.
+    pub fn fc00() -> AddressPrefix {
+        AddressPrefix { nibbles: vec![0xf, 0xc] }
+    }
+
+    /// Number of hexadecimal digits.
+    pub fn len(&self) -> usize {
+        self.nibbles.len()
+    }
+
+    /// Whether the prefix matches all addresses.
+    pub fn is_empty(&self) -> bool {
+        self.nibbles.is_empty()
+    }
+
+    pub fn matches(&self, address: &Address) -> bool {
+        let bytes = address.bytes();
+        self.nibbles.iter().enumerate().all(|(i, nibble)| {
+            let byte = bytes[i / 2];
+            let actual = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            actual == *nibble
+        })
+    }
+}
+
+/// Parses digits of an address written in full, with or without
+/// colons (eg. `fc12:34` or `fc1234`). Groups have to be written with
+/// four digits, as there is no way to tell where the address would
+/// be shortened.
+impl FromStr for AddressPrefix {
+    type Err = PrefixError;
+
+    fn from_str(s: &str) -> Result<AddressPrefix, PrefixError> {
+        let nibbles = s.chars().filter(|c| *c != ':')
+                .map(|c| c.to_digit(16).map(|digit| digit as u8).ok_or(PrefixError::InvalidDigit))
+                .collect::<Result<Vec<u8>, PrefixError>>()?;
+        if nibbles.len() > 32 {
+            return Err(PrefixError::TooLong)
+        }
+        let prefix = AddressPrefix { nibbles: nibbles };
+        if !AddressPrefix::fc00().nibbles.iter().zip(prefix.nibbles.iter()).all(|(a, b)| a == b) {
+            return Err(PrefixError::NotInFc00)
+        }
+        Ok(prefix)
+    }
+}
+
+/// Generates a keypair with a valid address.
+pub fn generate() -> KeyPair {
+    search(&AddressPrefix::fc00(), 1)
+}
+
+/// Generates keypairs on `nb_threads` threads until the address of one
+/// of them starts with the prefix (and with `fc`, even if the prefix
+/// does not).
+pub fn search(prefix: &AddressPrefix, nb_threads: usize) -> KeyPair {
+    let found = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let threads: Vec<thread::JoinHandle<()>> = (0..nb_threads.max(1)).map(|_| {
+        let prefix = prefix.clone();
+        let found = found.clone();
+        let sender = sender.clone();
+        let mut secret_key = [0u8; 32];
+        OsRng.fill_bytes(&mut secret_key);
+        thread::spawn(move || {
+            if let Some(keypair) = search_from(secret_key, &prefix, &found) {
+                found.store(true, Ordering::Relaxed);
+                let _ = sender.send(keypair);
+            }
+        })
+    }).collect();
+    // Only the threads' senders are left, so `recv` fails instead of
+    // blocking if they all stop without a key (eg. if one panics).
+    drop(sender);
+    let keypair = receiver.recv().expect("All search threads stopped.");
+    for thread in threads {
+        let _ = thread.join();
+    }
+    keypair
+}
+
+/// Tries successive secret keys until one matches, or another thread
+/// found one.
+fn search_from(mut secret_key: [u8; 32], prefix: &AddressPrefix, found: &AtomicBool) -> Option<KeyPair> {
+    let fc00 = AddressPrefix::fc00();
+    while !found.load(Ordering::Relaxed) {
+        let keypair = KeyPair::from_secret_key(secret_key);
+        let address = keypair.address();
+        if fc00.matches(&address) && prefix.matches(&address) {
+            return Some(keypair)
+        }
+        // Increment bytes 8 to 15 as a counter: the lowest and highest
+        // bits of the key are overwritten by X25519's clamping.
+        for byte in secret_key[8..16].iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_keypair() {
+        let secret_key = "ac3e53b518e68449692b0b2f2926ef2fdc1eac5b9dbd10a48114263b8c8ed12e";
+        let keypair = KeyPair::from_secret_key_hex(secret_key).unwrap();
+        assert_eq!(keypair.public_key_base32(), "2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k");
+        assert_eq!(keypair.address().to_string(), "fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9");
+        assert_eq!(keypair.secret_key_hex(), secret_key);
+        assert_eq!(KeyPair::from_secret_key_hex("ac3e"), None);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let address = Address::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap();
+        assert!(AddressPrefix::from_str("fc8f:a1").unwrap().matches(&address));
+        assert!(AddressPrefix::from_str("fc8fa188").unwrap().matches(&address));
+        assert!(!AddressPrefix::from_str("fc8f:a2").unwrap().matches(&address));
+        assert_eq!(AddressPrefix::from_str("fd"), Err(PrefixError::NotInFc00));
+        assert_eq!(AddressPrefix::from_str("fcg"), Err(PrefixError::InvalidDigit));
+    }
+
+    #[test]
+    fn test_search() {
+        assert!(generate().address().is_valid());
+        let prefix = AddressPrefix::from_str("fc1").unwrap();
+        let keypair = search(&prefix, 2);
+        assert!(prefix.matches(&keypair.address()));
+        assert_eq!(KeyPair::from_secret_key(keypair.secret_key), keypair);
+    }
+}
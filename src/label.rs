@@ -0,0 +1,126 @@
+//! Operations on paths (aka. labels), which are read by switches
+//! starting from their least significant bits.
+//! See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#the-switch
+
+use fcp_switching::encoding_scheme::EncodingSchemeForm;
+
+use node::Path;
+
+/// Path to ourselves.
+pub const SELF_PATH: Path = [0, 0, 0, 0, 0, 0, 0, 1];
+
+/// Reads a path as an integer.
+pub fn path_to_u64(path: &Path) -> u64 {
+    let mut label = 0u64;
+    for byte in path.iter() {
+        label = (label << 8) | (*byte as u64);
+    }
+    label
+}
+
+/// Writes an integer as a path.
+pub fn path_from_u64(label: u64) -> Path {
+    let mut path = [0u8; 8];
+    for (i, byte) in path.iter_mut().enumerate() {
+        *byte = (label >> (8*(7-i))) as u8;
+    }
+    path
+}
+
+/// Returns the path to a node, given a path from us to a router
+/// (`via_here`), and a path from this router to the node (`go_here`).
+/// Both paths have to use compatible encoding forms.
+/// Returns `None` if the resulting path would be too long.
+/// See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/switch/LabelSplicer.h
+pub fn splice(go_here: &Path, via_here: &Path) -> Option<Path> {
+    let go_here = path_to_u64(go_here);
+    let via_here = path_to_u64(via_here);
+    if go_here == 0 || via_here == 0 {
+        return None
+    }
+    let log2_go_here = 63 - go_here.leading_zeros();
+    let log2_via_here = 63 - via_here.leading_zeros();
+    if log2_go_here + log2_via_here > 59 {
+        return None
+    }
+    Some(path_from_u64(((go_here ^ 1) << log2_via_here) ^ via_here))
+}
+
+/// Returns whether `prefix` is a path to one of the routers `path`
+/// goes through.
+pub fn routes_through(path: &Path, prefix: &Path) -> bool {
+    let path = path_to_u64(path);
+    let prefix = path_to_u64(prefix);
+    if prefix == 0 || path < prefix {
+        return false
+    }
+    let mask = (1u64 << (63 - prefix.leading_zeros())) - 1;
+    path & mask == prefix & mask
+}
+
+/// Returns the paths to each router a path goes through, from the
+/// first hop to the destination (the path itself), given the encoding
+/// forms of the switches along the path. If there are fewer schemes
+/// than hops, the last one is used for the remaining hops.
+/// Returns `None` if the path cannot be read with these schemes.
+pub fn hop_prefixes(path: &Path, schemes: &[Vec<EncodingSchemeForm>]) -> Option<Vec<Path>> {
+    let label = path_to_u64(path);
+    if label == 0 {
+        return None
+    }
+    let mut prefixes = Vec::new();
+    let mut remaining = label;
+    let mut consumed = 0;
+    while remaining != 1 {
+        let forms = schemes.get(prefixes.len()).or_else(|| schemes.last())?;
+        let form = forms.iter().find(|form| {
+            let mask = (1u64 << form.prefix_length) - 1;
+            remaining & mask == form.prefix as u64 & mask
+        })?;
+        let width = form.prefix_length as u32 + form.bit_count as u32;
+        if width == 0 || width >= 64 || remaining >> width == 0 {
+            return None
+        }
+        remaining >>= width;
+        consumed += width;
+        prefixes.push(path_from_u64((label & ((1 << consumed) - 1)) | (1 << consumed)));
+    }
+    Some(prefixes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splice() {
+        // Go through interface 0b011 then 0b101 (3-bit encoding).
+        let via_here = path_from_u64(0b1_011);
+        let go_here = path_from_u64(0b1_101);
+        let spliced = splice(&go_here, &via_here).unwrap();
+        assert_eq!(path_to_u64(&spliced), 0b1_101_011);
+        assert!(routes_through(&spliced, &via_here));
+        assert!(!routes_through(&spliced, &go_here));
+        assert_eq!(splice(&SELF_PATH, &via_here), Some(via_here));
+        assert_eq!(splice(&path_from_u64(1 << 40), &path_from_u64(1 << 30)), None);
+    }
+
+    #[test]
+    fn test_hop_prefixes() {
+        let fixed = vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }];
+        let prefixes = hop_prefixes(&path_from_u64(0b1_101_011), &[fixed.clone()]).unwrap();
+        assert_eq!(prefixes, vec![path_from_u64(0b1_011), path_from_u64(0b1_101_011)]);
+        assert_eq!(hop_prefixes(&SELF_PATH, &[fixed.clone()]), Some(vec![]));
+        // Not enough bits left for the second director.
+        assert_eq!(hop_prefixes(&path_from_u64(0b11_011), &[fixed.clone()]), None);
+
+        // 4-bit directors prefixed with 1, or 8-bit directors prefixed with 00.
+        let variable = vec![
+            EncodingSchemeForm { prefix: 0b1, bit_count: 3, prefix_length: 1 },
+            EncodingSchemeForm { prefix: 0b00, bit_count: 6, prefix_length: 2 },
+        ];
+        let path = path_from_u64(0b1_101_01010100_0111);
+        let prefixes = hop_prefixes(&path, &[variable.clone(), variable, fixed]).unwrap();
+        assert_eq!(prefixes, vec![path_from_u64(0b1_0111), path_from_u64(0b1_01010100_0111), path]);
+    }
+}
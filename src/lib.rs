@@ -1,9 +1,58 @@
 extern crate simple_kbuckets;
 extern crate fcp_switching;
+extern crate sha2;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+#[cfg(any(feature = "keygen", feature = "decoder"))]
+extern crate x25519_dalek;
+#[cfg(feature = "keygen")]
+extern crate rand_core;
+#[cfg(feature = "decoder")]
+extern crate crypto_box;
+#[cfg(feature = "decoder")]
+extern crate crypto_secretbox;
+#[cfg(feature = "announcements")]
+extern crate ed25519_dalek;
+#[cfg(feature = "announcements")]
+extern crate curve25519_dalek;
 
+pub mod base32;
+pub mod label;
 pub mod node;
+pub mod distance;
+pub mod event;
 pub mod node_store;
+pub mod stats;
+pub mod metrics;
 pub mod router;
+pub mod driver;
+pub mod session;
+pub mod bencode;
+pub mod admin;
+pub mod simulator;
+pub mod crawler;
+pub mod traceroute;
+pub mod recording;
+pub mod supernode;
+pub mod janitor;
+#[cfg(feature = "async")]
+pub mod async_lookup;
+#[cfg(feature = "serde")]
+pub mod serialization;
+#[cfg(feature = "keygen")]
+pub mod keygen;
+#[cfg(feature = "decoder")]
+pub mod decoder;
+#[cfg(feature = "announcements")]
+pub mod announcement;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
 
 
 #[cfg(test)]
@@ -0,0 +1,247 @@
+//! Counters of the router's activity, rendered in the Prometheus text
+//! exposition format so nodes can be scraped by existing monitoring.
+//! See https://prometheus.io/docs/instrumenting/exposition_formats/
+//!
+//! `Router` owns a `Metrics`; the `Driver` records lookups and
+//! timeouts in it. Gauges about the routing table are computed from
+//! `NodeStoreStats` when rendering.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use driver::LookupError;
+use node_store::Reachability;
+use stats::NodeStoreStats;
+
+/// Queries counted separately; others are counted as invalid.
+const QUERY_TYPES: [&str; 5] = ["fn", "gp", "pn", "ann", "gr"];
+
+/// Upper bounds (in milliseconds) of the buckets of the lookup
+/// duration histogram.
+const LOOKUP_DURATION_BUCKETS: [u64; 9] = [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Histogram with fixed buckets, as Prometheus expects them
+/// (each bucket counts observations lower or equal to its bound).
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<u64>,
+    sum: u64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len()],
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: u64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all observations.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// Queries we answered, by type.
+    queries_answered: BTreeMap<&'static str, u64>,
+    /// Queries we could not answer (unknown type or bad arguments).
+    invalid_queries: u64,
+    /// Our queries that got no reply in time, by type.
+    query_timeouts: BTreeMap<&'static str, u64>,
+    lookups_found: u64,
+    lookups_not_found: u64,
+    lookups_no_nodes: u64,
+    /// Duration of lookups, whatever their result.
+    lookup_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            queries_answered: QUERY_TYPES.iter().map(|query| (*query, 0)).collect(),
+            invalid_queries: 0,
+            query_timeouts: QUERY_TYPES.iter().map(|query| (*query, 0)).collect(),
+            lookups_found: 0,
+            lookups_not_found: 0,
+            lookups_no_nodes: 0,
+            lookup_duration: Histogram::new(&LOOKUP_DURATION_BUCKETS),
+        }
+    }
+
+    /// Counts a query we answered. Queries of other types than `fn`,
+    /// `gp`, `pn`, `ann` and `gr` are counted as invalid.
+    pub fn record_query_answered(&mut self, query: &str) {
+        match self.queries_answered.iter_mut().find(|&(name, _)| *name == query) {
+            Some((_, count)) => *count += 1,
+            None => self.invalid_queries += 1,
+        }
+    }
+
+    /// Counts a query we could not answer.
+    pub fn record_invalid_query(&mut self) {
+        self.invalid_queries += 1;
+    }
+
+    /// Counts one of our queries that got no reply in time.
+    pub fn record_query_timeout(&mut self, query: &str) {
+        if let Some((_, count)) = self.query_timeouts.iter_mut().find(|&(name, _)| *name == query) {
+            *count += 1;
+        }
+    }
+
+    /// Counts a completed lookup, which took `duration` milliseconds.
+    pub fn record_lookup<T>(&mut self, result: &Result<T, LookupError>, duration: u64) {
+        match *result {
+            Ok(_) => self.lookups_found += 1,
+            Err(LookupError::NotFound) => self.lookups_not_found += 1,
+            Err(LookupError::NoNodes) => self.lookups_no_nodes += 1,
+        }
+        self.lookup_duration.observe(duration);
+    }
+
+    /// Number of queries of this type we answered.
+    pub fn queries_answered(&self, query: &str) -> u64 {
+        self.queries_answered.get(query).cloned().unwrap_or(0)
+    }
+
+    pub fn invalid_queries(&self) -> u64 {
+        self.invalid_queries
+    }
+
+    /// Number of our queries of this type that timed out.
+    pub fn query_timeouts(&self, query: &str) -> u64 {
+        self.query_timeouts.get(query).cloned().unwrap_or(0)
+    }
+
+    /// Number of lookups that found their target.
+    pub fn lookups_found(&self) -> u64 {
+        self.lookups_found
+    }
+
+    /// Number of lookups that failed, for any reason.
+    pub fn lookups_failed(&self) -> u64 {
+        self.lookups_not_found + self.lookups_no_nodes
+    }
+
+    pub fn lookup_duration(&self) -> &Histogram {
+        &self.lookup_duration
+    }
+
+    /// Renders the counters, and gauges computed from the routing
+    /// table's stats and number of peers, in the Prometheus text format.
+    pub fn render(&self, stats: &NodeStoreStats, nb_peers: usize) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "fcp_routing_queries_answered_total", "counter", "Queries answered, by type.");
+        for (query, count) in &self.queries_answered {
+            writeln!(out, "fcp_routing_queries_answered_total{{query=\"{}\"}} {}", query, count).unwrap();
+        }
+        header(&mut out, "fcp_routing_invalid_queries_total", "counter", "Queries of unknown type or with invalid arguments.");
+        writeln!(out, "fcp_routing_invalid_queries_total {}", self.invalid_queries).unwrap();
+        header(&mut out, "fcp_routing_query_timeouts_total", "counter", "Queries sent that got no reply in time, by type.");
+        for (query, count) in &self.query_timeouts {
+            writeln!(out, "fcp_routing_query_timeouts_total{{query=\"{}\"}} {}", query, count).unwrap();
+        }
+
+        header(&mut out, "fcp_routing_lookups_total", "counter", "Lookups completed, by result.");
+        writeln!(out, "fcp_routing_lookups_total{{result=\"found\"}} {}", self.lookups_found).unwrap();
+        writeln!(out, "fcp_routing_lookups_total{{result=\"not_found\"}} {}", self.lookups_not_found).unwrap();
+        writeln!(out, "fcp_routing_lookups_total{{result=\"no_nodes\"}} {}", self.lookups_no_nodes).unwrap();
+        header(&mut out, "fcp_routing_lookup_duration_milliseconds", "histogram", "Duration of lookups.");
+        self.lookup_duration.render(&mut out, "fcp_routing_lookup_duration_milliseconds");
+
+        header(&mut out, "fcp_routing_nodes", "gauge", "Nodes in the routing table.");
+        writeln!(out, "fcp_routing_nodes {}", stats.total_nodes).unwrap();
+        header(&mut out, "fcp_routing_nodes_by_reachability", "gauge", "Nodes in the routing table, by reachability.");
+        for &(reachability, name) in &[(Reachability::Unconfirmed, "unconfirmed"), (Reachability::Reachable, "reachable"), (Reachability::Unreachable, "unreachable")] {
+            let count = stats.reachability.get(&reachability).cloned().unwrap_or(0);
+            writeln!(out, "fcp_routing_nodes_by_reachability{{reachability=\"{}\"}} {}", name, count).unwrap();
+        }
+        header(&mut out, "fcp_routing_nonempty_buckets", "gauge", "Buckets of the routing table with at least one node.");
+        writeln!(out, "fcp_routing_nonempty_buckets {}", stats.nonempty_buckets()).unwrap();
+        header(&mut out, "fcp_routing_peers", "gauge", "Nodes we have a direct link with.");
+        writeln!(out, "fcp_routing_peers {}", nb_peers).unwrap();
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, type_: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, type_).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::Address;
+    use node_store::NodeStore;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::new();
+        metrics.record_query_answered("fn");
+        metrics.record_query_answered("fn");
+        metrics.record_query_answered("xx");
+        metrics.record_query_timeout("pn");
+        metrics.record_lookup::<()>(&Ok(()), 40);
+        metrics.record_lookup::<()>(&Err(LookupError::NotFound), 5000);
+
+        let stats = NodeStore::new(Address::from_public_key(&[1; 32])).stats();
+        let rendered = metrics.render(&stats, 0);
+        let lines: Vec<&str> = rendered.lines().collect();
+        for line in &[
+                "# TYPE fcp_routing_queries_answered_total counter",
+                "fcp_routing_queries_answered_total{query=\"fn\"} 2",
+                "fcp_routing_queries_answered_total{query=\"gp\"} 0",
+                "fcp_routing_invalid_queries_total 1",
+                "fcp_routing_query_timeouts_total{query=\"pn\"} 1",
+                "fcp_routing_lookups_total{result=\"found\"} 1",
+                "fcp_routing_lookups_total{result=\"not_found\"} 1",
+                "fcp_routing_lookup_duration_milliseconds_bucket{le=\"10\"} 0",
+                "fcp_routing_lookup_duration_milliseconds_bucket{le=\"50\"} 1",
+                "fcp_routing_lookup_duration_milliseconds_bucket{le=\"5000\"} 2",
+                "fcp_routing_lookup_duration_milliseconds_bucket{le=\"+Inf\"} 2",
+                "fcp_routing_lookup_duration_milliseconds_sum 5040",
+                "fcp_routing_nodes 0",
+                ] {
+            assert!(lines.contains(line), "Missing line {:?} in:\n{}", line, rendered);
+        }
+    }
+}
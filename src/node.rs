@@ -1,18 +1,131 @@
 use std::fmt;
+use std::error::Error;
 use std::net::Ipv6Addr;
+use std::str::FromStr;
 use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 
 use simple_kbuckets::Key;
+use sha2::{Sha512, Digest};
+
+use base32;
+use label::path_to_u64;
 
 pub const PUBLIC_KEY_LENGTH: usize = 32;
 
 pub type Path = [u8; 8];
 
+/// Returns the number of bits of a path, not counting the leading
+/// 1 bit that marks its end.
+pub fn path_length(path: &Path) -> u32 {
+    let label = path_to_u64(path);
+    if label == 0 {
+        0
+    }
+    else {
+        63 - label.leading_zeros()
+    }
+}
+
+/// Error returned when parsing addresses, paths, keys, or nodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    InvalidAddress,
+    InvalidPath,
+    InvalidPublicKey,
+    InvalidVersion,
+    InvalidNode,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let message = match *self {
+            ParseError::InvalidAddress => "invalid IPv6 address",
+            ParseError::InvalidPath => "invalid path, expected a label like 0000.0000.0000.0013",
+            ParseError::InvalidPublicKey => "invalid base32 public key",
+            ParseError::InvalidVersion => "invalid protocol version",
+            ParseError::InvalidNode => "invalid node, expected v<version>.<path>.<public key>.k",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl Error for ParseError {
+}
+
+/// Wrapper of a `Path` that displays it the way cjdns does, as four
+/// groups of four hexadecimal digits (eg. `0000.0000.0000.0013`).
+pub struct DisplayPath<'a>(pub &'a Path);
+
+impl<'a> fmt::Display for DisplayPath<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let p = self.0;
+        write!(f, "{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}",
+               p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7])
+    }
+}
+
+/// Parses a path written the way cjdns does, as four groups of four
+/// hexadecimal digits (eg. `0000.0000.0000.0013`).
+pub fn parse_path(s: &str) -> Result<Path, ParseError> {
+    let groups: Vec<&str> = s.split('.').collect();
+    if groups.len() != 4 {
+        return Err(ParseError::InvalidPath)
+    }
+    let mut path = [0u8; 8];
+    for (i, group) in groups.iter().enumerate() {
+        if group.len() != 4 || !group.bytes().all(|c| (c as char).is_digit(16)) {
+            return Err(ParseError::InvalidPath)
+        }
+        let value = u16::from_str_radix(group, 16).map_err(|_| ParseError::InvalidPath)?;
+        path[2*i] = (value >> 8) as u8;
+        path[2*i+1] = (value & 0xff) as u8;
+    }
+    Ok(path)
+}
+
+/// Encodes a public key in cjdns' base32, with the `.k` suffix.
+pub fn public_key_to_base32(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> String {
+    format!("{}.k", base32::encode(public_key))
+}
+
+/// Decodes a public key encoded in cjdns' base32. The `.k` suffix
+/// is optional.
+pub fn public_key_from_base32(s: &str) -> Result<[u8; PUBLIC_KEY_LENGTH], ParseError> {
+    let s = if s.ends_with(".k") { &s[..s.len()-2] } else { s };
+    let bytes = base32::decode(s).map_err(|_| ParseError::InvalidPublicKey)?;
+    if bytes.len() != PUBLIC_KEY_LENGTH {
+        return Err(ParseError::InvalidPublicKey)
+    }
+    let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+    public_key.copy_from_slice(&bytes);
+    Ok(public_key)
+}
+
+/// Decodes an even number of hexadecimal digits.
+pub fn hex_to_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).ok()).collect()
+}
+
+/// Decodes a secret key written as 64 hexadecimal digits (like in
+/// `fcp-routerd`'s configuration).
+pub fn secret_key_from_hex(s: &str) -> Option<[u8; 32]> {
+    let bytes = hex_to_bytes(s)?;
+    if bytes.len() != 32 {
+        return None
+    }
+    let mut secret_key = [0u8; 32];
+    secret_key.copy_from_slice(&bytes);
+    Some(secret_key)
+}
+
 /// Rotates an IPv6 address 64 bits, which is a required preprocessing
 /// for computing the XOR metric.
 /// See https://github.com/cjdelisle/cjdns/blob/cjdns-v18/doc/Whitepaper.md#the-router
-fn rotate_64(i: &[u8; 16]) -> [u8; 16] {
+pub fn rotate_64(i: &[u8; 16]) -> [u8; 16] {
     [
         i[ 8], i[ 9], i[10], i[11], i[12], i[13], i[14], i[15],
         i[ 0], i[ 1], i[ 2], i[ 3], i[ 4], i[ 5], i[ 6], i[ 7],
@@ -34,6 +147,27 @@ impl Address {
     pub fn bytes(&self) -> [u8; 16] {
         rotate_64(&self.bytes)
     }
+
+    /// Computes the address of a node from its public key: the first
+    /// 16 bytes of the double SHA-512 of the key.
+    pub fn from_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Address {
+        let hash = Sha512::digest(Sha512::digest(public_key));
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hash[0..16]);
+        Address::new(&bytes)
+    }
+
+    /// Computes the address of a node from its public key, encoded in
+    /// base32 (with or without the `.k` suffix).
+    pub fn from_base32_public_key(public_key: &str) -> Result<Address, ParseError> {
+        public_key_from_base32(public_key).map(|pk| Address::from_public_key(&pk))
+    }
+
+    /// Returns whether this address is in fc00::/8, which is required
+    /// for addresses derived from public keys to be usable.
+    pub fn is_valid(&self) -> bool {
+        self.bytes()[0] == 0xfc
+    }
 }
 
 impl fmt::Debug for Address {
@@ -43,6 +177,20 @@ impl fmt::Debug for Address {
     }
 }
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", Ipv6Addr::from(self))
+    }
+}
+
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Address, ParseError> {
+        Ipv6Addr::from_str(s).map(Address::from).map_err(|_| ParseError::InvalidAddress)
+    }
+}
+
 impl<'a> From<&'a Ipv6Addr> for Address {
     fn from(ipv6addr: &Ipv6Addr) -> Address {
         Address::new(&ipv6addr.octets())
@@ -80,8 +228,11 @@ impl Key for Address {
 
 /// Data of the hash table
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Node {
+    #[cfg_attr(feature = "serde", serde(with = "::serialization::public_key"))]
     public_key: [u8; PUBLIC_KEY_LENGTH],
+    #[cfg_attr(feature = "serde", serde(with = "::serialization::path"))]
     path: Path,
     version: u64,
 }
@@ -103,6 +254,46 @@ impl Node {
     pub fn version(&self) -> u64 {
         self.version
     }
+
+    /// Creates a node from its public key encoded in base32 (with or
+    /// without the `.k` suffix).
+    pub fn from_base32_public_key(public_key: &str, path: Path, version: u64) -> Result<Node, ParseError> {
+        public_key_from_base32(public_key).map(|pk| Node::new(pk, path, version))
+    }
+
+    /// Returns the public key encoded in base32, with the `.k` suffix.
+    pub fn public_key_base32(&self) -> String {
+        public_key_to_base32(&self.public_key)
+    }
+
+    /// Returns the address derived from the public key of the node.
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public_key)
+    }
+}
+
+/// Displays a node the way cjdns does:
+/// `v<version>.<path>.<public key>.k`
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "v{}.{}.{}", self.version, DisplayPath(&self.path), public_key_to_base32(&self.public_key))
+    }
+}
+
+impl FromStr for Node {
+    type Err = ParseError;
+
+    /// Parses a node written as `v<version>.<path>.<public key>.k`
+    fn from_str(s: &str) -> Result<Node, ParseError> {
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 7 || !parts[0].starts_with('v') || parts[6] != "k" {
+            return Err(ParseError::InvalidNode)
+        }
+        let version = parts[0][1..].parse().map_err(|_| ParseError::InvalidVersion)?;
+        let path = parse_path(&parts[1..5].join("."))?;
+        let public_key = public_key_from_base32(parts[5])?;
+        Ok(Node::new(public_key, path, version))
+    }
 }
 
 impl Eq for Node {
@@ -129,3 +320,71 @@ impl Hash for Node {
         self.public_key.hash(state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_address_display() {
+        let s = "fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9";
+        let addr = Address::from_str(s).unwrap();
+        assert_eq!(addr.to_string(), s);
+        assert_eq!(Address::from_str("foo"), Err(ParseError::InvalidAddress));
+    }
+
+    #[test]
+    fn test_path_display() {
+        let path = parse_path("0000.0000.0000.0013").unwrap();
+        assert_eq!(path, [0, 0, 0, 0, 0, 0, 0, 0x13]);
+        assert_eq!(DisplayPath(&[0xfe, 0xdc, 0xba, 0x98, 0, 0, 0, 1]).to_string(), "fedc.ba98.0000.0001");
+        assert_eq!(parse_path("0000.0000.0013"), Err(ParseError::InvalidPath));
+        assert_eq!(parse_path("0000.0000.0000.+013"), Err(ParseError::InvalidPath));
+    }
+
+    #[test]
+    fn test_node_display() {
+        let s = "v18.0000.0000.0000.0013.g0pt6kwnwj8ndktjhs7pmcl14rg6uugn8kt4nykudtl96r27sch0.k";
+        let node = Node::from_str(s).unwrap();
+        assert_eq!(node.version(), 18);
+        assert_eq!(*node.path(), [0, 0, 0, 0, 0, 0, 0, 0x13]);
+        assert_eq!(node.public_key()[0..4], [14, 212, 108, 34]);
+        assert_eq!(node.to_string(), s);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_to_bytes("00ff7A"), Some(vec![0, 0xff, 0x7a]));
+        assert_eq!(hex_to_bytes(""), Some(vec![]));
+        assert_eq!(hex_to_bytes("0"), None);
+        assert_eq!(hex_to_bytes("+f"), None);
+        assert_eq!(hex_to_bytes("zz"), None);
+        let s = "ac3e53b518e68449692b0b2f2926ef2fdc1eac5b9dbd10a48114263b8c8ed12e";
+        assert_eq!(secret_key_from_hex(s).map(|secret_key| secret_key[..2].to_vec()), Some(vec![0xac, 0x3e]));
+        assert_eq!(secret_key_from_hex(&s[2..]), None);
+        assert_eq!(secret_key_from_hex(&format!("{}00", s)), None);
+    }
+
+    #[test]
+    fn test_address_from_public_key() {
+        let addr = Address::from_base32_public_key("2wrpv8p4tjwm532sjxcbqzkp7kdwfwzzbg7g0n5l6g3s8df4kvv0.k").unwrap();
+        assert_eq!(addr, Address::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap());
+        assert!(addr.is_valid());
+
+        let node = Node::from_base32_public_key("g0pt6kwnwj8ndktjhs7pmcl14rg6uugn8kt4nykudtl96r27sch0", [0, 0, 0, 0, 0, 0, 0, 1], 18).unwrap();
+        assert_eq!(node.address(), Address::from_str("fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc").unwrap());
+        assert_eq!(node.public_key_base32(), "g0pt6kwnwj8ndktjhs7pmcl14rg6uugn8kt4nykudtl96r27sch0.k");
+
+        assert_eq!(Address::from_base32_public_key("foo.k"), Err(ParseError::InvalidPublicKey));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rotate_64_roundtrip(bytes in any::<[u8; 16]>()) {
+            prop_assert_eq!(rotate_64(&rotate_64(&bytes)), bytes);
+            prop_assert_eq!(Address::new(&bytes).bytes(), bytes);
+        }
+    }
+}
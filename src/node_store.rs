@@ -1,9 +1,20 @@
+use std::collections::{HashMap, BTreeMap};
+
 use simple_kbuckets::Table;
 
-use node::{Address, Node, ADDRESS_BITS};
+use node::{Address, Node, ADDRESS_BITS, path_length};
+use event::{NodeStoreEvent, Observers, SubscriptionId, Callback};
+use stats::NodeStoreStats;
+#[cfg(feature = "announcements")]
+use node::Path;
+#[cfg(feature = "announcements")]
+use label::splice;
+#[cfg(feature = "announcements")]
+use announcement::VerifiedAnnouncement;
 
 /// Returns by a request to find a node's path and public key.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum GetNodeResult<'a> {
     /// The exact node, if it was found.
     FoundNode(&'a Node),
@@ -15,24 +26,261 @@ pub enum GetNodeResult<'a> {
     Nothing,
 }
 
-pub struct NodeStore {
+impl<'a> GetNodeResult<'a> {
+    /// Clones the nodes, so the result does not borrow the NodeStore
+    /// anymore.
+    pub fn into_owned(self) -> OwnedGetNodeResult {
+        match self {
+            GetNodeResult::FoundNode(node) => OwnedGetNodeResult::FoundNode(node.clone()),
+            GetNodeResult::ClosestNodes(nodes) => OwnedGetNodeResult::ClosestNodes(
+                nodes.into_iter().map(|(addr, node)| (addr, node.clone())).collect()),
+            GetNodeResult::Nothing => OwnedGetNodeResult::Nothing,
+        }
+    }
+}
+
+/// Same as `GetNodeResult`, but owning its nodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OwnedGetNodeResult {
+    FoundNode(Node),
+    ClosestNodes(Vec<(Address, Node)>),
+    Nothing,
+}
+
+/// Whether a node answered our last attempt to reach it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Reachability {
+    /// The node was added or changed path, and has not been contacted
+    /// through this path yet.
+    Unconfirmed,
+    Reachable,
+    Unreachable,
+}
+
+/// Copy of a node of the table, with some metadata.
+#[derive(Clone, Debug)]
+struct KnownNode {
+    node: Node,
+    reachability: Reachability,
+}
+
+/// Table of nodes, notifying callbacks of type `C` of its changes
+/// (`SendCallback` makes the store `Send`).
+pub struct NodeStore<C: ?Sized = Callback> {
     pub table: Table<Address, Node>,
+    my_address: Address,
+    bucket_size: usize,
+    /// Copy of the nodes in `table`, used to detect changes.
+    known_nodes: HashMap<Address, KnownNode>,
+    /// Addresses in `known_nodes`, indexed by bucket.
+    buckets: Vec<Vec<Address>>,
+    observers: Observers<C>,
+    /// Timestamp of the last announcement applied, for announcers in
+    /// the store.
+    #[cfg(feature = "announcements")]
+    announcements: HashMap<Address, u64>,
 }
 
 impl NodeStore {
     /// Creates a new empty NodeStore.
     pub fn new(my_address: Address) -> NodeStore {
+        NodeStore::with_observers(my_address, Observers::new())
+    }
+}
+
+impl<C: ?Sized + FnMut(&NodeStoreEvent)> NodeStore<C> {
+    /// Creates a new empty NodeStore, notifying these observers.
+    pub fn with_observers(my_address: Address, observers: Observers<C>) -> NodeStore<C> {
         let bucket_size = 32;
         let max_distance = ADDRESS_BITS;
         NodeStore {
-            table: Table::new(my_address, bucket_size, max_distance),
+            table: Table::new(my_address.clone(), bucket_size, max_distance),
+            my_address: my_address,
+            bucket_size: bucket_size,
+            known_nodes: HashMap::new(),
+            buckets: vec![Vec::new(); max_distance+1],
+            observers: observers,
+            #[cfg(feature = "announcements")]
+            announcements: HashMap::new(),
         }
     }
 
+    /// Registers a callback that will be called every time a node
+    /// is added, evicted, changes path, or is marked as unreachable.
+    pub fn subscribe(&mut self, callback: Box<C>) -> SubscriptionId {
+        self.observers.subscribe(callback)
+    }
+
+    /// Removes a callback added with `subscribe`.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.observers.unsubscribe(id)
+    }
+
     /// Inserts a node in the NodeStore, poping nodes from full
     /// buckets if necessary.
     pub fn update(&mut self, address: Address, node: Node) {
-        self.table.update(address, node);
+        self.table.update(address.clone(), node.clone());
+        if !self.contains(&address) {
+            // Refused by the table (eg. it is our own address).
+            return;
+        }
+        let bucket = self.bucket_index(&address);
+        let previous = self.known_nodes.get(&address).cloned();
+        match previous {
+            Some(old) => {
+                if old.node.path() != node.path() {
+                    self.known_nodes.insert(address.clone(), KnownNode { node: node.clone(), reachability: Reachability::Unconfirmed });
+                    self.observers.emit(NodeStoreEvent::PathChanged {
+                        address: address,
+                        old_path: *old.node.path(),
+                        new_path: *node.path(),
+                    });
+                }
+                else {
+                    self.known_nodes.insert(address.clone(), KnownNode { node: node.clone(), reachability: old.reachability });
+                }
+            }
+            None => {
+                self.known_nodes.insert(address.clone(), KnownNode { node: node.clone(), reachability: Reachability::Unconfirmed });
+                self.buckets[bucket].push(address.clone());
+                self.observers.emit(NodeStoreEvent::NodeAdded { address: address, node: node });
+                if self.buckets[bucket].len() > self.bucket_size {
+                    // The bucket was full, so the table made room.
+                    self.remove_evicted(bucket);
+                }
+            }
+        }
+    }
+
+    /// Records that the node answered through its current path.
+    pub fn mark_reachable(&mut self, address: &Address) {
+        if let Some(known_node) = self.known_nodes.get_mut(address) {
+            known_node.reachability = Reachability::Reachable;
+        }
+    }
+
+    /// Records that the node did not answer, and emits a
+    /// `NodeUnreachable` event if the node is in the store.
+    pub fn mark_unreachable(&mut self, address: &Address) {
+        if let Some(known_node) = self.known_nodes.get_mut(address) {
+            known_node.reachability = Reachability::Unreachable;
+        }
+        else {
+            return;
+        }
+        self.observers.emit(NodeStoreEvent::NodeUnreachable { address: address.clone() });
+    }
+
+    /// Inserts the announcer of a verified announcement, through this
+    /// path, and its peers through it. Peers whose link is down are
+    /// marked as unreachable if we know them through the announcer.
+    ///
+    /// Announcements not newer than the last one applied for this
+    /// announcer are ignored (unless it was evicted since). Returns
+    /// whether the announcement was applied.
+    #[cfg(feature = "announcements")]
+    pub fn add_announcement(&mut self, path: &Path, announcement: &VerifiedAnnouncement) -> bool {
+        let announcement = announcement.announcement();
+        let address = announcement.address();
+        if self.announcements.get(&address).map_or(false, |last| announcement.timestamp <= *last) {
+            return false
+        }
+        self.update(address.clone(), Node::new(announcement.public_key, *path, announcement.version));
+        if !self.contains(&address) {
+            return false
+        }
+        self.announcements.insert(address, announcement.timestamp);
+        for peer in &announcement.peers {
+            let peer_path = match splice(&peer.label, path) {
+                Some(peer_path) => peer_path,
+                None => continue, // Too long
+            };
+            let address = peer.address();
+            if peer.state == Reachability::Unreachable {
+                let known_path = self.known_nodes.get(&address).map(|known_node| *known_node.node.path());
+                if known_path == Some(peer_path) {
+                    self.mark_unreachable(&address);
+                }
+            }
+            else {
+                self.update(address, Node::new(peer.public_key, peer_path, peer.version));
+            }
+        }
+        true
+    }
+
+    /// Returns the reachability of a node, if it is in the store.
+    pub fn reachability(&self, address: &Address) -> Option<Reachability> {
+        self.known_nodes.get(address).map(|known_node| known_node.reachability)
+    }
+
+    /// Computes statistics about the content of the table.
+    pub fn stats(&self) -> NodeStoreStats {
+        let mut versions = BTreeMap::new();
+        let mut reachability = BTreeMap::new();
+        let mut total_path_length = 0u64;
+        for known_node in self.known_nodes.values() {
+            *versions.entry(known_node.node.version()).or_insert(0) += 1;
+            *reachability.entry(known_node.reachability).or_insert(0) += 1;
+            total_path_length += path_length(known_node.node.path()) as u64;
+        }
+        let total_nodes = self.known_nodes.len();
+        NodeStoreStats {
+            total_nodes: total_nodes,
+            bucket_size: self.bucket_size,
+            bucket_fill: self.buckets.iter().map(Vec::len).collect(),
+            versions: versions,
+            average_path_length: if total_nodes == 0 { 0. } else { total_path_length as f64 / total_nodes as f64 },
+            reachability: reachability,
+        }
+    }
+
+    /// Returns all nodes of the table, ordered by address.
+    pub fn nodes(&self) -> Vec<(&Address, &Node)> {
+        let mut nodes: Vec<_> = self.known_nodes.iter().map(|(addr, known_node)| (addr, &known_node.node)).collect();
+        nodes.sort_by(|&(addr1, _), &(addr2, _)| addr1.cmp(addr2));
+        nodes
+    }
+
+    /// Number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.known_nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known_nodes.is_empty()
+    }
+
+    /// Returns whether the table currently contains this address.
+    pub fn contains(&self, address: &Address) -> bool {
+        match self.table.find(address, 1).get(0) {
+            Some(&(ref addr, _)) => addr == address,
+            None => false,
+        }
+    }
+
+    /// Index of the bucket this address belongs to.
+    fn bucket_index(&self, address: &Address) -> usize {
+        self.my_address.distance(address).bucket_index()
+    }
+
+    /// Forgets the node the table popped from a full bucket to insert
+    /// another one, and notifies observers about it. The table pops at
+    /// most one node per insertion; nodes are checked oldest first, as
+    /// the oldest is the one usually popped.
+    fn remove_evicted(&mut self, bucket: usize) {
+        let index = match self.buckets[bucket].iter().position(|addr| !self.contains(addr)) {
+            Some(index) => index,
+            None => return,
+        };
+        let address = self.buckets[bucket].remove(index);
+        #[cfg(feature = "announcements")]
+        self.announcements.remove(&address);
+        if let Some(known_node) = self.known_nodes.remove(&address) {
+            self.observers.emit(NodeStoreEvent::NodeEvicted { address: address, node: known_node.node });
+        }
     }
 
     /// Retrurns an ordered vector of nodes, which are the closest to the
@@ -67,6 +315,7 @@ mod tests {
     use std::str::FromStr;
     use node::Address;
     use node::Node;
+    use proptest::prelude::*;
 
     #[test]
     fn test_get_one_node() {
@@ -78,4 +327,116 @@ mod tests {
         let res = ns.get_node(&target, 42);
         assert_eq!(res, GetNodeResult::ClosestNodes(vec![(addr, &node)]));
     }
+
+    #[test]
+    fn test_events() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use event::NodeStoreEvent;
+
+        let mut ns = NodeStore::new(Address::from(Ipv6Addr::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap()));
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+        ns.subscribe(Box::new(move |event| events2.borrow_mut().push(event.clone())));
+
+        let addr = Address::from(Ipv6Addr::from_str("fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc").unwrap());
+        let pk = [14, 212, 108, 34, 167, 28, 34, 202, 98, 134, 15, 159, 58, 151, 12, 228, 58, 163, 181, 163, 40, 102,  66, 125, 212, 44, 203, 100, 174, 56, 120, 61];
+        let node = Node::new(pk, [0, 0, 0, 0, 0, 0, 0, 11], 17);
+        ns.update(addr.clone(), node.clone());
+        ns.update(addr.clone(), node.clone());
+        let node2 = Node::new(pk, [0, 0, 0, 0, 0, 0, 0, 13], 17);
+        ns.update(addr.clone(), node2.clone());
+        ns.mark_unreachable(&addr);
+
+        assert_eq!(*events.borrow(), vec![
+            NodeStoreEvent::NodeAdded { address: addr.clone(), node: node },
+            NodeStoreEvent::PathChanged { address: addr.clone(), old_path: [0, 0, 0, 0, 0, 0, 0, 11], new_path: [0, 0, 0, 0, 0, 0, 0, 13] },
+            NodeStoreEvent::NodeUnreachable { address: addr },
+            ]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut ns = NodeStore::new(Address::from(Ipv6Addr::from_str("fc8f:a188:1b5:4de9:b0cb:5729:23a1:60f9").unwrap()));
+        let addr1 = Address::from(Ipv6Addr::from_str("fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc").unwrap());
+        let addr2 = Address::from(Ipv6Addr::from_str("fcb9:326d:37d5:c57b:7ee5:28b5:7aa5:525").unwrap());
+        ns.update(addr1.clone(), Node::new([1; 32], [0, 0, 0, 0, 0, 0, 0, 0b1011], 17));
+        ns.update(addr2.clone(), Node::new([2; 32], [0, 0, 0, 0, 0, 0, 0, 0b10011], 18));
+        ns.mark_reachable(&addr1);
+
+        let stats = ns.stats();
+        assert_eq!(stats.total_nodes, 2);
+        assert_eq!(stats.bucket_fill.iter().sum::<usize>(), 2);
+        assert_eq!(stats.versions.get(&17), Some(&1));
+        assert_eq!(stats.versions.get(&18), Some(&1));
+        assert_eq!(stats.average_path_length, 3.5);
+        assert_eq!(stats.reachability.get(&Reachability::Reachable), Some(&1));
+        assert_eq!(stats.reachability.get(&Reachability::Unconfirmed), Some(&1));
+        assert_eq!(stats.reachability.get(&Reachability::Unreachable), None);
+    }
+
+    fn new_store(addresses: &[[u8; 16]]) -> NodeStore {
+        let mut ns = NodeStore::new(Address::new(&[0xfc; 16]));
+        for (i, bytes) in addresses.iter().enumerate() {
+            ns.update(Address::new(bytes), Node::new([i as u8; 32], [0, 0, 0, 0, 0, 0, 0, 0b1011], 18));
+        }
+        ns
+    }
+
+    proptest! {
+        #[test]
+        fn prop_find_closest_nodes_sorted(
+                addresses in prop::collection::vec(any::<[u8; 16]>(), 0..100),
+                target in any::<[u8; 16]>(),
+                count in 0usize..20) {
+            let ns = new_store(&addresses);
+            let target = Address::new(&target);
+            let closest = ns.find_closest_nodes(&target, count);
+            prop_assert!(closest.len() <= count);
+            for pair in closest.windows(2) {
+                prop_assert!(pair[0].0.distance(&target) <= pair[1].0.distance(&target));
+            }
+        }
+
+        #[test]
+        fn prop_get_node_found_iff_stored(
+                addresses in prop::collection::vec(any::<[u8; 16]>(), 1..100),
+                random_target in any::<[u8; 16]>(),
+                index in any::<prop::sample::Index>(),
+                pick_stored in any::<bool>()) {
+            use std::collections::HashMap;
+            use std::rc::Rc;
+            use std::cell::RefCell;
+            use event::NodeStoreEvent;
+
+            // Model of the store: inserted nodes, minus evicted ones.
+            let my_address = Address::new(&[0xfc; 16]);
+            let mut ns = NodeStore::new(my_address.clone());
+            let evicted = Rc::new(RefCell::new(Vec::new()));
+            let evicted2 = evicted.clone();
+            ns.subscribe(Box::new(move |event| {
+                if let NodeStoreEvent::NodeEvicted { ref address, .. } = *event {
+                    evicted2.borrow_mut().push(address.clone());
+                }
+            }));
+            let mut model = HashMap::new();
+            for (i, bytes) in addresses.iter().enumerate() {
+                let node = Node::new([i as u8; 32], [0, 0, 0, 0, 0, 0, 0, 0b1011], 18);
+                ns.update(Address::new(bytes), node.clone());
+                if Address::new(bytes) != my_address {
+                    model.insert(Address::new(bytes), node);
+                }
+                for address in evicted.borrow_mut().drain(..) {
+                    model.remove(&address);
+                }
+            }
+
+            let target = if pick_stored { Address::new(index.get(&addresses)) } else { Address::new(&random_target) };
+            let stored = model.get(&target).cloned();
+            match ns.get_node(&target, 8) {
+                GetNodeResult::FoundNode(node) => { prop_assert_eq!(Some(node.clone()), stored); }
+                _ => { prop_assert!(stored.is_none()); }
+            }
+        }
+    }
 }
@@ -0,0 +1,478 @@
+//! Recording of everything fed to a `Driver` (packets received, timer
+//! ticks, lookups, links, queries sent on behalf of the application),
+//! and its replay into a fresh `Driver`, to reproduce the behavior of
+//! a router offline (see `Driver::start_recording` and the
+//! `fcp-replay` binary).
+//!
+//! The driver does not read the clock nor draw random numbers, so
+//! replaying a recording gives the same actions and routing table as
+//! the original run, as long as the recording started with the driver.
+//!
+//! A recording is a sequence of bencoded dictionaries: a header with
+//! the address, routing mode and configuration of the driver, then
+//! one per event.
+//! Route packets are stored with the fields the router reads (not the
+//! encoding scheme).
+
+use std::error::Error;
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{self, Write};
+
+use fcp_switching::route_packet::{RoutePacketBuilder, NodeData};
+
+use bencode::Value;
+use driver::{Action, Driver, DriverConfig, Input};
+use janitor::JanitorConfig;
+use node::{Address, Node, Path, PUBLIC_KEY_LENGTH};
+use router::{Router, RoutingMode};
+
+/// Version of the format, increased on incompatible changes.
+/// Version 1 had no routing mode nor announcement interval (the
+/// router was always in DHT mode); it is still read.
+const FORMAT_VERSION: i64 = 2;
+
+/// Something fed to the driver.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Input(Input),
+    /// Call to `Driver::ping`.
+    Ping { node: Node },
+    /// Call to `Driver::get_peers`.
+    GetPeers { node: Node },
+    /// Call to `Driver::find_node`.
+    FindNode { node: Node, target: Address },
+}
+
+impl Event {
+    /// Gives the event to the driver.
+    pub fn apply(self, driver: &mut Driver) {
+        match self {
+            Event::Input(input) => driver.handle_input(input),
+            Event::Ping { node } => driver.ping(node),
+            Event::GetPeers { node } => driver.get_peers(node),
+            Event::FindNode { node, target } => driver.find_node(node, target),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RecordingError {
+    /// The recording does not start with a valid header.
+    InvalidHeader,
+    /// The recording was made by an incompatible version.
+    UnsupportedVersion(i64),
+    /// The event with this index (starting from 0) is invalid or
+    /// truncated.
+    InvalidEvent(usize),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RecordingError::InvalidHeader => write!(f, "not a recording"),
+            RecordingError::UnsupportedVersion(version) => write!(f, "unsupported recording version {}", version),
+            RecordingError::InvalidEvent(index) => write!(f, "invalid or truncated event #{}", index),
+        }
+    }
+}
+
+impl Error for RecordingError {
+}
+
+/// Writes events to a file (or anything else).
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    /// Writes the header of a recording of a driver with this address,
+    /// routing mode and configuration.
+    pub fn new(mut writer: Box<dyn Write + Send>, my_address: &Address, mode: &RoutingMode, config: &DriverConfig) -> io::Result<Recorder> {
+        let mut items = vec![
+            ("recording", Value::string("fcp-routing")),
+            ("version", Value::Int(FORMAT_VERSION)),
+            ("address", Value::Bytes(my_address.bytes().to_vec())),
+            ("queryTimeout", Value::Int(config.query_timeout as i64)),
+            ("parallelism", Value::Int(config.parallelism as i64)),
+            ("nbClosest", Value::Int(config.nb_closest as i64)),
+            ("announceInterval", Value::Int(config.announce_interval as i64)),
+            ];
+        if let Some(ref janitor) = config.janitor {
+            items.push(("janitorInterval", Value::Int(janitor.interval as i64)));
+            items.push(("janitorMaxQueries", Value::Int(janitor.max_queries as i64)));
+            items.push(("janitorMinBucketSize", Value::Int(janitor.min_bucket_size as i64)));
+            items.push(("janitorRefreshInterval", Value::Int(janitor.refresh_interval as i64)));
+            items.push(("janitorStaleAfter", Value::Int(janitor.stale_after as i64)));
+        }
+        match *mode {
+            RoutingMode::Dht => items.push(("mode", Value::string("dht"))),
+            RoutingMode::Supernode => items.push(("mode", Value::string("supernode"))),
+            RoutingMode::Subnode { ref supernode } => {
+                items.push(("mode", Value::string("subnode")));
+                items.push(("supernode", Value::Bytes(supernode.bytes().to_vec())));
+            }
+        }
+        let header = Value::dict(items);
+        writer.write_all(&header.encode())?;
+        Ok(Recorder { writer: writer })
+    }
+
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        self.writer.write_all(&encode_event(event).encode())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn node_value(public_key: &[u8; PUBLIC_KEY_LENGTH], path: &Path, version: u64) -> Value {
+    Value::dict(vec![
+        ("key", Value::Bytes(public_key.to_vec())),
+        ("path", Value::Bytes(path.to_vec())),
+        ("version", Value::Int(version as i64)),
+        ])
+}
+
+fn encode_node(node: &Node) -> Value {
+    node_value(node.public_key(), node.path(), node.version())
+}
+
+fn encode_event(event: &Event) -> Value {
+    match *event {
+        Event::Input(Input::RoutePacket { ref label, ref public_key, ref packet, now }) => {
+            let mut items = vec![
+                ("e", Value::string("packet")),
+                ("now", Value::Int(now as i64)),
+                ("label", Value::Bytes(label.to_vec())),
+                ("key", Value::Bytes(public_key.to_vec())),
+                ("p", Value::Int(packet.protocol_version)),
+                ("txid", Value::Bytes(packet.transaction_id.clone())),
+                ];
+            if let Some(ref query) = packet.query {
+                items.push(("q", Value::string(query)));
+            }
+            if let Some(ref target) = packet.target_address {
+                items.push(("tar", Value::Bytes(target.clone())));
+            }
+            if let Some(ref nodes) = packet.nodes {
+                let nodes = nodes.iter().map(|node| node_value(&node.public_key, &node.path, node.version)).collect();
+                items.push(("n", Value::List(nodes)));
+            }
+            Value::dict(items)
+        }
+        Event::Input(Input::Tick { now }) =>
+            Value::dict(vec![("e", Value::string("tick")), ("now", Value::Int(now as i64))]),
+        Event::Input(Input::Lookup { ref target }) =>
+            Value::dict(vec![("e", Value::string("lookup")), ("target", Value::Bytes(target.bytes().to_vec()))]),
+        Event::Input(Input::PeerUp { ref node }) =>
+            Value::dict(vec![("e", Value::string("peerUp")), ("node", encode_node(node))]),
+        Event::Input(Input::PeerDown { ref address }) =>
+            Value::dict(vec![("e", Value::string("peerDown")), ("address", Value::Bytes(address.bytes().to_vec()))]),
+        Event::Ping { ref node } =>
+            Value::dict(vec![("e", Value::string("ping")), ("node", encode_node(node))]),
+        Event::GetPeers { ref node } =>
+            Value::dict(vec![("e", Value::string("getPeers")), ("node", encode_node(node))]),
+        Event::FindNode { ref node, ref target } =>
+            Value::dict(vec![
+                ("e", Value::string("findNode")),
+                ("node", encode_node(node)),
+                ("target", Value::Bytes(target.bytes().to_vec())),
+                ]),
+    }
+}
+
+fn decode_array<T: Default + AsMut<[u8]>>(value: Option<&Value>) -> Option<T> {
+    let bytes = value?.as_bytes()?;
+    let mut array = T::default();
+    if bytes.len() != array.as_mut().len() {
+        return None
+    }
+    array.as_mut().copy_from_slice(bytes);
+    Some(array)
+}
+
+fn decode_address(value: Option<&Value>) -> Option<Address> {
+    decode_array::<[u8; 16]>(value).map(|bytes| Address::new(&bytes))
+}
+
+fn decode_u64(value: Option<&Value>) -> Option<u64> {
+    value?.as_int().map(|i| i as u64)
+}
+
+fn decode_node_data(value: &Value) -> Option<NodeData> {
+    Some(NodeData {
+        public_key: decode_array(value.get("key"))?,
+        path: decode_array(value.get("path"))?,
+        version: decode_u64(value.get("version"))?,
+    })
+}
+
+fn decode_node(value: Option<&Value>) -> Option<Node> {
+    let node_data = decode_node_data(value?)?;
+    Some(Node::new(node_data.public_key, node_data.path, node_data.version))
+}
+
+fn decode_config(header: &Value, version: i64) -> Option<DriverConfig> {
+    let janitor = match header.get("janitorInterval") {
+        Some(interval) => Some(JanitorConfig {
+            interval: decode_u64(Some(interval))?,
+            max_queries: decode_u64(header.get("janitorMaxQueries"))? as usize,
+            min_bucket_size: decode_u64(header.get("janitorMinBucketSize"))? as usize,
+            refresh_interval: decode_u64(header.get("janitorRefreshInterval"))?,
+            stale_after: decode_u64(header.get("janitorStaleAfter"))?,
+        }),
+        None => None,
+    };
+    Some(DriverConfig {
+        query_timeout: decode_u64(header.get("queryTimeout"))?,
+        parallelism: decode_u64(header.get("parallelism"))? as usize,
+        nb_closest: decode_u64(header.get("nbClosest"))? as usize,
+        announce_interval: match header.get("announceInterval") {
+            None if version == 1 => DriverConfig::default().announce_interval,
+            interval => decode_u64(interval)?,
+        },
+        janitor: janitor,
+    })
+}
+
+fn decode_mode(header: &Value, version: i64) -> Option<RoutingMode> {
+    if version == 1 {
+        return Some(RoutingMode::Dht)
+    }
+    match header.get("mode")?.as_str()? {
+        "dht" => Some(RoutingMode::Dht),
+        "supernode" => Some(RoutingMode::Supernode),
+        "subnode" => Some(RoutingMode::Subnode { supernode: decode_address(header.get("supernode"))? }),
+        _ => None,
+    }
+}
+
+fn decode_event(value: &Value) -> Option<Event> {
+    let event = match value.get("e")?.as_str()? {
+        "packet" => {
+            let mut packet = RoutePacketBuilder::new(value.get("p")?.as_int()?, value.get("txid")?.as_bytes()?.to_vec()).finalize();
+            packet.query = match value.get("q") {
+                Some(query) => Some(query.as_str()?.to_owned()),
+                None => None,
+            };
+            packet.target_address = match value.get("tar") {
+                Some(target) => Some(target.as_bytes()?.to_vec()),
+                None => None,
+            };
+            packet.nodes = match value.get("n") {
+                Some(&Value::List(ref nodes)) => Some(nodes.iter().map(decode_node_data).collect::<Option<Vec<_>>>()?),
+                Some(_) => return None,
+                None => None,
+            };
+            Input::RoutePacket {
+                label: decode_array(value.get("label"))?,
+                public_key: decode_array(value.get("key"))?,
+                packet: packet,
+                // Absent from recordings made before it was added.
+                now: match value.get("now") {
+                    Some(now) => decode_u64(Some(now))?,
+                    None => 0,
+                },
+            }
+        }
+        "tick" => Input::Tick { now: decode_u64(value.get("now"))? },
+        "lookup" => Input::Lookup { target: decode_address(value.get("target"))? },
+        "peerUp" => Input::PeerUp { node: decode_node(value.get("node"))? },
+        "peerDown" => Input::PeerDown { address: decode_address(value.get("address"))? },
+        "ping" => return Some(Event::Ping { node: decode_node(value.get("node"))? }),
+        "getPeers" => return Some(Event::GetPeers { node: decode_node(value.get("node"))? }),
+        "findNode" => return Some(Event::FindNode {
+            node: decode_node(value.get("node"))?,
+            target: decode_address(value.get("target"))?,
+        }),
+        _ => return None,
+    };
+    Some(Event::Input(event))
+}
+
+/// Content of a recording.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    pub my_address: Address,
+    pub mode: RoutingMode,
+    pub config: DriverConfig,
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn parse(data: &[u8]) -> Result<Recording, RecordingError> {
+        let (header, mut rest) = Value::decode_prefix(data).map_err(|_| RecordingError::InvalidHeader)?;
+        if header.get("recording").and_then(Value::as_str) != Some("fcp-routing") {
+            return Err(RecordingError::InvalidHeader)
+        }
+        let version = match header.get("version").and_then(Value::as_int) {
+            Some(version) if (1..=FORMAT_VERSION).contains(&version) => version,
+            Some(version) => return Err(RecordingError::UnsupportedVersion(version)),
+            None => return Err(RecordingError::InvalidHeader),
+        };
+        let config = decode_config(&header, version).ok_or(RecordingError::InvalidHeader)?;
+        let my_address = decode_address(header.get("address")).ok_or(RecordingError::InvalidHeader)?;
+        let mode = decode_mode(&header, version).ok_or(RecordingError::InvalidHeader)?;
+
+        let mut events = Vec::new();
+        while !rest.is_empty() {
+            let invalid = RecordingError::InvalidEvent(events.len());
+            let (value, new_rest) = Value::decode_prefix(rest).map_err(|_| invalid.clone())?;
+            events.push(decode_event(&value).ok_or(invalid)?);
+            rest = new_rest;
+        }
+        Ok(Recording { my_address: my_address, mode: mode, config: config, events: events })
+    }
+
+    /// Feeds the events to a fresh driver with the same address, mode
+    /// and configuration as the recorded one, and collects the actions
+    /// triggered by each of them.
+    pub fn replay(&self) -> Replay {
+        let mut router = Router::new(self.my_address.clone());
+        router.set_mode(self.mode.clone());
+        let mut driver = Driver::new(router, self.config.clone());
+        let steps = self.events.iter().map(|event| {
+            event.clone().apply(&mut driver);
+            let mut actions = Vec::new();
+            while let Some(action) = driver.poll_action() {
+                actions.push(action);
+            }
+            (event.clone(), actions)
+        }).collect();
+        Replay { driver: driver, steps: steps }
+    }
+}
+
+/// Result of `Recording::replay`.
+pub struct Replay {
+    /// The driver, after all events.
+    pub driver: Driver,
+    /// Each event, with the actions it triggered.
+    pub steps: Vec<(Event, Vec<Action>)>,
+}
+
+impl Replay {
+    /// Describes the events, their actions, and the final routing
+    /// table, one per line, in a form meant to be diffed (eg. between
+    /// the replay of a recording before and after a change).
+    pub fn transcript(&self) -> String {
+        let mut out = String::new();
+        for (i, &(ref event, ref actions)) in self.steps.iter().enumerate() {
+            writeln!(out, "#{} {:?}", i, event).unwrap();
+            for action in actions {
+                writeln!(out, "    {:?}", action).unwrap();
+            }
+        }
+        let node_store = self.driver.router().node_store();
+        writeln!(out, "Routing table ({} nodes):", node_store.len()).unwrap();
+        for (address, node) in node_store.nodes() {
+            writeln!(out, "    {} {} {:?}", address, node, node_store.reachability(address)).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use driver::LookupError;
+    use label::path_from_u64;
+
+    /// Buffer the test can read while the driver writes to it.
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn drain(driver: &mut Driver) -> Vec<Action> {
+        let mut actions = Vec::new();
+        while let Some(action) = driver.poll_action() {
+            actions.push(action);
+        }
+        actions
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let (pk_a, pk_b, pk_c) = ([1; 32], [2; 32], [3; 32]);
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut a = Driver::new(Router::new(Address::from_public_key(&pk_a)), DriverConfig::default());
+        a.start_recording(Box::new(buffer.clone())).unwrap();
+        let mut b = Driver::new(Router::new(Address::from_public_key(&pk_b)), DriverConfig::default());
+        b.handle_input(Input::PeerUp { node: Node::new(pk_c, path_from_u64(0b1_101), 18) });
+
+        let peer = Node::new(pk_b, path_from_u64(0b1_011), 18);
+        a.handle_input(Input::PeerUp { node: peer.clone() });
+        a.handle_input(Input::Lookup { target: Address::from_public_key(&[4; 32]) });
+        a.ping(peer.clone());
+        let mut original = drain(&mut a);
+        for action in original.clone() {
+            if let Action::SendToNode { packet, .. } = action {
+                b.handle_input(Input::RoutePacket { label: path_from_u64(0b1_110), public_key: pk_a, packet: packet, now: 0 });
+            }
+        }
+        for action in drain(&mut b) {
+            if let Action::SendToLabel { packet, .. } = action {
+                a.handle_input(Input::RoutePacket { label: path_from_u64(0b1_011), public_key: pk_b, packet: packet, now: 0 });
+            }
+        }
+        a.handle_input(Input::Tick { now: 10000 });
+        original.extend(drain(&mut a));
+        assert!(original.iter().any(|action| match *action {
+            Action::LookupCompleted { ref result, .. } => *result == Err(LookupError::NotFound),
+            _ => false,
+        }));
+
+        let recording = Recording::parse(&buffer.0.lock().unwrap()).unwrap();
+        assert_eq!(recording.my_address, Address::from_public_key(&pk_a));
+        assert_eq!(recording.mode, RoutingMode::Dht);
+        assert_eq!(recording.events.len(), 6);
+        let replay = recording.replay();
+        let replayed: Vec<Action> = replay.steps.iter().flat_map(|&(_, ref actions)| actions.clone()).collect();
+        assert_eq!(format!("{:?}", replayed), format!("{:?}", original));
+        assert_eq!(replay.driver.router().node_store().nodes(), a.router().node_store().nodes());
+        assert_eq!(replay.transcript(), recording.replay().transcript());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Recording::parse(b"").unwrap_err(), RecordingError::InvalidHeader);
+        let header = Value::dict(vec![("recording", Value::string("fcp-routing")), ("version", Value::Int(3))]);
+        assert_eq!(Recording::parse(&header.encode()).unwrap_err(), RecordingError::UnsupportedVersion(3));
+
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut recorder = Recorder::new(Box::new(buffer.clone()), &Address::new(&[0xfc; 16]), &RoutingMode::Dht, &DriverConfig::default()).unwrap();
+        recorder.record(&Event::Input(Input::Tick { now: 5 })).unwrap();
+        let mut data = buffer.0.lock().unwrap().clone();
+        data.extend(b"d1:e4:tick");
+        assert_eq!(Recording::parse(&data).unwrap_err(), RecordingError::InvalidEvent(1));
+    }
+
+    #[test]
+    fn test_version_1() {
+        let header = Value::dict(vec![
+            ("recording", Value::string("fcp-routing")),
+            ("version", Value::Int(1)),
+            ("address", Value::Bytes(vec![0xfc; 16])),
+            ("queryTimeout", Value::Int(2000)),
+            ("parallelism", Value::Int(2)),
+            ("nbClosest", Value::Int(4)),
+            ]);
+        let mut data = header.encode();
+        data.extend(Value::dict(vec![("e", Value::string("tick")), ("now", Value::Int(5))]).encode());
+        let recording = Recording::parse(&data).unwrap();
+        assert_eq!(recording.mode, RoutingMode::Dht);
+        assert_eq!((recording.config.query_timeout, recording.config.parallelism), (2000, 2));
+        assert_eq!(recording.config.announce_interval, DriverConfig::default().announce_interval);
+        assert_eq!(recording.events.len(), 1);
+    }
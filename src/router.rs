@@ -1,33 +1,219 @@
-use fcp_switching::route_packet::{RoutePacket, RoutePacketBuilder};
+use std::collections::HashMap;
+
+use fcp_switching::route_packet::{RoutePacket, RoutePacketBuilder, NodeData};
 use fcp_switching::operation::Label;
 use std::iter::FromIterator;
 use fcp_switching::encoding_scheme::{EncodingScheme, EncodingSchemeForm};
 
 use node_store::{NodeStore, GetNodeResult};
 use node::{Address, Node};
+use supernode::{LinkGraph, ANNOUNCE_QUERY, GET_ROUTE_QUERY};
+use event::{NodeStoreEvent, Observers, SubscriptionId, Callback};
+use stats::NodeStoreStats;
+use metrics::Metrics;
+#[cfg(feature = "announcements")]
+use node::Path;
+#[cfg(feature = "announcements")]
+use node_store::Reachability;
+#[cfg(feature = "announcements")]
+use announcement::{Announcement, AnnouncementError};
+
+pub const PROTOCOL_VERSION: i64 = 18;
+
+/// Number of nodes sent in replies to `fn` and `gp` queries.
+const NB_NODES_PER_REPLY: usize = 8;
+
+/// Encoding forms of our switch: fixed-width 3-bit directors.
+pub fn encoding_scheme_forms() -> Vec<EncodingSchemeForm> {
+    vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }]
+}
+
+/// Encoding scheme of our switch, sent along with paths.
+pub fn encoding_scheme() -> EncodingScheme {
+    EncodingScheme::from_iter(encoding_scheme_forms().iter())
+}
+
+/// Builds a `fn` query, asking a node about the nodes closest to
+/// the target.
+pub fn find_node_query(target: &Address, transaction_id: Vec<u8>) -> RoutePacket {
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .query("fn".to_owned())
+            .target_address(target.bytes().to_vec())
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
+
+/// Builds a `pn` query, to check a node is reachable.
+pub fn ping_query(transaction_id: Vec<u8>) -> RoutePacket {
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .query("pn".to_owned())
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
+
+/// Builds a `gp` query, asking a node about its peers.
+pub fn get_peers_query(transaction_id: Vec<u8>) -> RoutePacket {
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .query("gp".to_owned())
+            .target_address(vec![0, 0, 0, 0, 0, 0, 0, 0])
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
 
-const PROTOCOL_VERSION: i64 = 18;
+/// Builds a reply to a query, containing the given nodes.
+fn nodes_reply(transaction_id: Vec<u8>, nodes: Vec<&Node>) -> RoutePacket {
+    let nodes = nodes.into_iter().map(|node| NodeData {
+        public_key: *node.public_key(),
+        path: *node.path(),
+        version: node.version(),
+    }).collect();
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .nodes_vec(nodes)
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
 
+/// How the router finds paths to other nodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RoutingMode {
+    /// Lookups query the nodes closest to the target in our routing
+    /// table, which is kept up to date as a DHT.
+    Dht,
+    /// We announce our peers to this supernode, and ask it for paths.
+    /// Lookups fall back to the DHT if it is not reachable or does not
+    /// know a path.
+    Subnode { supernode: Address },
+    /// We keep the links announced by subnodes, and answer their path
+    /// queries (see the `supernode` module).
+    Supernode,
+}
 
 /// Wrapper of `NodeStore` that reads/writes network packets.
 /// TODO: Check paths are valid before inserting them (eg. send a
 /// ping and wait for the reply).
-pub struct Router {
-    node_store: NodeStore,
+pub struct Router<C: ?Sized = Callback> {
+    my_address: Address,
+    node_store: NodeStore<C>,
+    /// Nodes we have a direct link with.
+    peers: HashMap<Address, Node>,
+    metrics: Metrics,
+    mode: RoutingMode,
+    /// Links announced to us, if we are a supernode.
+    link_graph: Option<LinkGraph>,
 }
 
 impl Router {
     pub fn new(my_address: Address) -> Router {
+        Router::with_observers(my_address, Observers::new())
+    }
+}
+
+impl<C: ?Sized + FnMut(&NodeStoreEvent)> Router<C> {
+    /// Same as `new`, with these observers of the node store (see
+    /// `NodeStore::with_observers`).
+    pub fn with_observers(my_address: Address, observers: Observers<C>) -> Router<C> {
         Router {
-            node_store: NodeStore::new(my_address),
+            my_address: my_address.clone(),
+            node_store: NodeStore::with_observers(my_address, observers),
+            peers: HashMap::new(),
+            metrics: Metrics::new(),
+            mode: RoutingMode::Dht,
+            link_graph: None,
         }
     }
 
+    pub fn mode(&self) -> &RoutingMode {
+        &self.mode
+    }
+
+    /// Changes the routing mode. Links announced to us are forgotten
+    /// if we stop being a supernode.
+    pub fn set_mode(&mut self, mode: RoutingMode) {
+        self.link_graph = match mode {
+            RoutingMode::Supernode => self.link_graph.take().or_else(|| Some(LinkGraph::new())),
+            _ => None,
+        };
+        self.mode = mode;
+    }
+
+    /// Links announced to us, if we are a supernode.
+    pub fn link_graph(&self) -> Option<&LinkGraph> {
+        self.link_graph.as_ref()
+    }
+
+    pub fn my_address(&self) -> &Address {
+        &self.my_address
+    }
+
+    pub fn node_store(&self) -> &NodeStore<C> {
+        &self.node_store
+    }
+
+    pub fn node_store_mut(&mut self) -> &mut NodeStore<C> {
+        &mut self.node_store
+    }
+
     /// See `NodeStore::update`.
     pub fn update(&mut self, address: Address, node: Node) {
         self.node_store.update(address, node)
     }
 
+    /// Adds a node we have a direct link with. It is also inserted
+    /// in the `NodeStore`.
+    pub fn add_peer(&mut self, node: Node) {
+        let address = node.address();
+        self.peers.insert(address.clone(), node.clone());
+        self.node_store.update(address, node);
+    }
+
+    /// Removes a peer whose link went down. Returns it if it was a
+    /// peer.
+    pub fn remove_peer(&mut self, address: &Address) -> Option<Node> {
+        let node = self.peers.remove(address);
+        if node.is_some() {
+            self.node_store.mark_unreachable(address);
+        }
+        node
+    }
+
+    /// Nodes we have a direct link with.
+    pub fn peers(&self) -> &HashMap<Address, Node> {
+        &self.peers
+    }
+
+    /// See `NodeStore::subscribe`.
+    pub fn subscribe(&mut self, callback: Box<C>) -> SubscriptionId {
+        self.node_store.subscribe(callback)
+    }
+
+    /// See `NodeStore::unsubscribe`.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.node_store.unsubscribe(id)
+    }
+
+    /// See `NodeStore::stats`.
+    pub fn stats(&self) -> NodeStoreStats {
+        self.node_store.stats()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// Renders the metrics, and the state of the routing table, in the
+    /// Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.stats(), self.peers.len())
+    }
+
     /// Wrapper for `NodeStore::get_node` that returns RoutePackets that
     /// should be sent in order to fetch the target node.
     pub fn get_node(&self, target: &Address, nb_closest: usize) -> (Option<&Node>, Vec<(&Node, RoutePacket)>) {
@@ -36,13 +222,7 @@ impl Router {
             GetNodeResult::ClosestNodes(nodes) => {
                 // Ask each of the closest nodes about the target
                 let requests = nodes.iter().map(|&(ref _addr, ref node)| {
-                    let encoding_scheme = EncodingScheme::from_iter(vec![EncodingSchemeForm { prefix: 0, bit_count: 3, prefix_length: 0 }].iter());
-                    let packet = RoutePacketBuilder::new(PROTOCOL_VERSION, b"blah".to_vec())
-                            .query("fn".to_owned())
-                            .target_address(target.bytes().to_vec())
-                            .encoding_index(0)
-                            .encoding_scheme(encoding_scheme)
-                            .finalize();
+                    let packet = find_node_query(target, b"blah".to_vec());
                     (*node, packet)
                 });
                 let requests = requests.collect();
@@ -55,9 +235,129 @@ impl Router {
         }
     }
 
-    /// Called when a RoutePacket is received from the network.
+    /// Called when a RoutePacket is received from the network, from
+    /// this node (whose path is the one the packet came from).
     /// Optionally returns RoutePackets to send back.
-    pub fn on_route_packet(&mut self, label: &Label, packet: &RoutePacket) -> Result<Vec<(Label, RoutePacket)>, ()> {
-        Ok(Vec::new())
+    ///
+    /// Answers `fn`, `gp` and `pn` queries. Replies to our own queries
+    /// are ignored; they are handled by `driver::Driver`.
+    pub fn on_route_packet(&mut self, sender: &Node, packet: &RoutePacket) -> Result<Vec<(Label, RoutePacket)>, ()> {
+        let query = match packet.query {
+            Some(ref query) => query,
+            None => return Ok(Vec::new()),
+        };
+        let transaction_id = packet.transaction_id.clone();
+        let reply = match query.as_ref() {
+            "fn" => {
+                let target = match packet.target_address {
+                    Some(ref target) if target.len() == 16 => {
+                        let mut bytes = [0u8; 16];
+                        bytes.copy_from_slice(target);
+                        Address::new(&bytes)
+                    }
+                    _ => {
+                        self.metrics.record_invalid_query();
+                        return Err(())
+                    }
+                };
+                let closest_nodes = self.node_store.find_closest_nodes(&target, NB_NODES_PER_REPLY);
+                nodes_reply(transaction_id, closest_nodes.into_iter().map(|(_addr, node)| node).collect())
+            }
+            "gp" => {
+                // The requester knows itself already.
+                let requester = sender.address();
+                let mut peers: Vec<&Node> = self.peers.iter()
+                        .filter(|&(address, _)| *address != requester)
+                        .map(|(_, node)| node)
+                        .collect();
+                peers.sort_by_key(|node| *node.path());
+                peers.truncate(NB_NODES_PER_REPLY);
+                nodes_reply(transaction_id, peers)
+            }
+            "pn" => {
+                RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id).finalize()
+            }
+            _ => {
+                self.metrics.record_invalid_query();
+                return Err(())
+            }
+        };
+        self.metrics.record_query_answered(query);
+        Ok(vec![(*sender.path(), reply)])
+    }
+
+    /// Verifies a signed announcement (see the `announcement` module)
+    /// from the node at this path, and adds the announcer and its
+    /// peers to the node store; in supernode mode, its links replace
+    /// the ones in the link graph. Returns whether it was used (it is
+    /// not if an announcement of the same node, at least as recent,
+    /// was already used).
+    #[cfg(feature = "announcements")]
+    pub fn on_announcement(&mut self, path: &Path, data: &[u8]) -> Result<bool, AnnouncementError> {
+        let verified = Announcement::verify(data)?;
+        if !self.node_store.add_announcement(path, &verified) {
+            return Ok(false)
+        }
+        if let Some(ref mut graph) = self.link_graph {
+            let announcement = verified.announcement();
+            let announcer = Node::new(announcement.public_key, *path, announcement.version);
+            let peers: Vec<NodeData> = announcement.peers.iter()
+                    .filter(|peer| peer.state != Reachability::Unreachable)
+                    .map(|peer| NodeData { public_key: peer.public_key, path: peer.label, version: peer.version })
+                    .collect();
+            return Ok(graph.announce(&announcer, &peers).is_ok())
+        }
+        Ok(true)
+    }
+
+    /// Answers `ann` and `gr` queries from a subnode (see the
+    /// `supernode` module). Fails if we are not a supernode.
+    pub fn on_supernode_query(&mut self, sender: &Node, packet: &RoutePacket) -> Result<RoutePacket, ()> {
+        let (query, graph) = match (packet.query.as_ref(), self.link_graph.as_mut()) {
+            (Some(query), Some(graph)) => (query, graph),
+            _ => {
+                self.metrics.record_invalid_query();
+                return Err(())
+            }
+        };
+        let transaction_id = packet.transaction_id.clone();
+        let reply = match query.as_ref() {
+            ANNOUNCE_QUERY => {
+                if graph.announce(sender, packet.nodes.as_ref().map(|nodes| &nodes[..]).unwrap_or(&[])).is_err() {
+                    self.metrics.record_invalid_query();
+                    return Err(())
+                }
+                RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id).finalize()
+            }
+            GET_ROUTE_QUERY => {
+                let target = match packet.target_address {
+                    Some(ref target) if target.len() == 16 => {
+                        let mut bytes = [0u8; 16];
+                        bytes.copy_from_slice(target);
+                        Address::new(&bytes)
+                    }
+                    _ => {
+                        self.metrics.record_invalid_query();
+                        return Err(())
+                    }
+                };
+                // Our own links are part of the graph too.
+                let mut peers: Vec<NodeData> = self.peers.values().map(|node| NodeData {
+                    public_key: *node.public_key(),
+                    path: *node.path(),
+                    version: node.version(),
+                }).collect();
+                peers.sort_by_key(|peer| peer.path);
+                graph.set_links(self.my_address.clone(), &peers);
+                let node = graph.find_path(&sender.address(), &target);
+                nodes_reply(transaction_id, node.iter().collect())
+            }
+            _ => {
+                self.metrics.record_invalid_query();
+                return Err(())
+            }
+        };
+        self.metrics.record_query_answered(query);
+        Ok(reply)
     }
 }
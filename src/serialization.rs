@@ -0,0 +1,86 @@
+//! Implementations of serde's traits, enabled by the `serde` feature.
+//!
+//! Addresses are encoded with their IPv6 textual form, public keys in
+//! cjdns' base32 (with the `.k` suffix), and paths as cjdns labels
+//! (eg. `0000.0000.0000.0013`).
+
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+
+use node::Address;
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Address::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+/// For use with `#[serde(with = "::serialization::path")]`
+pub mod path {
+    use serde::{Serializer, Deserialize, Deserializer};
+    use serde::de::Error;
+
+    use node::{Path, DisplayPath, parse_path};
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&DisplayPath(path))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Path, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_path(&s).map_err(D::Error::custom)
+    }
+}
+
+/// For use with `#[serde(with = "::serialization::public_key")]`
+pub mod public_key {
+    use serde::{Serializer, Deserialize, Deserializer};
+    use serde::de::Error;
+
+    use node::{PUBLIC_KEY_LENGTH, public_key_to_base32, public_key_from_base32};
+
+    pub fn serialize<S: Serializer>(public_key: &[u8; PUBLIC_KEY_LENGTH], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&public_key_to_base32(public_key))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; PUBLIC_KEY_LENGTH], D::Error> {
+        let s = String::deserialize(deserializer)?;
+        public_key_from_base32(&s).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json;
+
+    use node::{Address, Node};
+    use node_store::OwnedGetNodeResult;
+
+    #[test]
+    fn test_json() {
+        let addr = Address::from_str("fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc").unwrap();
+        let node = Node::from_str("v18.0000.0000.0000.0013.g0pt6kwnwj8ndktjhs7pmcl14rg6uugn8kt4nykudtl96r27sch0.k").unwrap();
+        let result = OwnedGetNodeResult::ClosestNodes(vec![(addr, node.clone())]);
+
+        let json = serde_json::to_string(&result).unwrap();
+        assert_eq!(json, r#"{"ClosestNodes":[["fc7c:8316:ec7d:1308:d3c2:6db7:5ad9:6ebc",{"public_key":"g0pt6kwnwj8ndktjhs7pmcl14rg6uugn8kt4nykudtl96r27sch0.k","path":"0000.0000.0000.0013","version":18}]]}"#);
+        let decoded: OwnedGetNodeResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, result);
+        // Node's equality only compares keys.
+        match decoded {
+            OwnedGetNodeResult::ClosestNodes(nodes) => assert_eq!(nodes[0].1.path(), node.path()),
+            _ => panic!("Wrong variant"),
+        }
+    }
+}
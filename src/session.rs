@@ -0,0 +1,364 @@
+//! Maps addresses to end-to-end sessions, on top of the `Driver`.
+//!
+//! The `SessionManager` does not do any cryptography itself: it tells
+//! the application when to open a session (eg. a CryptoAuth session)
+//! with a node and with which handle, and which message to send
+//! through which session and path. Messages sent to a node whose path
+//! is not known yet are buffered until the lookup is over.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use node::{Address, Node, Path};
+use node_store::GetNodeResult;
+use event::NodeStoreEvent;
+use driver::{Driver, Input, Action, LookupError};
+
+/// Maximum number of messages buffered for a node while looking it up.
+/// Older messages are dropped first.
+const MAX_BUFFERED_MESSAGES: usize = 32;
+
+/// Session handles are never smaller than this, as these values are
+/// used by CryptoAuth handshake packets in place of the handle.
+const FIRST_HANDLE: u32 = 4;
+
+/// End-to-end session with a node.
+#[derive(Clone, Debug)]
+pub struct Session {
+    handle: u32,
+    node: Node,
+}
+
+impl Session {
+    /// Our handle of the session.
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+    /// The node at the other end, with the current path to it.
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+    pub fn path(&self) -> &Path {
+        self.node.path()
+    }
+}
+
+/// Something the application should do, returned by
+/// `SessionManager::poll_action`.
+#[derive(Debug)]
+pub enum SessionAction<M> {
+    /// Action of the underlying `Driver`.
+    Router(Action),
+    /// Open a session with this node, identified by this handle.
+    OpenSession { handle: u32, node: Node },
+    /// Send a message through a session, using this path.
+    SendMessage { handle: u32, path: Path, message: M },
+    /// Forget the session with this handle; it was closed or replaced
+    /// by a session the node opened.
+    CloseSession { handle: u32 },
+    /// The path of a session changed; the following messages will use
+    /// the new one.
+    PathChanged { handle: u32, path: Path },
+    /// The lookup of a node failed; messages buffered for it are
+    /// given back.
+    Unreachable { address: Address, error: LookupError, messages: Vec<M> },
+}
+
+pub struct SessionManager<M> {
+    driver: Driver,
+    sessions: HashMap<Address, Session>,
+    handles: HashMap<u32, Address>,
+    next_handle: u32,
+    /// Messages waiting for a lookup to complete.
+    buffered: HashMap<Address, VecDeque<M>>,
+    /// Events of the node store, filled by a callback.
+    store_events: Arc<Mutex<Vec<NodeStoreEvent>>>,
+    actions: VecDeque<SessionAction<M>>,
+}
+
+impl<M> SessionManager<M> {
+    pub fn new(mut driver: Driver) -> SessionManager<M> {
+        let store_events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let store_events = store_events.clone();
+            driver.router_mut().subscribe(Box::new(move |event| {
+                if let NodeStoreEvent::PathChanged { .. } = *event {
+                    store_events.lock().unwrap().push(event.clone());
+                }
+            }));
+        }
+        SessionManager {
+            driver: driver,
+            sessions: HashMap::new(),
+            handles: HashMap::new(),
+            next_handle: FIRST_HANDLE,
+            buffered: HashMap::new(),
+            store_events: store_events,
+            actions: VecDeque::new(),
+        }
+    }
+
+    pub fn driver(&self) -> &Driver {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut Driver {
+        &mut self.driver
+    }
+
+    /// Returns the session with this address, if any.
+    pub fn session(&self, address: &Address) -> Option<&Session> {
+        self.sessions.get(address)
+    }
+
+    /// Returns the session with this handle, if any.
+    pub fn session_by_handle(&self, handle: u32) -> Option<&Session> {
+        self.handles.get(&handle).and_then(|address| self.sessions.get(address))
+    }
+
+    /// Returns the next action to perform, if any.
+    pub fn poll_action(&mut self) -> Option<SessionAction<M>> {
+        self.actions.pop_front()
+    }
+
+    /// Feeds an event to the driver (see `Driver::handle_input`).
+    pub fn handle_input(&mut self, input: Input) {
+        self.driver.handle_input(input);
+        self.process_driver();
+    }
+
+    /// Returns the session using this path, if any.
+    pub fn session_by_path(&self, path: &Path) -> Option<&Session> {
+        self.sessions.values().find(|session| session.path() == path)
+    }
+
+    /// Returns a handle that is not used by any session, for sessions
+    /// opened by other nodes (see `on_incoming_session`).
+    pub fn gen_handle(&mut self) -> u32 {
+        loop {
+            let handle = self.next_handle;
+            self.next_handle = self.next_handle.checked_add(1).unwrap_or(FIRST_HANDLE);
+            if !self.handles.contains_key(&handle) {
+                return handle
+            }
+        }
+    }
+
+    /// Registers a session opened by a node (eg. when receiving a
+    /// CryptoAuth handshake), with a handle returned by `gen_handle`.
+    /// It replaces any previous session with this node.
+    pub fn on_incoming_session(&mut self, handle: u32, node: Node) {
+        let address = node.address();
+        self.close(&address);
+        self.handles.insert(handle, address.clone());
+        self.sessions.insert(address, Session { handle: handle, node: node });
+        self.flush_buffered(handle);
+    }
+
+    /// Forgets the session with this node, and asks the application to
+    /// do the same with `CloseSession`. Returns its handle.
+    pub fn close(&mut self, address: &Address) -> Option<u32> {
+        let session = self.sessions.remove(address);
+        session.map(|session| {
+            self.handles.remove(&session.handle);
+            self.actions.push_back(SessionAction::CloseSession { handle: session.handle });
+            session.handle
+        })
+    }
+
+    /// Sends a message to a node. If there is no session with this
+    /// node, it is opened; and if its path is unknown, the message is
+    /// buffered and the node is looked up.
+    pub fn send(&mut self, address: Address, message: M) {
+        if let Some(session) = self.sessions.get(&address) {
+            self.actions.push_back(SessionAction::SendMessage {
+                handle: session.handle,
+                path: *session.path(),
+                message: message,
+            });
+            return;
+        }
+
+        let node = match self.driver.router().node_store().get_node(&address, 1) {
+            GetNodeResult::FoundNode(node) => Some(node.clone()),
+            _ => None,
+        };
+        match node {
+            Some(node) => {
+                let handle = self.add_session(address.clone(), node.clone());
+                self.actions.push_back(SessionAction::SendMessage {
+                    handle: handle,
+                    path: *node.path(),
+                    message: message,
+                });
+            }
+            None => {
+                let is_looking_up = self.buffered.contains_key(&address);
+                {
+                    let buffer = self.buffered.entry(address.clone()).or_insert_with(VecDeque::new);
+                    if buffer.len() >= MAX_BUFFERED_MESSAGES {
+                        buffer.pop_front();
+                    }
+                    buffer.push_back(message);
+                }
+                if !is_looking_up {
+                    self.handle_input(Input::Lookup { target: address });
+                }
+            }
+        }
+    }
+
+    fn add_session(&mut self, address: Address, node: Node) -> u32 {
+        let handle = self.gen_handle();
+        self.handles.insert(handle, address.clone());
+        self.sessions.insert(address, Session { handle: handle, node: node.clone() });
+        self.actions.push_back(SessionAction::OpenSession { handle: handle, node: node });
+        handle
+    }
+
+    /// Sends messages buffered for the node of this session.
+    fn flush_buffered(&mut self, handle: u32) {
+        let (address, path) = {
+            let session = self.session_by_handle(handle).unwrap();
+            (session.node.address(), *session.path())
+        };
+        if let Some(messages) = self.buffered.remove(&address) {
+            for message in messages {
+                self.actions.push_back(SessionAction::SendMessage { handle: handle, path: path, message: message });
+            }
+        }
+    }
+
+    /// Reads the driver's actions, and updates sessions accordingly.
+    fn process_driver(&mut self) {
+        while let Some(action) = self.driver.poll_action() {
+            match action {
+                Action::LookupCompleted { ref target, result: Ok(ref node) } => {
+                    if self.buffered.contains_key(target) && !self.sessions.contains_key(target) {
+                        let handle = self.add_session(target.clone(), node.clone());
+                        self.flush_buffered(handle);
+                    }
+                }
+                Action::LookupCompleted { ref target, result: Err(ref error) } => {
+                    if let Some(messages) = self.buffered.remove(target) {
+                        self.actions.push_back(SessionAction::Unreachable {
+                            address: target.clone(),
+                            error: error.clone(),
+                            messages: messages.into_iter().collect(),
+                        });
+                    }
+                }
+                _ => (),
+            }
+            self.actions.push_back(SessionAction::Router(action));
+        }
+
+        let events: Vec<NodeStoreEvent> = self.store_events.lock().unwrap().drain(..).collect();
+        for event in events {
+            if let NodeStoreEvent::PathChanged { address, new_path, .. } = event {
+                if let Some(session) = self.sessions.get_mut(&address) {
+                    session.node = Node::new(*session.node.public_key(), new_path, session.node.version());
+                    self.actions.push_back(SessionAction::PathChanged { handle: session.handle, path: new_path });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use router::Router;
+    use driver::DriverConfig;
+    use label::{path_from_u64, path_to_u64};
+
+    fn new_manager(public_key: [u8; 32]) -> SessionManager<&'static str> {
+        let router = Router::new(Address::from_public_key(&public_key));
+        SessionManager::new(Driver::new(router, DriverConfig::default()))
+    }
+
+    #[test]
+    fn test_send_to_known_node() {
+        let mut manager = new_manager([1; 32]);
+        let peer = Node::new([2; 32], path_from_u64(0b1_011), 18);
+        manager.handle_input(Input::PeerUp { node: peer.clone() });
+        while manager.poll_action().is_some() {}
+
+        manager.send(peer.address(), "hello");
+        let handle = match manager.poll_action() {
+            Some(SessionAction::OpenSession { handle, node }) => {
+                assert_eq!(node, peer);
+                handle
+            }
+            action => panic!("Unexpected action: {:?}", action),
+        };
+        assert!(handle >= FIRST_HANDLE);
+        match manager.poll_action() {
+            Some(SessionAction::SendMessage { handle: h, path, message }) => {
+                assert_eq!((h, path_to_u64(&path), message), (handle, 0b1_011, "hello"));
+            }
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        assert_eq!(manager.session_by_handle(handle).unwrap().node(), &peer);
+
+        // The router learns a new path.
+        manager.driver_mut().router_mut().update(peer.address(), Node::new([2; 32], path_from_u64(0b1_101), 18));
+        manager.handle_input(Input::Tick { now: 1 });
+        let mut path_changed = false;
+        while let Some(action) = manager.poll_action() {
+            if let SessionAction::PathChanged { handle: h, path } = action {
+                assert_eq!((h, path_to_u64(&path)), (handle, 0b1_101));
+                path_changed = true;
+            }
+        }
+        assert!(path_changed);
+        assert_eq!(path_to_u64(manager.session(&peer.address()).unwrap().path()), 0b1_101);
+    }
+
+    #[test]
+    fn test_buffer_until_lookup_fails() {
+        let mut manager = new_manager([1; 32]);
+        let target = Address::from_public_key(&[3; 32]);
+        manager.send(target.clone(), "hello");
+        let mut unreachable = false;
+        while let Some(action) = manager.poll_action() {
+            if let SessionAction::Unreachable { address, error, messages } = action {
+                assert_eq!(address, target);
+                assert_eq!(error, LookupError::NoNodes);
+                assert_eq!(messages, vec!["hello"]);
+                unreachable = true;
+            }
+        }
+        assert!(unreachable);
+        assert!(manager.session(&target).is_none());
+    }
+
+    #[test]
+    fn test_close() {
+        let mut manager = new_manager([1; 32]);
+        let peer = Node::new([2; 32], path_from_u64(0b1_011), 18);
+        manager.handle_input(Input::PeerUp { node: peer.clone() });
+        while manager.poll_action().is_some() {}
+
+        // The node opens a session, then replaces it with another one.
+        let handle1 = manager.gen_handle();
+        manager.on_incoming_session(handle1, peer.clone());
+        assert!(manager.poll_action().is_none());
+        let handle2 = manager.gen_handle();
+        manager.on_incoming_session(handle2, peer.clone());
+        match manager.poll_action() {
+            Some(SessionAction::CloseSession { handle }) => assert_eq!(handle, handle1),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        assert!(manager.poll_action().is_none());
+        assert!(manager.session_by_handle(handle1).is_none());
+
+        assert_eq!(manager.close(&peer.address()), Some(handle2));
+        match manager.poll_action() {
+            Some(SessionAction::CloseSession { handle }) => assert_eq!(handle, handle2),
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        assert_eq!(manager.close(&peer.address()), None);
+        assert!(manager.poll_action().is_none());
+    }
+}
@@ -0,0 +1,571 @@
+//! In-process network simulator, to test routing convergence without
+//! real peers.
+//!
+//! Each simulated node runs a `Driver`, behind a virtual switch whose
+//! interfaces are the node's links. Switches use fixed-width
+//! directors, as wide as their number of links requires (at least 3
+//! bits), so paths across the network mix different encoding widths.
+//! Packets are forwarded by reading their label hop by hop, like real
+//! switches do, and delivered after a configurable latency; they can
+//! also be dropped at random.
+//!
+//! Everything is deterministic for a given seed.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use fcp_switching::route_packet::RoutePacket;
+
+use node::{Address, Node, Path, PUBLIC_KEY_LENGTH};
+use router::{Router, PROTOCOL_VERSION};
+use driver::{Driver, DriverConfig, Input, Action, LookupError};
+use label::{path_to_u64, path_from_u64};
+
+/// Smallest width of the directors of a switch.
+const MIN_DIRECTOR_BITS: u32 = 3;
+
+/// Directors 0 and 1 are not used for links: 1 routes to the switch
+/// itself.
+const FIRST_INTERFACE: usize = 2;
+
+/// Deterministic pseudo-random number generator (SplitMix64).
+#[derive(Clone, Debug)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`.
+    fn gen_range(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Returns a number in `[0, 1)`.
+    fn gen_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Nodes and the links between them.
+#[derive(Clone, Debug)]
+pub struct Topology {
+    nb_nodes: usize,
+    links: Vec<(usize, usize)>,
+}
+
+impl Topology {
+    /// Nodes with no links.
+    pub fn new(nb_nodes: usize) -> Topology {
+        Topology { nb_nodes: nb_nodes, links: Vec::new() }
+    }
+
+    /// Adds a link between two nodes.
+    pub fn link(&mut self, a: usize, b: usize) {
+        assert!(a < self.nb_nodes && b < self.nb_nodes, "No such node.");
+        assert!(a != b, "A node cannot be linked to itself.");
+        assert!(!self.is_linked(a, b), "Nodes are already linked.");
+        self.links.push((a, b));
+    }
+
+    pub fn is_linked(&self, a: usize, b: usize) -> bool {
+        self.links.iter().any(|&(x, y)| (x, y) == (a, b) || (x, y) == (b, a))
+    }
+
+    pub fn nb_nodes(&self) -> usize {
+        self.nb_nodes
+    }
+
+    pub fn links(&self) -> &[(usize, usize)] {
+        &self.links
+    }
+
+    /// Node `i` is linked to node `i+1`.
+    pub fn line(nb_nodes: usize) -> Topology {
+        let mut topology = Topology::new(nb_nodes);
+        for i in 1..nb_nodes {
+            topology.link(i-1, i);
+        }
+        topology
+    }
+
+    /// A line whose ends are linked.
+    pub fn ring(nb_nodes: usize) -> Topology {
+        let mut topology = Topology::line(nb_nodes);
+        if nb_nodes > 2 {
+            topology.link(nb_nodes-1, 0);
+        }
+        topology
+    }
+
+    /// Nodes on a `width`×`height` grid, linked to their horizontal
+    /// and vertical neighbours. Node `(x, y)` has index `y*width + x`.
+    pub fn grid(width: usize, height: usize) -> Topology {
+        let mut topology = Topology::new(width*height);
+        for y in 0..height {
+            for x in 0..width {
+                let i = y*width + x;
+                if x+1 < width {
+                    topology.link(i, i+1);
+                }
+                if y+1 < height {
+                    topology.link(i, i+width);
+                }
+            }
+        }
+        topology
+    }
+
+    /// Random connected graph: a random spanning tree, plus
+    /// `nb_extra_links` random links.
+    pub fn random(nb_nodes: usize, nb_extra_links: usize, seed: u64) -> Topology {
+        let mut rng = Rng::new(seed);
+        let mut topology = Topology::new(nb_nodes);
+        for i in 1..nb_nodes {
+            let j = rng.gen_range(i as u64) as usize;
+            topology.link(j, i);
+        }
+        let max_links = nb_nodes * nb_nodes.saturating_sub(1) / 2;
+        let nb_links = ::std::cmp::min(topology.links.len() + nb_extra_links, max_links);
+        while topology.links.len() < nb_links {
+            let a = rng.gen_range(nb_nodes as u64) as usize;
+            let b = rng.gen_range(nb_nodes as u64) as usize;
+            if a != b && !topology.is_linked(a, b) {
+                topology.link(a, b);
+            }
+        }
+        topology
+    }
+}
+
+/// Parameters of the `Simulator`.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    /// Milliseconds for a packet to cross a link.
+    pub latency: u64,
+    /// Maximum random delay (in milliseconds) added to each packet.
+    pub jitter: u64,
+    /// Probability for a packet to be lost, between 0 and 1.
+    pub loss_rate: f64,
+    /// Seed of the public keys, jitter and losses.
+    pub seed: u64,
+    /// Configuration of the nodes' drivers.
+    pub driver: DriverConfig,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> SimulatorConfig {
+        SimulatorConfig {
+            latency: 10,
+            jitter: 0,
+            loss_rate: 0.,
+            seed: 0,
+            driver: DriverConfig::default(),
+        }
+    }
+}
+
+/// A lookup that completed on a node.
+#[derive(Clone, Debug)]
+pub struct CompletedLookup {
+    /// Index of the node that did the lookup.
+    pub from: usize,
+    pub target: Address,
+    pub result: Result<Node, LookupError>,
+}
+
+struct SimNode {
+    driver: Driver,
+    public_key: [u8; PUBLIC_KEY_LENGTH],
+    /// Width of the directors of this node's switch.
+    director_bits: u32,
+    /// Node at the other end of each interface, indexed by director.
+    interfaces: Vec<Option<usize>>,
+    /// Actions of the driver not handled by the simulator.
+    actions: Vec<Action>,
+}
+
+/// A packet on its way.
+struct InFlight {
+    to: usize,
+    /// Path from the destination back to the sender.
+    reverse_label: Path,
+    from_public_key: [u8; PUBLIC_KEY_LENGTH],
+    packet: RoutePacket,
+}
+
+pub struct Simulator {
+    config: SimulatorConfig,
+    rng: Rng,
+    now: u64,
+    nodes: Vec<SimNode>,
+    addresses: HashMap<Address, usize>,
+    /// Packets in flight, ordered by delivery time then sequence number.
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    in_flight: HashMap<u64, InFlight>,
+    next_packet_id: u64,
+    completed: Vec<CompletedLookup>,
+    packets_sent: u64,
+    packets_lost: u64,
+}
+
+impl Simulator {
+    /// Creates the nodes of the topology, and tells each of them
+    /// about its peers.
+    pub fn new(topology: &Topology, config: SimulatorConfig) -> Simulator {
+        let mut rng = Rng::new(config.seed);
+        let mut degrees = vec![0; topology.nb_nodes()];
+        for &(a, b) in topology.links() {
+            degrees[a] += 1;
+            degrees[b] += 1;
+        }
+        let mut nodes = Vec::new();
+        let mut addresses = HashMap::new();
+        for (i, degree) in degrees.into_iter().enumerate() {
+            let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+            for chunk in public_key.chunks_mut(8) {
+                let random = rng.next_u64();
+                for (j, byte) in chunk.iter_mut().enumerate() {
+                    *byte = (random >> (8*j)) as u8;
+                }
+            }
+            let mut director_bits = MIN_DIRECTOR_BITS;
+            while (1usize << director_bits) < FIRST_INTERFACE + degree {
+                director_bits += 1;
+            }
+            let address = Address::from_public_key(&public_key);
+            addresses.insert(address.clone(), i);
+            nodes.push(SimNode {
+                driver: Driver::new(Router::new(address), config.driver.clone()),
+                public_key: public_key,
+                director_bits: director_bits,
+                interfaces: vec![None; FIRST_INTERFACE],
+                actions: Vec::new(),
+            });
+        }
+
+        let mut sim = Simulator {
+            config: config,
+            rng: rng,
+            now: 0,
+            nodes: nodes,
+            addresses: addresses,
+            queue: BinaryHeap::new(),
+            in_flight: HashMap::new(),
+            next_packet_id: 0,
+            completed: Vec::new(),
+            packets_sent: 0,
+            packets_lost: 0,
+        };
+        for &(a, b) in topology.links() {
+            sim.nodes[a].interfaces.push(Some(b));
+            sim.nodes[b].interfaces.push(Some(a));
+        }
+        for &(a, b) in topology.links() {
+            sim.peer_up(a, b);
+            sim.peer_up(b, a);
+        }
+        sim
+    }
+
+    /// Tells `a` about its link with `b`.
+    fn peer_up(&mut self, a: usize, b: usize) {
+        let director = self.interface_to(a, b).unwrap();
+        let path = path_from_u64((1u64 << self.nodes[a].director_bits) | director as u64);
+        let node = Node::new(self.nodes[b].public_key, path, PROTOCOL_VERSION as u64);
+        self.nodes[a].driver.handle_input(Input::PeerUp { node: node });
+        self.process_actions(a);
+    }
+
+    /// Director of the interface of `a` linked to `b`.
+    fn interface_to(&self, a: usize, b: usize) -> Option<usize> {
+        self.nodes[a].interfaces.iter().position(|node| *node == Some(b))
+    }
+
+    pub fn nb_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Current time, in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn driver(&self, index: usize) -> &Driver {
+        &self.nodes[index].driver
+    }
+
+    pub fn driver_mut(&mut self, index: usize) -> &mut Driver {
+        &mut self.nodes[index].driver
+    }
+
+    /// Calls `f` with the driver of a node, then sends the packets it
+    /// queued. Use this instead of `driver_mut` to send queries.
+    pub fn with_driver<F, R>(&mut self, index: usize, f: F) -> R
+            where F: FnOnce(&mut Driver) -> R {
+        let result = f(&mut self.nodes[index].driver);
+        self.process_actions(index);
+        result
+    }
+
+    /// Returns the actions of a node's driver other than packets to
+    /// send and completed lookups (see `take_completed`), since the
+    /// last call.
+    pub fn take_actions(&mut self, index: usize) -> Vec<Action> {
+        self.nodes[index].actions.drain(..).collect()
+    }
+
+    pub fn public_key(&self, index: usize) -> &[u8; PUBLIC_KEY_LENGTH] {
+        &self.nodes[index].public_key
+    }
+
+    pub fn address(&self, index: usize) -> Address {
+        self.nodes[index].driver.router().my_address().clone()
+    }
+
+    /// Index of the node with this address, if any.
+    pub fn node_index(&self, address: &Address) -> Option<usize> {
+        self.addresses.get(address).cloned()
+    }
+
+    /// Number of packets sent by all nodes.
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// Number of packets dropped, either at random or because their
+    /// label did not lead anywhere.
+    pub fn packets_lost(&self) -> u64 {
+        self.packets_lost
+    }
+
+    /// Returns the node a packet sent by `from` with this path would
+    /// reach, if any.
+    pub fn route(&self, from: usize, path: &Path) -> Option<usize> {
+        self.walk(from, path_to_u64(path)).map(|(to, _, _)| to)
+    }
+
+    /// Follows a label from a node. Returns the destination, the
+    /// label back to the sender, and the number of hops.
+    fn walk(&self, from: usize, mut label: u64) -> Option<(usize, u64, u64)> {
+        let mut current = from;
+        let mut reverse_label = 1u64;
+        let mut hops = 0;
+        while label != 1 {
+            let bits = self.nodes[current].director_bits;
+            if label >> bits == 0 {
+                // Not enough bits left for a director.
+                return None
+            }
+            let director = (label & ((1 << bits) - 1)) as usize;
+            label >>= bits;
+            let next = self.nodes[current].interfaces.get(director).cloned()??;
+            let back = self.interface_to(next, current).unwrap() as u64;
+            let next_bits = self.nodes[next].director_bits;
+            if reverse_label.leading_zeros() < next_bits {
+                return None
+            }
+            reverse_label = (reverse_label << next_bits) | back;
+            current = next;
+            hops += 1;
+        }
+        if hops == 0 { None } else { Some((current, reverse_label, hops)) }
+    }
+
+    /// Tells a node to look for another one. The result can then be
+    /// fetched with `take_completed`, once the simulation ran long
+    /// enough.
+    pub fn start_lookup(&mut self, from: usize, target: Address) {
+        self.nodes[from].driver.handle_input(Input::Lookup { target: target });
+        self.process_actions(from);
+    }
+
+    /// Returns lookups completed since the last call.
+    pub fn take_completed(&mut self) -> Vec<CompletedLookup> {
+        self.completed.drain(..).collect()
+    }
+
+    /// Makes node `from` look for node `to`, and runs the simulation
+    /// until the lookup is over.
+    pub fn lookup(&mut self, from: usize, to: usize) -> Result<Node, LookupError> {
+        let target = self.address(to);
+        self.start_lookup(from, target.clone());
+        loop {
+            if let Some(i) = self.completed.iter().position(|c| c.from == from && c.target == target) {
+                return self.completed.remove(i).result;
+            }
+            if !self.step() {
+                // Nothing left to deliver; let pending queries time out.
+                let now = self.now + self.config.driver.query_timeout;
+                self.set_time(now);
+            }
+        }
+    }
+
+    /// Delivers packets due before `time` (included), then advances
+    /// the clock to `time`.
+    pub fn run_until(&mut self, time: u64) {
+        while let Some(&Reverse((deliver_at, _))) = self.queue.peek() {
+            if deliver_at > time {
+                break;
+            }
+            self.step();
+        }
+        if time > self.now {
+            self.set_time(time);
+        }
+    }
+
+    /// Delivers the next packet. Returns `false` if there was none.
+    pub fn step(&mut self) -> bool {
+        let (deliver_at, id) = match self.queue.pop() {
+            Some(Reverse(next)) => next,
+            None => return false,
+        };
+        if deliver_at > self.now {
+            self.set_time(deliver_at);
+        }
+        let in_flight = self.in_flight.remove(&id).unwrap();
+        let input = Input::RoutePacket {
+            label: in_flight.reverse_label,
+            public_key: in_flight.from_public_key,
+            packet: in_flight.packet,
+            now: self.now,
+        };
+        self.nodes[in_flight.to].driver.handle_input(input);
+        self.process_actions(in_flight.to);
+        true
+    }
+
+    /// Advances the clock of all nodes.
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+        for i in 0..self.nodes.len() {
+            self.nodes[i].driver.handle_input(Input::Tick { now: now });
+            self.process_actions(i);
+        }
+    }
+
+    fn process_actions(&mut self, index: usize) {
+        while let Some(action) = self.nodes[index].driver.poll_action() {
+            match action {
+                Action::SendToNode { node, packet } => self.send(index, node.path(), packet),
+                Action::SendToLabel { label, packet } => self.send(index, &label, packet),
+                Action::LookupCompleted { target, result } =>
+                    self.completed.push(CompletedLookup { from: index, target: target, result: result }),
+                action => self.nodes[index].actions.push(action),
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, label: &Path, packet: RoutePacket) {
+        self.packets_sent += 1;
+        let (to, reverse_label, hops) = match self.walk(from, path_to_u64(label)) {
+            Some(route) => route,
+            None => {
+                self.packets_lost += 1;
+                return;
+            }
+        };
+        if self.config.loss_rate > 0. && self.rng.gen_f64() < self.config.loss_rate {
+            self.packets_lost += 1;
+            return;
+        }
+        let jitter = if self.config.jitter > 0 { self.rng.gen_range(self.config.jitter + 1) } else { 0 };
+        let deliver_at = self.now + hops*self.config.latency + jitter;
+        let id = self.next_packet_id;
+        self.next_packet_id += 1;
+        self.queue.push(Reverse((deliver_at, id)));
+        self.in_flight.insert(id, InFlight {
+            to: to,
+            reverse_label: path_from_u64(reverse_label),
+            from_public_key: self.nodes[from].public_key,
+            packet: packet,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lookups consider as many nodes as there are in the simulated
+    /// networks, so they only fail if the routing is broken.
+    fn config() -> SimulatorConfig {
+        let mut config = SimulatorConfig::default();
+        config.driver.nb_closest = 32;
+        config.jitter = 5;
+        config
+    }
+
+    fn check_all_lookups(topology: Topology) {
+        let mut sim = Simulator::new(&topology, config());
+        for from in 0..sim.nb_nodes() {
+            for to in 0..sim.nb_nodes() {
+                if from == to {
+                    continue;
+                }
+                match sim.lookup(from, to) {
+                    Ok(node) => {
+                        assert_eq!(node.public_key(), sim.public_key(to));
+                        assert_eq!(sim.route(from, node.path()), Some(to), "Wrong path from {} to {}", from, to);
+                    }
+                    Err(e) => panic!("Lookup from {} to {} failed: {:?}", from, to, e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_line() {
+        check_all_lookups(Topology::line(10));
+    }
+
+    #[test]
+    fn test_ring() {
+        check_all_lookups(Topology::ring(12));
+    }
+
+    #[test]
+    fn test_grid() {
+        check_all_lookups(Topology::grid(4, 4));
+    }
+
+    #[test]
+    fn test_random_graph() {
+        check_all_lookups(Topology::random(20, 10, 42));
+    }
+
+    #[test]
+    fn test_route() {
+        let sim = Simulator::new(&Topology::line(3), config());
+        // Node 1 has two links, with directors 2 (to node 0) and 3 (to node 2).
+        assert_eq!(sim.route(0, &path_from_u64(0b1_011_010)), Some(2));
+        assert_eq!(sim.route(0, &path_from_u64(0b1_010_010)), Some(0));
+        assert_eq!(sim.route(0, &path_from_u64(0b1_111_010)), None);
+        assert_eq!(sim.route(0, &path_from_u64(0b1)), None);
+    }
+
+    #[test]
+    fn test_total_loss() {
+        let mut config = config();
+        config.loss_rate = 1.;
+        let mut sim = Simulator::new(&Topology::ring(4), config);
+        // Node 0 is only linked to nodes 1 and 3.
+        assert_eq!(sim.lookup(0, 2).map(|_| ()), Err(LookupError::NotFound));
+        assert!(sim.packets_lost() > 0);
+        assert_eq!(sim.packets_lost(), sim.packets_sent());
+        assert!(sim.driver(0).router().metrics().query_timeouts("fn") > 0);
+    }
+}
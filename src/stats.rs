@@ -0,0 +1,35 @@
+use std::collections::BTreeMap;
+
+use node_store::Reachability;
+
+/// Health report of a `NodeStore`, as returned by `NodeStore::stats`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeStoreStats {
+    /// Number of nodes in the table.
+    pub total_nodes: usize,
+    /// Maximum number of nodes in a bucket.
+    pub bucket_size: usize,
+    /// Number of nodes in each bucket, indexed by bucket.
+    pub bucket_fill: Vec<usize>,
+    /// Number of nodes for each protocol version.
+    pub versions: BTreeMap<u64, usize>,
+    /// Mean of the number of bits of the nodes' paths (see
+    /// `node::path_length`), or 0 if the table is empty.
+    pub average_path_length: f64,
+    /// Number of nodes in each reachability state. States with no
+    /// nodes are absent.
+    pub reachability: BTreeMap<Reachability, usize>,
+}
+
+impl NodeStoreStats {
+    /// Number of buckets with at least one node.
+    pub fn nonempty_buckets(&self) -> usize {
+        self.bucket_fill.iter().filter(|fill| **fill > 0).count()
+    }
+
+    /// Number of buckets that have no room left.
+    pub fn full_buckets(&self) -> usize {
+        self.bucket_fill.iter().filter(|fill| **fill >= self.bucket_size).count()
+    }
+}
@@ -0,0 +1,212 @@
+//! Supernode routing: instead of keeping a DHT, subnodes announce
+//! their peers to a supernode, which keeps the graph of links between
+//! nodes and computes paths for them.
+//!
+//! Subnodes send `ann` queries, whose nodes are their peers (with paths
+//! from the subnode), and `gr` queries, whose target is the address
+//! they want a path to. The supernode replies to `gr` with the target,
+//! with a path from the subnode, or with no node if it does not know
+//! a path. See `router::RoutingMode`.
+//!
+//! Announcements are not signed: the announcer is the node at the
+//! other end of the session the query came through, so a node can only
+//! replace its own links, but nothing proves the links it claims exist.
+//! To limit the damage, the number of links per announcer is bounded,
+//! and a link between two announcers is only used if both announced it.
+//! With the `announcements` feature, `Router::on_announcement` also
+//! feeds the graph with signed announcements, which may be relayed.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use fcp_switching::route_packet::{RoutePacket, RoutePacketBuilder, NodeData};
+
+use node::{Address, Node, Path, PUBLIC_KEY_LENGTH};
+use label::splice;
+use router::{PROTOCOL_VERSION, encoding_scheme};
+
+/// Name of announcement queries.
+pub const ANNOUNCE_QUERY: &str = "ann";
+/// Name of path queries.
+pub const GET_ROUTE_QUERY: &str = "gr";
+/// Maximum number of peers in an announcement.
+pub const MAX_LINKS_PER_ANNOUNCER: usize = 64;
+
+/// Builds an `ann` query, announcing our peers to the supernode.
+pub fn announce_query(peers: Vec<&Node>, transaction_id: Vec<u8>) -> RoutePacket {
+    let peers = peers.into_iter().map(|node| NodeData {
+        public_key: *node.public_key(),
+        path: *node.path(),
+        version: node.version(),
+    }).collect();
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .query(ANNOUNCE_QUERY.to_owned())
+            .nodes_vec(peers)
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
+
+/// Builds a `gr` query, asking the supernode for a path to the target.
+pub fn get_route_query(target: &Address, transaction_id: Vec<u8>) -> RoutePacket {
+    RoutePacketBuilder::new(PROTOCOL_VERSION, transaction_id)
+            .query(GET_ROUTE_QUERY.to_owned())
+            .target_address(target.bytes().to_vec())
+            .encoding_index(0)
+            .encoding_scheme(encoding_scheme())
+            .finalize()
+}
+
+/// Links announced to a supernode.
+#[derive(Clone, Debug, Default)]
+pub struct LinkGraph {
+    /// Public key and version of each node announced or appearing in
+    /// an announcement.
+    keys: HashMap<Address, ([u8; PUBLIC_KEY_LENGTH], u64)>,
+    /// Peers of each announcer, with the path from the announcer.
+    links: HashMap<Address, Vec<(Address, Path)>>,
+}
+
+impl LinkGraph {
+    pub fn new() -> LinkGraph {
+        LinkGraph { keys: HashMap::new(), links: HashMap::new() }
+    }
+
+    /// Replaces the links of the announcer with these peers. Fails if
+    /// there are more than `MAX_LINKS_PER_ANNOUNCER` of them.
+    pub fn announce(&mut self, announcer: &Node, peers: &[NodeData]) -> Result<(), ()> {
+        if peers.len() > MAX_LINKS_PER_ANNOUNCER {
+            return Err(())
+        }
+        let address = announcer.address();
+        self.keys.insert(address.clone(), (*announcer.public_key(), announcer.version()));
+        self.set_links(address, peers);
+        Ok(())
+    }
+
+    /// Replaces the links of a node, whose key may not be known (eg.
+    /// the supernode itself).
+    pub fn set_links(&mut self, address: Address, peers: &[NodeData]) {
+        let links = peers.iter().map(|peer| {
+            let peer_address = Address::from_public_key(&peer.public_key);
+            self.keys.entry(peer_address.clone()).or_insert((peer.public_key, peer.version));
+            (peer_address, peer.path)
+        }).collect();
+        self.links.insert(address, links);
+    }
+
+    /// Number of nodes which announced their links.
+    pub fn nb_announcers(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Links of this node, if it announced them.
+    pub fn links(&self, address: &Address) -> Option<&[(Address, Path)]> {
+        self.links.get(address).map(|links| &links[..])
+    }
+
+    /// Returns whether the link from `from` to `to` can be used: `to`
+    /// did not announce its links, or announced this one too.
+    fn is_confirmed(&self, from: &Address, to: &Address) -> bool {
+        match self.links.get(to) {
+            Some(links) => links.iter().any(|&(ref peer, _)| peer == from),
+            None => true,
+        }
+    }
+
+    /// Returns the target, with the path with the fewest hops from
+    /// `from`, if there is one.
+    pub fn find_path(&self, from: &Address, to: &Address) -> Option<Node> {
+        let &(public_key, version) = self.keys.get(to)?;
+        let mut visited = HashSet::new();
+        visited.insert(from.clone());
+        let mut queue = VecDeque::new();
+        queue.push_back((from.clone(), None));
+        while let Some((address, path)) = queue.pop_front() {
+            for &(ref peer, ref link) in self.links.get(&address).map(|links| &links[..]).unwrap_or(&[]) {
+                if visited.contains(peer) || !self.is_confirmed(&address, peer) {
+                    continue;
+                }
+                let peer_path = match path {
+                    None => *link,
+                    Some(ref path) => match splice(link, path) {
+                        Some(peer_path) => peer_path,
+                        None => continue, // Too long
+                    },
+                };
+                if peer == to {
+                    return Some(Node::new(public_key, peer_path, version))
+                }
+                visited.insert(peer.clone());
+                queue.push_back((peer.clone(), Some(peer_path)));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use label::{path_from_u64, path_to_u64};
+    use router::RoutingMode;
+    use driver::Action;
+    use simulator::{Simulator, SimulatorConfig, Topology};
+
+    fn node_data(public_key: [u8; PUBLIC_KEY_LENGTH], path: u64) -> NodeData {
+        NodeData { public_key: public_key, path: path_from_u64(path), version: 18 }
+    }
+
+    #[test]
+    fn test_find_path() {
+        let (a, b, c, d) = ([1; 32], [2; 32], [3; 32], [4; 32]);
+        let mut graph = LinkGraph::new();
+        // A - B - C, and D which announced nothing.
+        graph.announce(&Node::new(a, path_from_u64(0b1_000), 18), &[node_data(b, 0b1_011)]).unwrap();
+        graph.announce(&Node::new(b, path_from_u64(0b1_000), 18), &[node_data(a, 0b1_110), node_data(c, 0b1_101)]).unwrap();
+        graph.set_links(Address::from_public_key(&d), &[]);
+        assert_eq!(graph.nb_announcers(), 3);
+
+        let (address_a, address_c) = (Address::from_public_key(&a), Address::from_public_key(&c));
+        let node = graph.find_path(&address_a, &address_c).unwrap();
+        assert_eq!(node.public_key(), &c);
+        assert_eq!(path_to_u64(node.path()), 0b1_101_011);
+        // C did not announce its links.
+        assert_eq!(graph.find_path(&address_c, &address_a), None);
+        assert_eq!(graph.find_path(&address_a, &Address::from_public_key(&d)), None);
+
+        // E claims a link to A, which A did not announce.
+        let e = [5; 32];
+        graph.announce(&Node::new(e, path_from_u64(0b1_000), 18), &[node_data(a, 0b1_011)]).unwrap();
+        assert_eq!(graph.find_path(&Address::from_public_key(&e), &address_a), None);
+        let peers: Vec<NodeData> = (0..MAX_LINKS_PER_ANNOUNCER+1).map(|i| node_data([i as u8; 32], 0b1_011)).collect();
+        assert_eq!(graph.announce(&Node::new(e, path_from_u64(0b1_000), 18), &peers), Err(()));
+    }
+
+    #[test]
+    fn test_subnodes() {
+        let mut sim = Simulator::new(&Topology::line(5), SimulatorConfig::default());
+        let supernode = sim.address(2);
+        sim.driver_mut(2).router_mut().set_mode(RoutingMode::Supernode);
+        for i in &[0, 1, 3, 4] {
+            sim.driver_mut(*i).router_mut().set_mode(RoutingMode::Subnode { supernode: supernode.clone() });
+        }
+        // 1 and 3 are peers of the supernode; 0 and 4 look it up first,
+        // then announce themselves at the next tick.
+        for i in 1..4 {
+            sim.run_until(i*1000);
+        }
+        assert_eq!(sim.driver(2).router().link_graph().unwrap().nb_announcers(), 4);
+
+        let node = sim.lookup(0, 4).unwrap();
+        assert_eq!(node.public_key(), sim.public_key(4));
+        assert_eq!(sim.route(0, node.path()), Some(4));
+        assert_eq!(sim.driver(2).router().metrics().queries_answered("gr"), 1);
+        let target = sim.address(4);
+        let queried: Vec<Vec<Node>> = sim.take_actions(0).into_iter().filter_map(|action| match action {
+            Action::LookupQueried { target: ref queried_target, ref nodes } if *queried_target == target => Some(nodes.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(queried.len(), 1);
+        assert_eq!(queried[0][0].address(), supernode);
+    }
+}
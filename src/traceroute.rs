@@ -0,0 +1,203 @@
+//! Traceroute along a path, using switch pings.
+//!
+//! Each switch answers pings whose label ends on it, so pinging each
+//! successive prefix of a path (see `label::hop_prefixes`) tells which
+//! hop stops forwarding packets, and how long each hop takes.
+//!
+//! Switch pings are handled by the switch, not the router, so this
+//! only tracks the pings: the application sends them with
+//! `ControlPacket::Ping`, and gives back the `ControlPacket::Pong`s
+//! whose opaque data belongs to a traceroute (see `traceroute_id`).
+
+use std::fmt;
+
+use fcp_switching::encoding_scheme::EncodingSchemeForm;
+
+use node::{Path, DisplayPath, PUBLIC_KEY_LENGTH, public_key_to_base32};
+use label::hop_prefixes;
+use router::Router;
+
+/// Prefix of the opaque data of our pings.
+const MAGIC: &[u8; 2] = b"tr";
+
+/// State of the ping to a hop.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HopStatus {
+    /// Not sent yet, or waiting for the pong.
+    Pending,
+    /// The switch answered after `rtt` milliseconds, with this
+    /// protocol version.
+    Replied { rtt: u64, version: u64 },
+    /// No pong in time.
+    TimedOut,
+}
+
+/// A router on the path.
+#[derive(Clone, Debug)]
+pub struct Hop {
+    /// Path from us to this router.
+    pub label: Path,
+    /// Public key of the router, if a node with this path is in our
+    /// routing table.
+    pub public_key: Option<[u8; PUBLIC_KEY_LENGTH]>,
+    pub status: HopStatus,
+}
+
+/// Displays a hop like `0000.0000.0000.0015 3ms v18 <key>.k`.
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", DisplayPath(&self.label))?;
+        match self.status {
+            HopStatus::Pending => write!(f, " ...")?,
+            HopStatus::Replied { rtt, version } => write!(f, " {}ms v{}", rtt, version)?,
+            HopStatus::TimedOut => write!(f, " timeout")?,
+        }
+        match self.public_key {
+            Some(ref public_key) => write!(f, " {}", public_key_to_base32(public_key)),
+            None => write!(f, " (unknown key)"),
+        }
+    }
+}
+
+/// Switch ping the application should send.
+#[derive(Clone, Debug)]
+pub struct Ping {
+    pub label: Path,
+    pub opaque_data: Vec<u8>,
+}
+
+pub struct Traceroute {
+    id: u32,
+    hops: Vec<Hop>,
+    sent_at: u64,
+    deadline: u64,
+}
+
+/// Returns the id of the traceroute a pong belongs to, given its
+/// opaque data, or `None` if it is not one of ours.
+pub fn traceroute_id(opaque_data: &[u8]) -> Option<u32> {
+    parse_opaque_data(opaque_data).map(|(id, _)| id)
+}
+
+fn parse_opaque_data(opaque_data: &[u8]) -> Option<(u32, usize)> {
+    if opaque_data.len() != 8 || &opaque_data[..2] != MAGIC {
+        return None
+    }
+    let id = opaque_data[2..6].iter().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+    let hop = ((opaque_data[6] as usize) << 8) | opaque_data[7] as usize;
+    Some((id, hop))
+}
+
+impl Traceroute {
+    /// Prepares a traceroute along the path, whose switches use these
+    /// encoding forms (see `label::hop_prefixes`). Keys of the hops
+    /// are taken from the router's peers and routing table. `id`
+    /// distinguishes concurrent traceroutes.
+    /// Returns `None` if the path cannot be read with these schemes.
+    pub fn new(id: u32, path: &Path, schemes: &[Vec<EncodingSchemeForm>], router: &Router) -> Option<Traceroute> {
+        let hops = hop_prefixes(path, schemes)?.into_iter().map(|label| {
+            let public_key = router.peers().values().chain(router.node_store().nodes().into_iter().map(|(_, node)| node))
+                    .find(|node| *node.path() == label)
+                    .map(|node| *node.public_key());
+            Hop { label: label, public_key: public_key, status: HopStatus::Pending }
+        }).collect();
+        Some(Traceroute { id: id, hops: hops, sent_at: 0, deadline: 0 })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Hops, from the closest to us to the destination.
+    pub fn hops(&self) -> &[Hop] {
+        &self.hops
+    }
+
+    /// Returns the pings to send to all hops. They time out after
+    /// `timeout` milliseconds.
+    pub fn start(&mut self, now: u64, timeout: u64) -> Vec<Ping> {
+        self.sent_at = now;
+        self.deadline = now.saturating_add(timeout);
+        let id = self.id;
+        self.hops.iter().enumerate().map(|(i, hop)| {
+            let mut opaque_data = MAGIC.to_vec();
+            opaque_data.extend((0..4).map(|j| (id >> (8*(3-j))) as u8));
+            opaque_data.extend(&[(i >> 8) as u8, i as u8]);
+            Ping { label: hop.label, opaque_data: opaque_data }
+        }).collect()
+    }
+
+    /// Records a pong. Returns `false` if it does not belong to this
+    /// traceroute, or came too late.
+    pub fn on_pong(&mut self, opaque_data: &[u8], version: u64, now: u64) -> bool {
+        let hop = match parse_opaque_data(opaque_data) {
+            Some((id, hop)) if id == self.id && hop < self.hops.len() => &mut self.hops[hop],
+            _ => return false,
+        };
+        if hop.status != HopStatus::Pending {
+            return false;
+        }
+        hop.status = HopStatus::Replied { rtt: now.saturating_sub(self.sent_at), version: version };
+        true
+    }
+
+    /// Marks hops that did not answer in time as timed out.
+    pub fn on_tick(&mut self, now: u64) {
+        if now < self.deadline {
+            return;
+        }
+        for hop in self.hops.iter_mut() {
+            if hop.status == HopStatus::Pending {
+                hop.status = HopStatus::TimedOut;
+            }
+        }
+    }
+
+    /// Returns whether all hops answered or timed out.
+    pub fn is_done(&self) -> bool {
+        self.hops.iter().all(|hop| hop.status != HopStatus::Pending)
+    }
+
+    /// First hop that did not answer, if any: packets are probably
+    /// lost between the previous hop and this one.
+    pub fn first_failing_hop(&self) -> Option<&Hop> {
+        self.hops.iter().find(|hop| hop.status == HopStatus::TimedOut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node::{Address, Node};
+    use router::encoding_scheme_forms;
+    use label::path_from_u64;
+
+    #[test]
+    fn test_traceroute() {
+        let mut router = Router::new(Address::from_public_key(&[1; PUBLIC_KEY_LENGTH]));
+        router.add_peer(Node::new([2; PUBLIC_KEY_LENGTH], path_from_u64(0b1_011), 18));
+
+        let path = path_from_u64(0b1_110_101_011);
+        let mut traceroute = Traceroute::new(42, &path, &[encoding_scheme_forms()], &router).unwrap();
+        let pings = traceroute.start(100, 1000);
+        assert_eq!(pings.len(), 3);
+        assert_eq!(pings[0].label, path_from_u64(0b1_011));
+        assert_eq!(pings[2].label, path);
+        assert_eq!(traceroute_id(&pings[1].opaque_data), Some(42));
+        assert_eq!(traceroute.hops()[0].public_key, Some([2; PUBLIC_KEY_LENGTH]));
+        assert_eq!(traceroute.hops()[1].public_key, None);
+
+        assert!(traceroute.on_pong(&pings[0].opaque_data, 18, 105));
+        assert!(!traceroute.on_pong(&pings[0].opaque_data, 18, 106));
+        assert!(traceroute.on_pong(&pings[1].opaque_data, 18, 120));
+        assert!(!traceroute.on_pong(b"not ours", 18, 120));
+        traceroute.on_tick(500);
+        assert!(!traceroute.is_done());
+        traceroute.on_tick(1100);
+        assert!(traceroute.is_done());
+
+        assert_eq!(traceroute.hops()[0].status, HopStatus::Replied { rtt: 5, version: 18 });
+        assert_eq!(traceroute.hops()[1].status, HopStatus::Replied { rtt: 20, version: 18 });
+        assert_eq!(traceroute.first_failing_hop().unwrap().label, path);
+    }
+}